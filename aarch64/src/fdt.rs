@@ -3,12 +3,15 @@
 // found in the LICENSE file.
 
 use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::ffi::CString;
 use std::fs::File;
 use std::io::Read;
 
 use arch::CpuSet;
 use arch::SERIAL_ADDR;
 use cros_fdt::Error;
+use cros_fdt::FdtReserveEntry;
 use cros_fdt::FdtWriter;
 use cros_fdt::Result;
 // This is a Battery related constant
@@ -28,26 +31,20 @@ use crate::AARCH64_GIC_CPUI_SIZE;
 use crate::AARCH64_GIC_DIST_BASE;
 use crate::AARCH64_GIC_DIST_SIZE;
 use crate::AARCH64_GIC_REDIST_SIZE;
+use crate::AARCH64_MMIO_SIZE;
 use crate::AARCH64_PMU_IRQ;
 use crate::AARCH64_PROTECTED_VM_FW_START;
-// These are RTC related constants
-use crate::AARCH64_RTC_ADDR;
-use crate::AARCH64_RTC_IRQ;
-use crate::AARCH64_RTC_SIZE;
 // These are serial device related constants.
 use crate::AARCH64_SERIAL_1_3_IRQ;
 use crate::AARCH64_SERIAL_2_4_IRQ;
 use crate::AARCH64_SERIAL_SIZE;
 use crate::AARCH64_SERIAL_SPEED;
+use crate::AARCH64_VIRTIO_MMIO_SIZE;
 
 // This is an arbitrary number to specify the node for the GIC.
 // If we had a more complex interrupt architecture, then we'd need an enum for
 // these.
 const PHANDLE_GIC: u32 = 1;
-const PHANDLE_RESTRICTED_DMA_POOL: u32 = 2;
-
-// CPUs are assigned phandles starting with this number.
-const PHANDLE_CPU0: u32 = 0x100;
 
 // These are specified by the Linux GIC bindings
 const GIC_FDT_IRQ_NUM_CELLS: u32 = 3;
@@ -59,40 +56,147 @@ const IRQ_TYPE_EDGE_RISING: u32 = 0x00000001;
 const IRQ_TYPE_LEVEL_HIGH: u32 = 0x00000004;
 const IRQ_TYPE_LEVEL_LOW: u32 = 0x00000008;
 
-fn create_memory_node(fdt: &mut FdtWriter, guest_mem: &GuestMemory) -> Result<()> {
-    let mut mem_reg_prop = Vec::new();
-    for region in guest_mem.guest_memory_regions() {
-        if region.0.offset() == AARCH64_PROTECTED_VM_FW_START {
-            continue;
+// PSCI function IDs, from the Arm Power State Coordination Interface specification
+const PSCI_FN64_AFFINITY_INFO: u32 = 0xc4000004;
+const PSCI_FN_MIGRATE_INFO_TYPE: u32 = 0x84000006;
+
+// Size of the CAM configuration window for a single PCI bus: 32 devices * 8 functions * 256 bytes.
+const PCI_CAM_BUS_SIZE: u64 = 0x10000;
+// Size of the ECAM configuration window for a single PCI bus: 32 devices * 8 functions * 4KiB.
+const PCI_ECAM_BUS_SIZE: u64 = 0x100000;
+
+// Each spin-table CPU polls a single 64-bit release address.
+const CPU_RELEASE_ADDR_SIZE: u64 = 8;
+
+// Cell sizes declared by the root node, which the "reg" of every top-level node is encoded with.
+const ROOT_ADDRESS_CELLS: u32 = 2;
+const ROOT_SIZE_CELLS: u32 = 2;
+
+/// Hands out unique phandle values to the nodes that are referenced by other nodes.
+///
+/// `PHANDLE_GIC` is well-known since it is referenced as the interrupt parent throughout the tree,
+/// so it is never handed out.
+struct PhandleAllocator {
+    next: u32,
+}
+
+impl PhandleAllocator {
+    fn new() -> Self {
+        PhandleAllocator {
+            next: PHANDLE_GIC + 1,
         }
-        mem_reg_prop.push(region.0.offset());
-        mem_reg_prop.push(region.1 as u64);
     }
 
-    let memory_node = fdt.begin_node("memory")?;
-    fdt.property_string("device_type", "memory")?;
-    fdt.property_array_u64("reg", &mem_reg_prop)?;
-    fdt.end_node(memory_node)?;
+    fn allocate(&mut self) -> u32 {
+        let phandle = self.next;
+        self.next += 1;
+        phandle
+    }
+}
+
+/// Writes a "reg" property with one (address, size) pair per entry of `regs`, each encoded in
+/// `address_cells` and `size_cells` cells, which must match the parent's `#address-cells` and
+/// `#size-cells`.
+///
+/// Fails instead of writing a tree the guest would misparse if a cell count is above 2 or a value
+/// does not fit in its cells.
+fn property_reg(
+    fdt: &mut FdtWriter,
+    regs: &[(u64, u64)],
+    address_cells: u32,
+    size_cells: u32,
+) -> Result<()> {
+    let mut cells = Vec::with_capacity(regs.len() * (address_cells + size_cells) as usize);
+    for &(address, size) in regs {
+        push_reg_cells(&mut cells, "address", address, address_cells)?;
+        push_reg_cells(&mut cells, "size", size, size_cells)?;
+    }
+    fdt.property_array_u32("reg", &cells)
+}
+
+fn push_reg_cells(cells: &mut Vec<u32>, what: &str, value: u64, num_cells: u32) -> Result<()> {
+    let fits = match num_cells {
+        0 => value == 0,
+        1 => value <= u32::MAX as u64,
+        2 => true,
+        _ => {
+            return Err(Error::InvalidPropertyValue(format!(
+                "reg {} cannot be encoded in {} cells",
+                what, num_cells
+            )))
+        }
+    };
+    if !fits {
+        return Err(Error::InvalidPropertyValue(format!(
+            "reg {} {:#x} does not fit in {} cells",
+            what, value, num_cells
+        )));
+    }
+    match num_cells {
+        1 => cells.push(value as u32),
+        2 => cells.extend([(value >> 32) as u32, value as u32]),
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Returns the RAM banks of `guest_mem` as (base, size) pairs.
+fn memory_banks(guest_mem: &GuestMemory, phys_mem_start: u64) -> Vec<(u64, u64)> {
+    // Only RAM is described; the regions below it hold firmware.
+    guest_mem
+        .guest_memory_regions()
+        .into_iter()
+        .filter(|region| {
+            region.0.offset() >= phys_mem_start
+                && region.0.offset() != AARCH64_PROTECTED_VM_FW_START
+        })
+        .map(|region| (region.0.offset(), region.1 as u64))
+        .collect()
+}
+
+/// Describes `banks` in a single memory node, or with one memory node per bank tagged with NUMA
+/// node 0 if `split_banks` is set.
+fn create_memory_node(fdt: &mut FdtWriter, banks: &[(u64, u64)], split_banks: bool) -> Result<()> {
+    if !split_banks {
+        let memory_node = fdt.begin_node("memory")?;
+        fdt.property_string("device_type", "memory")?;
+        property_reg(fdt, banks, ROOT_ADDRESS_CELLS, ROOT_SIZE_CELLS)?;
+        fdt.end_node(memory_node)?;
+        return Ok(());
+    }
+
+    for &(base, size) in banks {
+        let memory_node = fdt.begin_node(&format!("memory@{:x}", base))?;
+        fdt.property_string("device_type", "memory")?;
+        property_reg(fdt, &[(base, size)], ROOT_ADDRESS_CELLS, ROOT_SIZE_CELLS)?;
+        fdt.property_u32("numa-node-id", 0)?;
+        fdt.end_node(memory_node)?;
+    }
 
     Ok(())
 }
 
-fn create_resv_memory_node(fdt: &mut FdtWriter, resv_size: Option<u64>) -> Result<Option<u32>> {
+fn create_resv_memory_node(
+    fdt: &mut FdtWriter,
+    resv_size: Option<u64>,
+    phandles: &mut PhandleAllocator,
+) -> Result<Option<u32>> {
     if let Some(resv_size) = resv_size {
+        let dma_pool_phandle = phandles.allocate();
         let resv_memory_node = fdt.begin_node("reserved-memory")?;
         fdt.property_u32("#address-cells", 0x2)?;
         fdt.property_u32("#size-cells", 0x2)?;
         fdt.property_null("ranges")?;
 
         let restricted_dma_pool = fdt.begin_node("restricted_dma_reserved")?;
-        fdt.property_u32("phandle", PHANDLE_RESTRICTED_DMA_POOL)?;
+        fdt.property_u32("phandle", dma_pool_phandle)?;
         fdt.property_string("compatible", "restricted-dma-pool")?;
         fdt.property_u64("size", resv_size)?;
         fdt.property_u64("alignment", base::pagesize() as u64)?;
         fdt.end_node(restricted_dma_pool)?;
 
         fdt.end_node(resv_memory_node)?;
-        Ok(Some(PHANDLE_RESTRICTED_DMA_POOL))
+        Ok(Some(dma_pool_phandle))
     } else {
         Ok(None)
     }
@@ -103,7 +207,17 @@ fn create_cpu_nodes(
     num_cpus: u32,
     cpu_clusters: Vec<CpuSet>,
     cpu_capacity: BTreeMap<usize, u32>,
+    enable_method: CpuEnableMethod,
+    powered_off: &[u32],
+    phandles: &mut PhandleAllocator,
 ) -> Result<()> {
+    if let Some(cpu_id) = powered_off.iter().find(|&&cpu_id| cpu_id >= num_cpus) {
+        return Err(Error::InvalidPropertyValue(format!(
+            "cannot power off unknown cpu {}",
+            cpu_id
+        )));
+    }
+    let cpu_phandles: Vec<u32> = (0..num_cpus).map(|_| phandles.allocate()).collect();
     let cpus_node = fdt.begin_node("cpus")?;
     fdt.property_u32("#address-cells", 0x1)?;
     fdt.property_u32("#size-cells", 0x0)?;
@@ -114,14 +228,26 @@ fn create_cpu_nodes(
         fdt.property_string("device_type", "cpu")?;
         fdt.property_string("compatible", "arm,arm-v8")?;
         if num_cpus > 1 {
-            fdt.property_string("enable-method", "psci")?;
+            match enable_method {
+                CpuEnableMethod::Psci => fdt.property_string("enable-method", "psci")?,
+                CpuEnableMethod::SpinTable { release_addr_base } => {
+                    fdt.property_string("enable-method", "spin-table")?;
+                    fdt.property_u64(
+                        "cpu-release-addr",
+                        release_addr_base + CPU_RELEASE_ADDR_SIZE * cpu_id as u64,
+                    )?;
+                }
+            }
         }
         fdt.property_u32("reg", cpu_id)?;
-        fdt.property_u32("phandle", PHANDLE_CPU0 + cpu_id)?;
+        fdt.property_u32("phandle", cpu_phandles[cpu_id as usize])?;
 
         if let Some(capacity) = cpu_capacity.get(&(cpu_id as usize)) {
             fdt.property_u32("capacity-dmips-mhz", *capacity)?;
         }
+        if powered_off.contains(&cpu_id) {
+            fdt.property_string("status", "disabled")?;
+        }
 
         fdt.end_node(cpu_node)?;
     }
@@ -132,7 +258,13 @@ fn create_cpu_nodes(
             let cluster_node = fdt.begin_node(&format!("cluster{}", cluster_idx))?;
             for (core_idx, cpu_id) in cpus.iter().enumerate() {
                 let core_node = fdt.begin_node(&format!("core{}", core_idx))?;
-                fdt.property_u32("cpu", PHANDLE_CPU0 + *cpu_id as u32)?;
+                let cpu_phandle = cpu_phandles.get(*cpu_id).ok_or_else(|| {
+                    Error::InvalidPropertyValue(format!(
+                        "cpu-map references unknown cpu {}",
+                        cpu_id
+                    ))
+                })?;
+                fdt.property_u32("cpu", *cpu_phandle)?;
                 fdt.end_node(core_node)?;
             }
             fdt.end_node(cluster_node)?;
@@ -144,7 +276,41 @@ fn create_cpu_nodes(
     Ok(())
 }
 
-fn create_gic_node(fdt: &mut FdtWriter, is_gicv3: bool, num_cpus: u64) -> Result<()> {
+/// The MSI controller below the GIC node that PCI devices send their MSIs to.
+#[derive(Copy, Clone)]
+enum MsiParent {
+    /// A GICv2m frame, with its phandle.
+    V2m(u32),
+    /// A GICv3 ITS, with its phandle.
+    Its(u32),
+}
+
+/// Creates the GIC node, with a GICv2m MSI frame below it if `v2m` is set or an ITS below it if
+/// `its` is set. Returns the MSI controller, if any.
+fn create_gic_node(
+    fdt: &mut FdtWriter,
+    is_gicv3: bool,
+    num_cpus: u64,
+    v2m: Option<GicV2mConfig>,
+    its: Option<GicItsConfig>,
+    maintenance_irq: Option<u32>,
+    phandles: &mut PhandleAllocator,
+) -> Result<Option<MsiParent>> {
+    if is_gicv3 && v2m.is_some() {
+        return Err(Error::InvalidPropertyValue(
+            "a GICv2m MSI frame requires a GICv2".to_string(),
+        ));
+    }
+    if !is_gicv3 && its.is_some() {
+        return Err(Error::InvalidPropertyValue(
+            "an ITS requires a GICv3".to_string(),
+        ));
+    }
+    if !is_gicv3 && maintenance_irq.is_some() {
+        return Err(Error::InvalidPropertyValue(
+            "a maintenance interrupt requires a GICv3".to_string(),
+        ));
+    }
     let mut gic_reg_prop = [AARCH64_GIC_DIST_BASE, AARCH64_GIC_DIST_SIZE, 0, 0];
 
     let intc_node = fdt.begin_node("intc")?;
@@ -163,17 +329,85 @@ fn create_gic_node(fdt: &mut FdtWriter, is_gicv3: bool, num_cpus: u64) -> Result
     fdt.property_u32("phandle", PHANDLE_GIC)?;
     fdt.property_u32("#address-cells", 2)?;
     fdt.property_u32("#size-cells", 2)?;
+    if let Some(irq) = maintenance_irq {
+        let cpu_mask = ppi_cpu_mask(num_cpus as u32);
+        fdt.property_array_u32(
+            "interrupts",
+            &[GIC_FDT_IRQ_TYPE_PPI, irq, cpu_mask | IRQ_TYPE_LEVEL_HIGH],
+        )?;
+    }
+    if v2m.is_some() || its.is_some() {
+        // The MSI controller's registers are in the same address space as the GIC's.
+        fdt.property_null("ranges")?;
+    }
+    let msi_parent = match (v2m, its) {
+        (Some(v2m), _) => Some(MsiParent::V2m(create_v2m_node(fdt, v2m, phandles)?)),
+        (_, Some(its)) => Some(MsiParent::Its(create_its_node(fdt, its, phandles)?)),
+        (None, None) => None,
+    };
     fdt.end_node(intc_node)?;
 
-    Ok(())
+    Ok(msi_parent)
+}
+
+/// Creates the node of a GICv3 ITS, which must be a child of the GICv3 node, and returns its
+/// phandle.
+fn create_its_node(
+    fdt: &mut FdtWriter,
+    its: GicItsConfig,
+    phandles: &mut PhandleAllocator,
+) -> Result<u32> {
+    let phandle = phandles.allocate();
+    let its_node = fdt.begin_node(&format!("msi-controller@{:x}", its.base))?;
+    fdt.property_string("compatible", "arm,gic-v3-its")?;
+    fdt.property_null("msi-controller")?;
+    // The ITS identifies devices by a single cell, the requester ID for PCI devices.
+    fdt.property_u32("#msi-cells", 1)?;
+    property_reg(fdt, &[(its.base, its.size)], 2, 2)?;
+    fdt.property_u32("phandle", phandle)?;
+    fdt.end_node(its_node)?;
+    Ok(phandle)
+}
+
+/// Creates the node of a GICv2m MSI frame, which must be a child of the GICv2 node, and returns its
+/// phandle so devices can use it as their "msi-parent".
+fn create_v2m_node(
+    fdt: &mut FdtWriter,
+    v2m: GicV2mConfig,
+    phandles: &mut PhandleAllocator,
+) -> Result<u32> {
+    let phandle = phandles.allocate();
+    let v2m_node = fdt.begin_node(&format!("v2m@{:x}", v2m.base))?;
+    fdt.property_string("compatible", "arm,gic-v2m-frame")?;
+    fdt.property_null("msi-controller")?;
+    property_reg(fdt, &[(v2m.base, v2m.size)], 2, 2)?;
+    fdt.property_u32("arm,msi-base-spi", v2m.spi_base)?;
+    fdt.property_u32("arm,msi-num-spis", v2m.num_spis)?;
+    fdt.property_u32("phandle", phandle)?;
+    fdt.end_node(v2m_node)?;
+    Ok(phandle)
+}
+
+/// Returns the PPI affinity bits of a GIC interrupt specifier's flags cell for the cpus set in
+/// `cpu_mask` (bit n for cpu n).
+///
+/// The field can only name the first 8 cpus, the GICv2 limit; higher bits are dropped. The GICv3
+/// binding ignores the field, so this is harmless for larger GICv3 guests.
+fn ppi_affinity(cpu_mask: u32) -> u32 {
+    (cpu_mask << GIC_FDT_IRQ_PPI_CPU_SHIFT) & GIC_FDT_IRQ_PPI_CPU_MASK
+}
+
+/// Returns the PPI affinity bits targeting the first `num_cpus` cpus, saturating at 8 cpus as
+/// described for `ppi_affinity`.
+fn ppi_cpu_mask(num_cpus: u32) -> u32 {
+    ppi_affinity(1u32.checked_shl(num_cpus).map_or(u32::MAX, |bit| bit - 1))
 }
 
 fn create_timer_node(fdt: &mut FdtWriter, num_cpus: u32) -> Result<()> {
     // These are fixed interrupt numbers for the timer device.
     let irqs = [13, 14, 11, 10];
     let compatible = "arm,armv8-timer";
-    let cpu_mask: u32 =
-        (((1 << num_cpus) - 1) << GIC_FDT_IRQ_PPI_CPU_SHIFT) & GIC_FDT_IRQ_PPI_CPU_MASK;
+    let cpu_mask = ppi_cpu_mask(num_cpus);
 
     let mut timer_reg_cells = Vec::new();
     for &irq in &irqs {
@@ -191,10 +425,17 @@ fn create_timer_node(fdt: &mut FdtWriter, num_cpus: u32) -> Result<()> {
     Ok(())
 }
 
-fn create_pmu_node(fdt: &mut FdtWriter, num_cpus: u32) -> Result<()> {
+/// Creates the PMU node, whose interrupt targets the cpus set in `cpu_mask` (bit n for cpu n).
+///
+/// Only the first 8 cpus can be named; see `ppi_affinity`.
+fn create_pmu_node(fdt: &mut FdtWriter, cpu_mask: u32) -> Result<()> {
+    if cpu_mask == 0 {
+        return Err(Error::InvalidPropertyValue(
+            "PMU cpu mask is empty".to_string(),
+        ));
+    }
     let compatible = "arm,armv8-pmuv3";
-    let cpu_mask: u32 =
-        (((1 << num_cpus) - 1) << GIC_FDT_IRQ_PPI_CPU_SHIFT) & GIC_FDT_IRQ_PPI_CPU_MASK;
+    let cpu_mask = ppi_affinity(cpu_mask);
     let irq = [
         GIC_FDT_IRQ_TYPE_PPI,
         AARCH64_PMU_IRQ,
@@ -208,30 +449,104 @@ fn create_pmu_node(fdt: &mut FdtWriter, num_cpus: u32) -> Result<()> {
     Ok(())
 }
 
-fn create_serial_node(fdt: &mut FdtWriter, addr: u64, irq: u32) -> Result<()> {
-    let serial_reg_prop = [addr, AARCH64_SERIAL_SIZE];
-    let irq = [GIC_FDT_IRQ_TYPE_SPI, irq, IRQ_TYPE_EDGE_RISING];
+fn create_serial_node(
+    fdt: &mut FdtWriter,
+    port: &SerialPortConfig,
+    clock_phandle: Option<u32>,
+) -> Result<()> {
+    let serial_reg_prop = [port.addr, port.size];
+    let irq = [GIC_FDT_IRQ_TYPE_SPI, port.irq, IRQ_TYPE_EDGE_RISING];
 
-    let serial_node = fdt.begin_node(&format!("U6_16550A@{:x}", addr))?;
+    let serial_node = fdt.begin_node(&format!("U6_16550A@{:x}", port.addr))?;
     fdt.property_string("compatible", "ns16550a")?;
     fdt.property_array_u64("reg", &serial_reg_prop)?;
-    fdt.property_u32("clock-frequency", AARCH64_SERIAL_SPEED)?;
+    match clock_phandle {
+        Some(phandle) => {
+            fdt.property_u32("clocks", phandle)?;
+            fdt.property_string("clock-names", "uartclk")?;
+        }
+        None => fdt.property_u32("clock-frequency", port.clock_frequency)?,
+    }
     fdt.property_array_u32("interrupts", &irq)?;
     fdt.end_node(serial_node)?;
 
     Ok(())
 }
 
-fn create_serial_nodes(fdt: &mut FdtWriter) -> Result<()> {
+fn create_serial_nodes(
+    fdt: &mut FdtWriter,
+    ports: &[SerialPortConfig],
+    clock: SerialClock,
+    phandles: &mut PhandleAllocator,
+) -> Result<()> {
+    // Phandles of the fixed-clock nodes created so far, keyed by frequency.
+    let mut clocks = BTreeMap::new();
+    for (i, port) in ports.iter().enumerate() {
+        // Nodes are named after their address, which must therefore be unique.
+        if ports[..i].iter().any(|other| other.addr == port.addr) {
+            return Err(Error::InvalidPropertyValue(format!(
+                "duplicate serial port address {:#x}",
+                port.addr
+            )));
+        }
+        let clock_phandle = match clock {
+            SerialClock::Frequency => None,
+            SerialClock::FixedClock => match clocks.get(&port.clock_frequency) {
+                Some(&phandle) => Some(phandle),
+                None => {
+                    let phandle = create_fixed_clock_node(fdt, port.clock_frequency, phandles)?;
+                    clocks.insert(port.clock_frequency, phandle);
+                    Some(phandle)
+                }
+            },
+        };
+        create_serial_node(fdt, port, clock_phandle)?;
+    }
+
+    Ok(())
+}
+
+/// Creates a fixed-clock node running at `frequency` Hz and returns its phandle.
+fn create_fixed_clock_node(
+    fdt: &mut FdtWriter,
+    frequency: u32,
+    phandles: &mut PhandleAllocator,
+) -> Result<u32> {
+    let phandle = phandles.allocate();
+    let clock_node = fdt.begin_node(&format!("uartclk-{}", frequency))?;
+    fdt.property_u32("#clock-cells", 0)?;
+    fdt.property_string("compatible", "fixed-clock")?;
+    fdt.property_u32("clock-frequency", frequency)?;
+    fdt.property_u32("phandle", phandle)?;
+    fdt.end_node(clock_node)?;
+    Ok(phandle)
+}
+
+/// Returns the four serial ports that crosvm always creates on aarch64.
+fn default_serial_ports() -> [SerialPortConfig; 4] {
     // Note that SERIAL_ADDR contains the I/O port addresses conventionally used
     // for serial ports on x86. This uses the same addresses (but on the MMIO bus)
     // to simplify the shared serial code.
-    create_serial_node(fdt, SERIAL_ADDR[0], AARCH64_SERIAL_1_3_IRQ)?;
-    create_serial_node(fdt, SERIAL_ADDR[1], AARCH64_SERIAL_2_4_IRQ)?;
-    create_serial_node(fdt, SERIAL_ADDR[2], AARCH64_SERIAL_1_3_IRQ)?;
-    create_serial_node(fdt, SERIAL_ADDR[3], AARCH64_SERIAL_2_4_IRQ)?;
+    let port = |addr, irq| SerialPortConfig {
+        addr,
+        size: AARCH64_SERIAL_SIZE,
+        irq,
+        clock_frequency: AARCH64_SERIAL_SPEED,
+    };
+    [
+        port(SERIAL_ADDR[0], AARCH64_SERIAL_1_3_IRQ),
+        port(SERIAL_ADDR[1], AARCH64_SERIAL_2_4_IRQ),
+        port(SERIAL_ADDR[2], AARCH64_SERIAL_1_3_IRQ),
+        port(SERIAL_ADDR[3], AARCH64_SERIAL_2_4_IRQ),
+    ]
+}
 
-    Ok(())
+fn create_default_serial_nodes(
+    fdt: &mut FdtWriter,
+    clock: SerialClock,
+    phandles: &mut PhandleAllocator,
+) -> Result<()> {
+    create_serial_nodes(fdt, &default_serial_ports(), clock, phandles)
 }
 
 fn psci_compatible(version: &PsciVersion) -> Vec<&str> {
@@ -253,27 +568,121 @@ fn psci_compatible(version: &PsciVersion) -> Vec<&str> {
     compatible
 }
 
-fn create_psci_node(fdt: &mut FdtWriter, version: &PsciVersion) -> Result<()> {
+/// The instruction the guest uses to make PSCI calls.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum PsciConduit {
+    /// Calls trap to the hypervisor.
+    #[default]
+    Hvc,
+    /// Calls go to the secure monitor.
+    Smc,
+}
+
+fn create_psci_node(
+    fdt: &mut FdtWriter,
+    version: &PsciVersion,
+    conduit: PsciConduit,
+) -> Result<()> {
     let compatible = psci_compatible(version);
     let psci_node = fdt.begin_node("psci")?;
     fdt.property_string_list("compatible", &compatible)?;
     // Only support aarch64 guest
-    fdt.property_string("method", "hvc")?;
+    let method = match conduit {
+        PsciConduit::Hvc => "hvc",
+        PsciConduit::Smc => "smc",
+    };
+    fdt.property_string("method", method)?;
+    // Migration-aware guests look these up rather than assuming the standard IDs.
+    if *version >= PSCI_1_0 {
+        fdt.property_u32("affinity_info", PSCI_FN64_AFFINITY_INFO)?;
+        fdt.property_u32("migrate_info_type", PSCI_FN_MIGRATE_INFO_TYPE)?;
+    }
     fdt.end_node(psci_node)?;
 
     Ok(())
 }
 
+fn create_hypervisor_node(fdt: &mut FdtWriter) -> Result<()> {
+    let hypervisor_node = fdt.begin_node("hypervisor")?;
+    fdt.property_string("compatible", "linux,kvm")?;
+    fdt.end_node(hypervisor_node)?;
+
+    Ok(())
+}
+
+fn create_optee_node(fdt: &mut FdtWriter) -> Result<()> {
+    let optee_node = fdt.begin_node("optee")?;
+    fdt.property_string("compatible", "linaro,optee-tz")?;
+    fdt.property_string("method", "smc")?;
+    fdt.end_node(optee_node)?;
+
+    Ok(())
+}
+
+fn create_firmware_node(
+    fdt: &mut FdtWriter,
+    android_fstab: Option<File>,
+    use_optee: bool,
+) -> Result<()> {
+    if android_fstab.is_none() && !use_optee {
+        return Ok(());
+    }
+
+    // All firmware interfaces share a single /firmware node.
+    let firmware_node = fdt.begin_node("firmware")?;
+    if let Some(android_fstab) = android_fstab {
+        arch::android::create_android_firmware_node(fdt, android_fstab)?;
+    }
+    if use_optee {
+        create_optee_node(fdt)?;
+    }
+    fdt.end_node(firmware_node)?;
+
+    Ok(())
+}
+
+/// Returns the "stdout-path" of the console: the first 16550 UART, or the `virtio_console`th of
+/// the virtio-mmio transports in `virtio_mmio` when the console is a virtio device.
+fn stdout_path(
+    virtio_console: Option<u32>,
+    virtio_mmio: Option<VirtioMmioConfig>,
+) -> Result<String> {
+    let count = virtio_mmio.map_or(0, |cfg| cfg.count);
+    match (virtio_console, virtio_mmio) {
+        (None, _) => Ok(format!("/U6_16550A@{:x}", SERIAL_ADDR[0])),
+        (Some(index), Some(cfg)) if index < count => Ok(format!(
+            "/{}",
+            virtio_mmio_node_name(cfg.mmio_base + index as u64 * AARCH64_VIRTIO_MMIO_SIZE)
+        )),
+        (Some(index), _) => Err(Error::InvalidPropertyValue(format!(
+            "virtio console {} is not one of the {} virtio-mmio devices",
+            index, count
+        ))),
+    }
+}
+
+/// Optional properties of the chosen node describing how the kernel was loaded, for guests that
+/// read them to relocate themselves.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ChosenBootInfo {
+    /// Guest physical address the kernel image was loaded at.
+    pub kernel_load_addr: Option<GuestAddress>,
+    /// Version of the boot protocol the kernel image was loaded with, e.g. from its image header.
+    pub boot_protocol_version: Option<u32>,
+}
+
 fn create_chosen_node(
     fdt: &mut FdtWriter,
     cmdline: &str,
     initrd: Option<(GuestAddress, usize)>,
+    stdout_path: &str,
+    boot_info: ChosenBootInfo,
 ) -> Result<()> {
     let chosen_node = fdt.begin_node("chosen")?;
     fdt.property_u32("linux,pci-probe-only", 1)?;
     fdt.property_string("bootargs", cmdline)?;
     // Used by android bootloader for boot console output
-    fdt.property_string("stdout-path", &format!("/U6_16550A@{:x}", SERIAL_ADDR[0]))?;
+    fdt.property_string("stdout-path", stdout_path)?;
 
     let mut random_file = File::open("/dev/urandom").map_err(Error::FdtIoError)?;
     let mut kaslr_seed_bytes = [0u8; 8];
@@ -295,11 +704,48 @@ fn create_chosen_node(
         fdt.property_u32("linux,initrd-start", initrd_start)?;
         fdt.property_u32("linux,initrd-end", initrd_end)?;
     }
+    if let Some(kernel_load_addr) = boot_info.kernel_load_addr {
+        fdt.property_u64("linux,kernel-load-addr", kernel_load_addr.offset())?;
+    }
+    if let Some(version) = boot_info.boot_protocol_version {
+        fdt.property_u32("linux,boot-protocol-version", version)?;
+    }
     fdt.end_node(chosen_node)?;
 
     Ok(())
 }
 
+/// Like `create_chosen_node`, but writes `base` followed by a space and `append` as "bootargs",
+/// e.g. to add a debug flag to a fixed command line.
+///
+/// Fails if the combined command line, including its NUL terminator, is longer than `max_len`.
+fn create_chosen_node_append(
+    fdt: &mut FdtWriter,
+    base: &CStr,
+    append: &str,
+    max_len: usize,
+    initrd: Option<(GuestAddress, usize)>,
+    stdout_path: &str,
+    boot_info: ChosenBootInfo,
+) -> Result<()> {
+    let base = base.to_str().map_err(|_| {
+        Error::InvalidPropertyValue("kernel command line is not valid UTF-8".to_string())
+    })?;
+    let cmdline = match (base.is_empty(), append.is_empty()) {
+        (_, true) => base.to_string(),
+        (true, false) => append.to_string(),
+        (false, false) => format!("{} {}", base, append),
+    };
+    if cmdline.len() + 1 > max_len {
+        return Err(Error::InvalidPropertyValue(format!(
+            "kernel command line of {} bytes does not fit in {} bytes",
+            cmdline.len() + 1,
+            max_len
+        )));
+    }
+    create_chosen_node(fdt, &cmdline, initrd, stdout_path, boot_info)
+}
+
 fn create_config_node(fdt: &mut FdtWriter, (addr, size): (GuestAddress, usize)) -> Result<()> {
     let addr = addr
         .offset()
@@ -354,6 +800,135 @@ pub struct PciConfigRegion {
     pub size: u64,
 }
 
+/// Mechanism used to access the memory-mapped PCI configuration space.
+#[derive(Copy, Clone, Default)]
+pub enum ConfigAccess {
+    /// Configuration Access Mechanism, limited to 256 bytes of configuration space per function.
+    #[default]
+    Cam,
+    /// Enhanced Configuration Access Mechanism, with 4KiB of configuration space per function.
+    Ecam,
+}
+
+impl ConfigAccess {
+    fn compatible(self) -> &'static str {
+        match self {
+            ConfigAccess::Cam => "pci-host-cam-generic",
+            ConfigAccess::Ecam => "pci-host-ecam-generic",
+        }
+    }
+
+    fn bus_size(self) -> u64 {
+        match self {
+            ConfigAccess::Cam => PCI_CAM_BUS_SIZE,
+            ConfigAccess::Ecam => PCI_ECAM_BUS_SIZE,
+        }
+    }
+}
+
+/// Location, interrupt and input clock of a memory-mapped 16550-compatible UART.
+#[derive(Copy, Clone)]
+pub struct SerialPortConfig {
+    /// Physical address of the base of the UART's register region.
+    pub addr: u64,
+    /// Size of the register region in bytes.
+    pub size: u64,
+    /// SPI number of the UART's interrupt.
+    pub irq: u32,
+    /// Frequency of the UART's input clock in Hz.
+    pub clock_frequency: u32,
+}
+
+/// How serial nodes describe the frequency of their input clock.
+#[derive(Copy, Clone, Default, PartialEq, Eq)]
+pub enum SerialClock {
+    /// Each UART has a literal "clock-frequency" property.
+    #[default]
+    Frequency,
+    /// Each UART references a fixed-clock node through "clocks", shared by every UART with the
+    /// same frequency.
+    FixedClock,
+}
+
+/// Mechanism used by the guest to bring up secondary CPUs.
+#[derive(Copy, Clone, Default)]
+pub enum CpuEnableMethod {
+    /// Secondary CPUs are started with PSCI CPU_ON calls.
+    #[default]
+    Psci,
+    /// Secondary CPUs spin until the guest writes an entry point to their release address. CPU
+    /// `n` polls the 64-bit word at `release_addr_base + 8 * n`.
+    SpinTable { release_addr_base: u64 },
+}
+
+/// Returns the memory reservation covering the pages that hold the spin-table release addresses,
+/// so the guest kernel does not reuse them while secondary CPUs are still polling.
+fn cpu_release_reservation(
+    num_cpus: u32,
+    enable_method: CpuEnableMethod,
+) -> Option<FdtReserveEntry> {
+    match enable_method {
+        CpuEnableMethod::SpinTable { release_addr_base } if num_cpus > 1 => {
+            let page_mask = base::pagesize() as u64 - 1;
+            let start = release_addr_base & !page_mask;
+            let end = release_addr_base + CPU_RELEASE_ADDR_SIZE * num_cpus as u64;
+            let end = (end + page_mask) & !page_mask;
+            Some(FdtReserveEntry {
+                address: start,
+                size: end - start,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Location and SPI range of a GICv2m MSI frame, which turns MSI writes into SPIs on a GICv2.
+#[derive(Copy, Clone)]
+pub struct GicV2mConfig {
+    /// Physical address of the base of the frame's register region.
+    pub base: u64,
+    /// Size of the frame's register region in bytes.
+    pub size: u64,
+    /// The first SPI number the frame signals.
+    pub spi_base: u32,
+    /// The number of consecutive SPIs the frame signals, starting at `spi_base`.
+    pub num_spis: u32,
+}
+
+/// Location of a GICv3 Interrupt Translation Service, which turns MSI writes into LPIs.
+#[derive(Copy, Clone)]
+pub struct GicItsConfig {
+    /// Physical address of the base of the ITS register region.
+    pub base: u64,
+    /// Size of the ITS register region in bytes.
+    pub size: u64,
+}
+
+/// Location and interrupt of the memory-mapped RTC.
+#[derive(Copy, Clone)]
+pub struct RtcConfig {
+    /// Physical address of the base of the memory-mapped RTC region.
+    pub base: u64,
+    /// Size of the RTC region in bytes.
+    pub size: u64,
+    /// The SPI number of the RTC interrupt.
+    pub irq: u32,
+}
+
+/// A memory-mapped register block that the guest writes to in order to reboot or power off, for
+/// guests that cannot use PSCI for that.
+#[derive(Copy, Clone)]
+pub struct SysconConfig {
+    /// Physical address of the base of the register block.
+    pub base: u64,
+    /// Size of the register block in bytes.
+    pub size: u64,
+    /// Offset into the block of the register written to reboot, and the value written.
+    pub reboot: (u32, u32),
+    /// Offset into the block of the register written to power off, and the value written.
+    pub poweroff: (u32, u32),
+}
+
 /// Location of memory-mapped vm watchdog
 #[derive(Copy, Clone)]
 pub struct VmWdtConfig {
@@ -373,6 +948,9 @@ fn create_pci_nodes(
     cfg: PciConfigRegion,
     ranges: &[PciRange],
     dma_pool_phandle: Option<u32>,
+    msi_parent: Option<MsiParent>,
+    max_bus: u8,
+    config_access: ConfigAccess,
 ) -> Result<()> {
     // Add devicetree nodes describing a PCI generic host controller.
     // See Documentation/devicetree/bindings/pci/host-generic-pci.txt in the kernel
@@ -398,12 +976,22 @@ fn create_pci_nodes(
         .flatten()
         .collect();
 
-    let bus_range = [0, 0]; // Only bus 0
-    let reg = [cfg.base, cfg.size];
+    let bus_range = [0, max_bus as u32];
+    let cfg_size = (max_bus as u64 + 1) * config_access.bus_size();
+    if cfg_size > cfg.size {
+        return Err(Error::InvalidPropertyValue(format!(
+            "PCI bus range 0-{} needs {:#x} bytes of config space but only {:#x} are available",
+            max_bus, cfg_size, cfg.size
+        )));
+    }
+    // With a single bus, only the device number is needed to select an interrupt-map entry.
+    let bus_mask = if max_bus > 0 { 0xff0000 } else { 0 };
 
     let mut interrupts: Vec<u32> = Vec::new();
     let mut masks: Vec<u32> = Vec::new();
 
+    // Each entry matches one device and pin exactly and maps it to the SPI that the device's INTx
+    // line was connected to, so unlike a map shared by all slots, no INTx swizzle is applied here.
     for (address, irq_num, irq_pin) in pci_irqs.iter() {
         // PCI_DEVICE(3)
         interrupts.push(address.to_config_address(0, 8));
@@ -424,7 +1012,7 @@ fn create_pci_nodes(
         interrupts.push(IRQ_TYPE_LEVEL_HIGH);
 
         // PCI_DEVICE(3)
-        masks.push(0xf800); // bits 11..15 (device)
+        masks.push(bus_mask | 0xf800); // bits 16..23 (bus), bits 11..15 (device)
         masks.push(0);
         masks.push(0);
 
@@ -433,13 +1021,19 @@ fn create_pci_nodes(
     }
 
     let pci_node = fdt.begin_node("pci")?;
-    fdt.property_string("compatible", "pci-host-cam-generic")?;
+    fdt.property_string("compatible", config_access.compatible())?;
     fdt.property_string("device_type", "pci")?;
     fdt.property_array_u32("ranges", &ranges)?;
     fdt.property_array_u32("bus-range", &bus_range)?;
     fdt.property_u32("#address-cells", 3)?;
     fdt.property_u32("#size-cells", 2)?;
-    fdt.property_array_u64("reg", &reg)?;
+    // "reg" is in the parent's address space; the cell sizes above only apply to children.
+    property_reg(
+        fdt,
+        &[(cfg.base, cfg_size)],
+        ROOT_ADDRESS_CELLS,
+        ROOT_SIZE_CELLS,
+    )?;
     fdt.property_u32("#interrupt-cells", 1)?;
     fdt.property_array_u32("interrupt-map", &interrupts)?;
     fdt.property_array_u32("interrupt-map-mask", &masks)?;
@@ -447,39 +1041,109 @@ fn create_pci_nodes(
     if let Some(dma_pool_phandle) = dma_pool_phandle {
         fdt.property_u32("memory-region", dma_pool_phandle)?;
     }
+    // The INTx interrupt-map above stays for devices without MSI support.
+    match msi_parent {
+        Some(MsiParent::V2m(phandle)) => fdt.property_u32("msi-parent", phandle)?,
+        Some(MsiParent::Its(phandle)) => {
+            fdt.property_u32("msi-parent", phandle)?;
+            // Every requester ID is passed through to the ITS as the device ID.
+            fdt.property_array_u32("msi-map", &[0, phandle, 0, 0x10000])?;
+        }
+        None => {}
+    }
     fdt.end_node(pci_node)?;
 
     Ok(())
 }
 
-fn create_rtc_node(fdt: &mut FdtWriter) -> Result<()> {
+fn create_rtc_node(
+    fdt: &mut FdtWriter,
+    rtc_cfg: RtcConfig,
+    phandles: &mut PhandleAllocator,
+) -> Result<()> {
     // the kernel driver for pl030 really really wants a clock node
     // associated with an AMBA device or it will fail to probe, so we
     // need to make up a clock node to associate with the pl030 rtc
     // node and an associated handle with a unique phandle value.
-    const CLK_PHANDLE: u32 = 24;
+    let clk_phandle = phandles.allocate();
     let clock_node = fdt.begin_node("pclk@3M")?;
     fdt.property_u32("#clock-cells", 0)?;
     fdt.property_string("compatible", "fixed-clock")?;
     fdt.property_u32("clock-frequency", 3141592)?;
-    fdt.property_u32("phandle", CLK_PHANDLE)?;
+    fdt.property_u32("phandle", clk_phandle)?;
     fdt.end_node(clock_node)?;
 
-    let rtc_name = format!("rtc@{:x}", AARCH64_RTC_ADDR);
-    let reg = [AARCH64_RTC_ADDR, AARCH64_RTC_SIZE];
-    let irq = [GIC_FDT_IRQ_TYPE_SPI, AARCH64_RTC_IRQ, IRQ_TYPE_LEVEL_HIGH];
+    let rtc_name = format!("rtc@{:x}", rtc_cfg.base);
+    let reg = [rtc_cfg.base, rtc_cfg.size];
+    let irq = [GIC_FDT_IRQ_TYPE_SPI, rtc_cfg.irq, IRQ_TYPE_LEVEL_HIGH];
 
     let rtc_node = fdt.begin_node(&rtc_name)?;
     fdt.property_string("compatible", "arm,primecell")?;
     fdt.property_u32("arm,primecell-periphid", PL030_AMBA_ID)?;
     fdt.property_array_u64("reg", &reg)?;
     fdt.property_array_u32("interrupts", &irq)?;
-    fdt.property_u32("clocks", CLK_PHANDLE)?;
+    fdt.property_u32("clocks", clk_phandle)?;
     fdt.property_string("clock-names", "apb_pclk")?;
     fdt.end_node(rtc_node)?;
     Ok(())
 }
 
+/// Location and SPI range of the virtio-mmio transports described to the guest. The caller must
+/// keep the `count` slots of `AARCH64_VIRTIO_MMIO_SIZE` bytes from `mmio_base`, and the `count`
+/// SPIs from `irq_base`, out of the ranges it hands to other devices.
+#[derive(Copy, Clone)]
+pub struct VirtioMmioConfig {
+    /// Physical address of the first transport slot.
+    pub mmio_base: u64,
+    /// The SPI number of the first transport slot.
+    pub irq_base: u32,
+    /// The number of transport slots.
+    pub count: u32,
+}
+
+/// Returns the name of the virtio-mmio transport node at `addr`.
+fn virtio_mmio_node_name(addr: u64) -> String {
+    format!("virtio_mmio@{:x}", addr)
+}
+
+/// Creates one virtio-mmio transport node per device slot.
+///
+/// # Arguments
+///
+/// * `fdt` - A FdtWriter in which the nodes are created
+/// * `count` - The number of virtio-mmio device slots
+/// * `mmio_base` - The MMIO address of the first device slot
+/// * `mmio_stride` - The size of each device slot
+/// * `irq_base` - The SPI number of the first device slot
+fn create_virtio_mmio_nodes(
+    fdt: &mut FdtWriter,
+    count: u32,
+    mmio_base: u64,
+    mmio_stride: u64,
+    irq_base: u32,
+) -> Result<()> {
+    if count as u64 * mmio_stride > AARCH64_MMIO_SIZE {
+        return Err(Error::InvalidPropertyValue(format!(
+            "{} virtio-mmio devices of size {:#x} do not fit in the MMIO region",
+            count, mmio_stride
+        )));
+    }
+
+    for i in 0..count {
+        let addr = mmio_base + i as u64 * mmio_stride;
+        let reg = [addr, mmio_stride];
+        let irq = [GIC_FDT_IRQ_TYPE_SPI, irq_base + i, IRQ_TYPE_EDGE_RISING];
+
+        let virtio_node = fdt.begin_node(&virtio_mmio_node_name(addr))?;
+        fdt.property_string("compatible", "virtio,mmio")?;
+        fdt.property_array_u64("reg", &reg)?;
+        fdt.property_array_u32("interrupts", &irq)?;
+        fdt.end_node(virtio_node)?;
+    }
+
+    Ok(())
+}
+
 /// Create a flattened device tree node for Goldfish Battery device.
 ///
 /// # Arguments
@@ -510,9 +1174,136 @@ fn create_vmwdt_node(fdt: &mut FdtWriter, vmwdt_cfg: VmWdtConfig) -> Result<()>
     Ok(())
 }
 
+/// Creates a "syscon" node for the register block at `base` and returns its phandle, for the
+/// syscon-reboot and syscon-poweroff nodes to refer to.
+fn create_syscon_node(
+    fdt: &mut FdtWriter,
+    base: u64,
+    size: u64,
+    phandles: &mut PhandleAllocator,
+) -> Result<u32> {
+    let phandle = phandles.allocate();
+    let syscon_node = fdt.begin_node(&format!("syscon@{:x}", base))?;
+    fdt.property_string("compatible", "syscon")?;
+    fdt.property_array_u64("reg", &[base, size])?;
+    fdt.property_u32("phandle", phandle)?;
+    fdt.end_node(syscon_node)?;
+    Ok(phandle)
+}
+
+/// Creates a node telling the guest to reboot by writing `value` at `offset` into the syscon
+/// with phandle `syscon_phandle`.
+fn create_syscon_reboot_node(
+    fdt: &mut FdtWriter,
+    syscon_phandle: u32,
+    offset: u32,
+    value: u32,
+) -> Result<()> {
+    let reboot_node = fdt.begin_node("reboot")?;
+    fdt.property_string("compatible", "syscon-reboot")?;
+    fdt.property_u32("regmap", syscon_phandle)?;
+    fdt.property_u32("offset", offset)?;
+    fdt.property_u32("value", value)?;
+    fdt.end_node(reboot_node)?;
+    Ok(())
+}
+
+/// Creates a node telling the guest to power off by writing `value` at `offset` into the syscon
+/// with phandle `syscon_phandle`.
+fn create_syscon_poweroff_node(
+    fdt: &mut FdtWriter,
+    syscon_phandle: u32,
+    offset: u32,
+    value: u32,
+) -> Result<()> {
+    let poweroff_node = fdt.begin_node("poweroff")?;
+    fdt.property_string("compatible", "syscon-poweroff")?;
+    fdt.property_u32("regmap", syscon_phandle)?;
+    fdt.property_u32("offset", offset)?;
+    fdt.property_u32("value", value)?;
+    fdt.end_node(poweroff_node)?;
+    Ok(())
+}
+
+fn create_syscon_nodes(
+    fdt: &mut FdtWriter,
+    cfg: SysconConfig,
+    phandles: &mut PhandleAllocator,
+) -> Result<()> {
+    let syscon_phandle = create_syscon_node(fdt, cfg.base, cfg.size, phandles)?;
+    create_syscon_reboot_node(fdt, syscon_phandle, cfg.reboot.0, cfg.reboot.1)?;
+    create_syscon_poweroff_node(fdt, syscon_phandle, cfg.poweroff.0, cfg.poweroff.1)
+}
+
+/// Checks that an FDT of `fdt_len` bytes at `fdt_address` doesn't intersect any of the
+/// `occupied` (address, size) ranges, e.g. where the kernel or initrd were loaded.
+fn check_fdt_placement(
+    fdt_address: GuestAddress,
+    fdt_len: usize,
+    occupied: &[(GuestAddress, usize)],
+) -> Result<()> {
+    let fdt_start = fdt_address.offset();
+    let fdt_end = fdt_start.saturating_add(fdt_len as u64);
+    for &(addr, size) in occupied {
+        let start = addr.offset();
+        let end = start.saturating_add(size as u64);
+        if start < fdt_end && fdt_start < end {
+            return Err(Error::FdtPlacementConflict(fdt_start, fdt_len, start, size));
+        }
+    }
+    Ok(())
+}
+
+/// Optional features of the device tree created by `create_fdt`. The default describes none of
+/// them.
+#[derive(Clone, Default)]
+pub struct FdtOptions {
+    /// The highest PCI bus number behind the host controller.
+    pub pci_max_bus: u8,
+    /// How the PCI configuration space is accessed.
+    pub pci_config_access: ConfigAccess,
+    /// How the guest brings up secondary CPUs.
+    pub cpu_enable_method: CpuEnableMethod,
+    /// CPUs that are described but left powered off at boot.
+    pub powered_off_cpus: Vec<u32>,
+    /// Extra arguments appended to the kernel command line, separated by a space.
+    pub cmdline_append: Option<String>,
+    /// A GICv2m MSI frame, only valid with a GICv2.
+    pub gic_v2m: Option<GicV2mConfig>,
+    /// An ITS that PCI devices send MSIs to, only valid with a GICv3.
+    pub gic_its: Option<GicItsConfig>,
+    /// The PPI of the GIC maintenance interrupt, e.g. 9 for INTID 25, for guests using the GIC's
+    /// virtualization support; only valid with a GICv3.
+    pub gic_maintenance_irq: Option<u32>,
+    /// The instruction the guest uses to make PSCI calls.
+    pub psci_conduit: PsciConduit,
+    /// A register block the guest writes to reboot or power off, described in addition to PSCI,
+    /// which remains the default reset path.
+    pub syscon: Option<SysconConfig>,
+    /// How the serial nodes describe their input clock.
+    pub serial_clock: SerialClock,
+    /// Where the virtio-mmio transport nodes to create are.
+    pub virtio_mmio: Option<VirtioMmioConfig>,
+    /// The virtio-mmio transport of the console, pointed to by "stdout-path" instead of the first
+    /// UART; earlycon can still be chosen through `cmdline_append`.
+    pub virtio_console: Option<u32>,
+    /// Describe the OP-TEE secure monitor interface to the guest.
+    pub use_optee: bool,
+    /// Advertise the KVM paravirtual interface through a `/hypervisor` node.
+    pub use_kvm_pv: bool,
+    /// Describe each RAM bank in its own memory node with NUMA node 0, for guests that expect one
+    /// node per bank, e.g. with RAM split at the 4GB boundary.
+    pub split_memory_banks: bool,
+    /// How the kernel was loaded, described in the chosen node where provided.
+    pub chosen_boot_info: ChosenBootInfo,
+}
+
 /// Creates a flattened device tree containing all of the parameters for the
 /// kernel and loads it into the guest memory at the specified offset.
 ///
+/// Returns the number of bytes the device tree occupies, its header's totalsize, which is usually
+/// much less than `fdt_max_size`, so that the caller can place other images right after it.
+///
 /// # Arguments
 ///
 /// * `fdt_max_size` - The amount of space reserved for the device tree
@@ -521,16 +1312,22 @@ fn create_vmwdt_node(fdt: &mut FdtWriter, vmwdt_cfg: VmWdtConfig) -> Result<()>
 /// * `pci_cfg` - Location of the memory-mapped PCI configuration space.
 /// * `pci_ranges` - Memory ranges accessible via the PCI host controller.
 /// * `num_cpus` - Number of virtual CPUs the guest will have
+/// * `phys_mem_start` - The guest physical address RAM starts at, usually
+///   `AARCH64_PHYS_MEM_START`
 /// * `fdt_load_offset` - The offset into physical memory for the device tree
 /// * `cmdline` - The kernel commandline
 /// * `initrd` - An optional tuple of initrd guest physical address and size
+/// * `occupied` - Guest ranges, as (address, size), that the device tree must not overlap
 /// * `android_fstab` - An optional file holding Android fstab entries
 /// * `is_gicv3` - True if gicv3, false if v2
+/// * `pmu_cpu_mask` - The CPUs with a PMU, bit n for CPU n, or None to omit the PMU node
 /// * `psci_version` - the current PSCI version
 /// * `bat_mmio_base` - The battery base address
 /// * `bat_irq` - The battery irq number
 /// * `swiotlb` - Reserve a memory pool for DMA
 /// * `vmwdt_cfg` - The virtual watchdog configuration
+/// * `rtc_cfg` - The RTC configuration
+/// * `options` - The optional features of the device tree
 pub fn create_fdt(
     fdt_max_size: usize,
     guest_mem: &GuestMemory,
@@ -540,65 +1337,1207 @@ pub fn create_fdt(
     num_cpus: u32,
     cpu_clusters: Vec<CpuSet>,
     cpu_capacity: BTreeMap<usize, u32>,
-    fdt_address: GuestAddress,
+    phys_mem_start: u64,
+    fdt_load_offset: u64,
     cmdline: &str,
     image: (GuestAddress, usize),
     initrd: Option<(GuestAddress, usize)>,
+    occupied: &[(GuestAddress, usize)],
     android_fstab: Option<File>,
     is_gicv3: bool,
-    use_pmu: bool,
+    pmu_cpu_mask: Option<u32>,
     psci_version: PsciVersion,
     swiotlb: Option<u64>,
     bat_mmio_base_and_irq: Option<(u64, u32)>,
     vmwdt_cfg: VmWdtConfig,
-) -> Result<()> {
-    let mut fdt = FdtWriter::new(&[]);
+    rtc_cfg: RtcConfig,
+    options: FdtOptions,
+) -> Result<usize> {
+    let mem_reservations: Vec<FdtReserveEntry> =
+        cpu_release_reservation(num_cpus, options.cpu_enable_method)
+            .into_iter()
+            .collect();
+    let mut fdt = FdtWriter::new(&mem_reservations);
+    let mut phandles = PhandleAllocator::new();
+    let fdt_address = GuestAddress(phys_mem_start + fdt_load_offset);
 
     // The whole thing is put into one giant node with some top level properties
     let root_node = fdt.begin_node("")?;
     fdt.property_u32("interrupt-parent", PHANDLE_GIC)?;
     fdt.property_string("compatible", "linux,dummy-virt")?;
-    fdt.property_u32("#address-cells", 0x2)?;
-    fdt.property_u32("#size-cells", 0x2)?;
-    if let Some(android_fstab) = android_fstab {
-        arch::android::create_android_fdt(&mut fdt, android_fstab)?;
+    fdt.property_u32("#address-cells", ROOT_ADDRESS_CELLS)?;
+    fdt.property_u32("#size-cells", ROOT_SIZE_CELLS)?;
+    create_firmware_node(&mut fdt, android_fstab, options.use_optee)?;
+    let stdout_path = stdout_path(options.virtio_console, options.virtio_mmio)?;
+    match options.cmdline_append.as_deref() {
+        Some(append) => {
+            let base = CString::new(cmdline).map_err(|_| Error::InvalidString)?;
+            create_chosen_node_append(
+                &mut fdt,
+                &base,
+                append,
+                fdt_max_size,
+                initrd,
+                &stdout_path,
+                options.chosen_boot_info,
+            )?
+        }
+        None => create_chosen_node(
+            &mut fdt,
+            cmdline,
+            initrd,
+            &stdout_path,
+            options.chosen_boot_info,
+        )?,
     }
-    create_chosen_node(&mut fdt, cmdline, initrd)?;
     create_config_node(&mut fdt, image)?;
-    create_memory_node(&mut fdt, guest_mem)?;
-    let dma_pool_phandle = create_resv_memory_node(&mut fdt, swiotlb)?;
-    create_cpu_nodes(&mut fdt, num_cpus, cpu_clusters, cpu_capacity)?;
-    create_gic_node(&mut fdt, is_gicv3, num_cpus as u64)?;
+    create_memory_node(
+        &mut fdt,
+        &memory_banks(guest_mem, phys_mem_start),
+        options.split_memory_banks,
+    )?;
+    let dma_pool_phandle = create_resv_memory_node(&mut fdt, swiotlb, &mut phandles)?;
+    create_cpu_nodes(
+        &mut fdt,
+        num_cpus,
+        cpu_clusters,
+        cpu_capacity,
+        options.cpu_enable_method,
+        &options.powered_off_cpus,
+        &mut phandles,
+    )?;
+    let msi_parent = create_gic_node(
+        &mut fdt,
+        is_gicv3,
+        num_cpus as u64,
+        options.gic_v2m,
+        options.gic_its,
+        options.gic_maintenance_irq,
+        &mut phandles,
+    )?;
     create_timer_node(&mut fdt, num_cpus)?;
-    if use_pmu {
-        create_pmu_node(&mut fdt, num_cpus)?;
+    if let Some(cpu_mask) = pmu_cpu_mask {
+        create_pmu_node(&mut fdt, cpu_mask)?;
+    }
+    create_default_serial_nodes(&mut fdt, options.serial_clock, &mut phandles)?;
+    create_psci_node(&mut fdt, &psci_version, options.psci_conduit)?;
+    if let Some(cfg) = options.syscon {
+        create_syscon_nodes(&mut fdt, cfg, &mut phandles)?;
+    }
+    if options.use_kvm_pv {
+        create_hypervisor_node(&mut fdt)?;
     }
-    create_serial_nodes(&mut fdt)?;
-    create_psci_node(&mut fdt, &psci_version)?;
-    create_pci_nodes(&mut fdt, pci_irqs, pci_cfg, pci_ranges, dma_pool_phandle)?;
-    create_rtc_node(&mut fdt)?;
+    create_pci_nodes(
+        &mut fdt,
+        pci_irqs,
+        pci_cfg,
+        pci_ranges,
+        dma_pool_phandle,
+        msi_parent,
+        options.pci_max_bus,
+        options.pci_config_access,
+    )?;
+    create_rtc_node(&mut fdt, rtc_cfg, &mut phandles)?;
     if let Some((bat_mmio_base, bat_irq)) = bat_mmio_base_and_irq {
         create_battery_node(&mut fdt, bat_mmio_base, bat_irq)?;
     }
     create_vmwdt_node(&mut fdt, vmwdt_cfg)?;
+    if let Some(cfg) = options.virtio_mmio {
+        create_virtio_mmio_nodes(
+            &mut fdt,
+            cfg.count,
+            cfg.mmio_base,
+            AARCH64_VIRTIO_MMIO_SIZE,
+            cfg.irq_base,
+        )?;
+    }
     // End giant node
     fdt.end_node(root_node)?;
 
-    let fdt_final = fdt.finish(fdt_max_size)?;
+    // Only the bytes of the tree itself are written; the rest of the `fdt_max_size` region is
+    // left untouched since the guest only reads up to the header's totalsize.
+    let fdt_final = fdt.finish_unpadded(fdt_max_size)?;
+    check_fdt_placement(fdt_address, fdt_final.len(), occupied)?;
+
+    write_fdt(guest_mem, &fdt_final, fdt_address)
+}
 
+/// Writes the device tree `blob` to `guest_mem` at `fdt_address`, failing unless all of the
+/// `totalsize` bytes given by its header were written. Returns the totalsize.
+fn write_fdt(guest_mem: &GuestMemory, blob: &[u8], fdt_address: GuestAddress) -> Result<usize> {
+    let totalsize = blob
+        .get(4..8)
+        .map(|size| u32::from_be_bytes(size.try_into().unwrap()) as usize)
+        .filter(|&size| size <= blob.len())
+        .ok_or(Error::FdtGuestMemoryWriteError)?;
     let written = guest_mem
-        .write_at_addr(fdt_final.as_slice(), fdt_address)
+        .write_at_addr(&blob[..totalsize], fdt_address)
         .map_err(|_| Error::FdtGuestMemoryWriteError)?;
-    if written < fdt_max_size {
+    if written < totalsize {
         return Err(Error::FdtGuestMemoryWriteError);
     }
-    Ok(())
+    Ok(totalsize)
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::AARCH64_IRQ_BASE;
+
     use super::*;
 
+    fn build_fdt(f: impl FnOnce(&mut FdtWriter) -> Result<()>) -> FdtWriter {
+        let mut fdt = FdtWriter::new(&[]);
+        let root_node = fdt.begin_node("").unwrap();
+        f(&mut fdt).unwrap();
+        fdt.end_node(root_node).unwrap();
+        fdt
+    }
+
+    /// Parses a DTB and returns every property as a (node path, property name, value) tuple.
+    fn fdt_properties(blob: &[u8]) -> Vec<(String, String, Vec<u8>)> {
+        let be32 = |off: usize| u32::from_be_bytes(blob[off..off + 4].try_into().unwrap());
+        let cstr = |off: usize| {
+            let end = off + blob[off..].iter().position(|&b| b == 0).unwrap();
+            (String::from_utf8(blob[off..end].to_vec()).unwrap(), end + 1)
+        };
+        let off_dt_strings = be32(12) as usize;
+        let mut off = be32(8) as usize;
+        let mut path: Vec<String> = Vec::new();
+        let mut props = Vec::new();
+        loop {
+            let token = be32(off);
+            off += 4;
+            match token {
+                1 => {
+                    let (name, end) = cstr(off);
+                    path.push(name);
+                    off = (end + 3) & !3;
+                }
+                2 => {
+                    path.pop();
+                }
+                3 => {
+                    let len = be32(off) as usize;
+                    let (name, _) = cstr(off_dt_strings + be32(off + 4) as usize);
+                    let value = blob[off + 8..off + 8 + len].to_vec();
+                    props.push((path.join("/"), name, value));
+                    off = (off + 8 + len + 3) & !3;
+                }
+                9 => break,
+                t => panic!("unexpected FDT token {}", t),
+            }
+        }
+        props
+    }
+
+    /// Returns the value of property `name` in the node at `path`, if present.
+    fn fdt_property(blob: &[u8], path: &str, name: &str) -> Option<Vec<u8>> {
+        fdt_properties(blob)
+            .into_iter()
+            .find(|(p, n, _)| p == path && n == name)
+            .map(|(_, _, v)| v)
+    }
+
+    fn u32_cells(value: &[u8]) -> Vec<u32> {
+        value
+            .chunks(4)
+            .map(|c| u32::from_be_bytes(c.try_into().unwrap()))
+            .collect()
+    }
+
+    fn u64_cells(value: &[u8]) -> Vec<u64> {
+        value
+            .chunks(8)
+            .map(|c| u64::from_be_bytes(c.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn unpadded_blob_matches_padded() {
+        let guest_mem = GuestMemory::new(&[(GuestAddress(0x8000_0000), 0x10000)]).unwrap();
+        let nodes = |fdt: &mut FdtWriter| {
+            create_memory_node(fdt, &memory_banks(&guest_mem, 0x8000_0000), false)?;
+            create_gic_node(fdt, true, 2, None, None, None, &mut PhandleAllocator::new())?;
+            create_timer_node(fdt, 2)?;
+            create_default_serial_nodes(fdt, SerialClock::Frequency, &mut PhandleAllocator::new())?;
+            create_psci_node(fdt, &PsciVersion::new(1, 0).unwrap(), PsciConduit::Hvc)?;
+            create_rtc_node(
+                fdt,
+                RtcConfig {
+                    base: 0x2000,
+                    size: 0x1000,
+                    irq: 1,
+                },
+                &mut PhandleAllocator::new(),
+            )
+        };
+        let padded = build_fdt(nodes).finish(0x10000).unwrap();
+        let unpadded = build_fdt(nodes).finish_unpadded(0x10000).unwrap();
+        assert_eq!(unpadded[..], padded[..unpadded.len()]);
+        assert!(padded[unpadded.len()..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn split_memory_banks() {
+        let banks = [(0x8000_0000, 0x8000_0000), (0x1_0000_0000, 0x4000_0000)];
+        let blob = build_fdt(|fdt| create_memory_node(fdt, &banks, true))
+            .finish(0x10000)
+            .unwrap();
+
+        let memory_nodes: Vec<String> = fdt_properties(&blob)
+            .into_iter()
+            .filter(|(_, name, value)| name == "device_type" && value == b"memory\0")
+            .map(|(path, _, _)| path)
+            .collect();
+        assert_eq!(memory_nodes, ["/memory@80000000", "/memory@100000000"]);
+        for (path, &(base, size)) in memory_nodes.iter().zip(banks.iter()) {
+            let reg = fdt_property(&blob, path, "reg").unwrap();
+            assert_eq!(u64_cells(&reg), [base, size]);
+            let numa_node_id = fdt_property(&blob, path, "numa-node-id").unwrap();
+            assert_eq!(u32_cells(&numa_node_id), [0]);
+        }
+    }
+
+    #[test]
+    fn pci_interrupt_map_many_devices() {
+        let cfg = PciConfigRegion {
+            base: 0x10000,
+            size: 0x1000000,
+        };
+        let pins = [
+            PciInterruptPin::IntA,
+            PciInterruptPin::IntB,
+            PciInterruptPin::IntC,
+            PciInterruptPin::IntD,
+        ];
+        // Eight devices, using the pins in turn, each with its own SPI.
+        let pci_irqs: Vec<(PciAddress, u32, PciInterruptPin)> = (0..8)
+            .map(|i| {
+                (
+                    PciAddress::new(0, 0, i + 1, 0).unwrap(),
+                    AARCH64_IRQ_BASE + i,
+                    pins[i as usize % 4],
+                )
+            })
+            .collect();
+        let blob = build_fdt(|fdt| {
+            create_pci_nodes(
+                fdt,
+                pci_irqs.clone(),
+                cfg,
+                &[],
+                None,
+                None,
+                0,
+                ConfigAccess::Cam,
+            )
+        })
+        .finish_unpadded(0x10000)
+        .unwrap();
+
+        let map = u32_cells(&fdt_property(&blob, "/pci", "interrupt-map").unwrap());
+        assert_eq!(map.len(), 8 * 10);
+        for (entry, (address, irq, pin)) in map.chunks(10).zip(pci_irqs.iter()) {
+            // The unit address and pin of the device select the SPI it was assigned.
+            assert_eq!(entry[0], (address.dev as u32) << 11);
+            assert_eq!(entry[3], pin.to_mask() + 1);
+            assert_eq!(
+                &entry[7..],
+                [GIC_FDT_IRQ_TYPE_SPI, *irq, IRQ_TYPE_LEVEL_HIGH]
+            );
+        }
+        let mask = u32_cells(&fdt_property(&blob, "/pci", "interrupt-map-mask").unwrap());
+        assert_eq!(&mask[..4], [0xf800, 0, 0, 0x7]);
+    }
+
+    #[test]
+    fn pci_multiple_buses() {
+        let cfg = PciConfigRegion {
+            base: 0x10000,
+            size: 0x1000000,
+        };
+        let pci_irqs = vec![(
+            PciAddress::new(0, 2, 1, 0).unwrap(),
+            5,
+            PciInterruptPin::IntA,
+        )];
+        let blob = build_fdt(|fdt| {
+            create_pci_nodes(fdt, pci_irqs, cfg, &[], None, None, 3, ConfigAccess::Cam)
+        })
+        .finish_unpadded(0x10000)
+        .unwrap();
+        let bus_range = fdt_property(&blob, "/pci", "bus-range").unwrap();
+        assert_eq!(u32_cells(&bus_range), [0, 3]);
+        let reg = fdt_property(&blob, "/pci", "reg").unwrap();
+        assert_eq!(u64_cells(&reg), [0x10000, 4 * PCI_CAM_BUS_SIZE]);
+        let map = u32_cells(&fdt_property(&blob, "/pci", "interrupt-map").unwrap());
+        assert_eq!(map[0], 0x20800);
+        let mask = u32_cells(&fdt_property(&blob, "/pci", "interrupt-map-mask").unwrap());
+        assert_eq!(mask[0], 0xfff800);
+    }
+
+    #[test]
+    fn pci_ecam() {
+        let cfg = PciConfigRegion {
+            base: 0x10000,
+            size: 0x10000000,
+        };
+        let blob = build_fdt(|fdt| {
+            create_pci_nodes(fdt, Vec::new(), cfg, &[], None, None, 1, ConfigAccess::Ecam)
+        })
+        .finish_unpadded(0x10000)
+        .unwrap();
+        assert_eq!(
+            fdt_property(&blob, "/pci", "compatible").unwrap(),
+            b"pci-host-ecam-generic\0"
+        );
+        let reg = fdt_property(&blob, "/pci", "reg").unwrap();
+        // 2 buses * 32 devices * 8 functions * 4KiB.
+        assert_eq!(u64_cells(&reg), [0x10000, 2 * 32 * 8 * 0x1000]);
+    }
+
+    #[test]
+    fn virtio_mmio_nodes() {
+        let blob = build_fdt(|fdt| create_virtio_mmio_nodes(fdt, 4, 0x2000000, 0x200, 4))
+            .finish_unpadded(0x10000)
+            .unwrap();
+        for i in 0..4 {
+            let addr = 0x2000000 + i * 0x200;
+            let path = format!("/virtio_mmio@{:x}", addr);
+            assert_eq!(
+                fdt_property(&blob, &path, "compatible").unwrap(),
+                b"virtio,mmio\0"
+            );
+            let reg = fdt_property(&blob, &path, "reg").unwrap();
+            assert_eq!(u64_cells(&reg), [addr, 0x200]);
+            let irq = fdt_property(&blob, &path, "interrupts").unwrap();
+            assert_eq!(
+                u32_cells(&irq),
+                [GIC_FDT_IRQ_TYPE_SPI, 4 + i as u32, IRQ_TYPE_EDGE_RISING]
+            );
+        }
+    }
+
+    #[test]
+    fn reg_cells() {
+        let blob = build_fdt(|fdt| property_reg(fdt, &[(0x1000, 0x100), (0x2000, 0x200)], 1, 1))
+            .finish_unpadded(0x10000)
+            .unwrap();
+        assert_eq!(
+            u32_cells(&fdt_property(&blob, "", "reg").unwrap()),
+            [0x1000, 0x100, 0x2000, 0x200]
+        );
+
+        let mut fdt = FdtWriter::new(&[]);
+        // An address above 4GiB does not fit in a single cell.
+        assert!(property_reg(&mut fdt, &[(0x1_0000_0000, 0x1000)], 1, 2).is_err());
+        assert!(property_reg(&mut fdt, &[(0x1000, 0x1_0000_0000)], 2, 1).is_err());
+        // A node with #size-cells = 0 cannot describe a nonzero size.
+        assert!(property_reg(&mut fdt, &[(0x1000, 0x10)], 2, 0).is_err());
+        assert!(property_reg(&mut fdt, &[(0x1000, 0x10)], 3, 2).is_err());
+    }
+
+    #[test]
+    fn virtio_mmio_nodes_too_many() {
+        let mut fdt = FdtWriter::new(&[]);
+        let count = (AARCH64_MMIO_SIZE / 0x200) as u32 + 1;
+        assert!(create_virtio_mmio_nodes(&mut fdt, count, 0x2000000, 0x200, 4).is_err());
+    }
+
+    #[test]
+    fn optee_node() {
+        let blob = build_fdt(|fdt| create_firmware_node(fdt, None, true))
+            .finish_unpadded(0x10000)
+            .unwrap();
+        assert_eq!(
+            fdt_property(&blob, "/firmware/optee", "compatible").unwrap(),
+            b"linaro,optee-tz\0"
+        );
+        assert_eq!(
+            fdt_property(&blob, "/firmware/optee", "method").unwrap(),
+            b"smc\0"
+        );
+    }
+
+    #[test]
+    fn hypervisor_node() {
+        let blob = build_fdt(create_hypervisor_node)
+            .finish_unpadded(0x10000)
+            .unwrap();
+        assert_eq!(
+            fdt_property(&blob, "/hypervisor", "compatible").unwrap(),
+            b"linux,kvm\0"
+        );
+    }
+
+    #[test]
+    fn chosen_bootargs_append() {
+        let base = CString::new("console=ttyS0 root=/dev/vda").unwrap();
+        let blob = build_fdt(|fdt| {
+            create_chosen_node_append(
+                fdt,
+                &base,
+                "loglevel=8",
+                0x1000,
+                None,
+                "/",
+                Default::default(),
+            )
+        })
+        .finish_unpadded(0x10000)
+        .unwrap();
+        assert_eq!(
+            fdt_property(&blob, "/chosen", "bootargs").unwrap(),
+            b"console=ttyS0 root=/dev/vda loglevel=8\0"
+        );
+
+        let mut fdt = FdtWriter::new(&[]);
+        assert!(create_chosen_node_append(
+            &mut fdt,
+            &base,
+            "loglevel=8",
+            16,
+            None,
+            "/",
+            Default::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn chosen_boot_info() {
+        let blob = build_fdt(|fdt| {
+            create_chosen_node(
+                fdt,
+                "",
+                Some((GuestAddress(0x8100_0000), 0x1000)),
+                "/",
+                ChosenBootInfo {
+                    kernel_load_addr: Some(GuestAddress(0x8008_0000)),
+                    boot_protocol_version: Some(2),
+                },
+            )
+        })
+        .finish_unpadded(0x10000)
+        .unwrap();
+        let load_addr = fdt_property(&blob, "/chosen", "linux,kernel-load-addr").unwrap();
+        assert_eq!(u64_cells(&load_addr), [0x8008_0000]);
+        let version = fdt_property(&blob, "/chosen", "linux,boot-protocol-version").unwrap();
+        assert_eq!(u32_cells(&version), [2]);
+        let initrd_start = fdt_property(&blob, "/chosen", "linux,initrd-start").unwrap();
+        assert_eq!(u32_cells(&initrd_start), [0x8100_0000]);
+
+        let blob = build_fdt(|fdt| create_chosen_node(fdt, "", None, "/", Default::default()))
+            .finish_unpadded(0x10000)
+            .unwrap();
+        assert_eq!(
+            fdt_property(&blob, "/chosen", "linux,kernel-load-addr"),
+            None
+        );
+        assert_eq!(
+            fdt_property(&blob, "/chosen", "linux,boot-protocol-version"),
+            None
+        );
+    }
+
+    /// Creates a minimal device tree with `create_fdt` in `guest_mem`, which has RAM at
+    /// `phys_mem_start`.
+    fn create_test_fdt(
+        guest_mem: &GuestMemory,
+        phys_mem_start: u64,
+        fdt_load_offset: u64,
+        options: FdtOptions,
+    ) -> Result<usize> {
+        create_fdt(
+            0x20_0000,
+            guest_mem,
+            Vec::new(),
+            PciConfigRegion {
+                base: 0x1000_0000,
+                size: 0x100_0000,
+            },
+            &[],
+            1,
+            Vec::new(),
+            BTreeMap::new(),
+            phys_mem_start,
+            fdt_load_offset,
+            "console=ttyS0",
+            (GuestAddress(phys_mem_start + 0x80_0000), 0x1000),
+            None,
+            &[],
+            None,
+            false,
+            None,
+            PSCI_0_2,
+            None,
+            None,
+            VmWdtConfig {
+                base: 0x3000,
+                size: 0x1000,
+                clock_hz: 2,
+                timeout_sec: 10,
+            },
+            RtcConfig {
+                base: 0x2000,
+                size: 0x1000,
+                irq: 1,
+            },
+            options,
+        )
+    }
+
+    #[test]
+    fn create_fdt_returns_totalsize() {
+        let fdt_address = GuestAddress(0x8000_0000);
+        let guest_mem = GuestMemory::new(&[(fdt_address, 0x100_0000)]).unwrap();
+        let size = create_test_fdt(&guest_mem, 0x8000_0000, 0, Default::default()).unwrap();
+        assert!(size < 0x20_0000);
+
+        let mut header = [0u8; 8];
+        guest_mem.read_at_addr(&mut header, fdt_address).unwrap();
+        assert_eq!(
+            size,
+            u32::from_be_bytes(header[4..8].try_into().unwrap()) as usize
+        );
+    }
+
+    #[test]
+    fn create_fdt_phys_mem_start() {
+        let phys_mem_start = 0x4000_0000;
+        let guest_mem = GuestMemory::new(&[(GuestAddress(phys_mem_start), 0x100_0000)]).unwrap();
+        let size =
+            create_test_fdt(&guest_mem, phys_mem_start, 0x10_0000, Default::default()).unwrap();
+
+        let mut blob = vec![0u8; size];
+        guest_mem
+            .read_at_addr(&mut blob, GuestAddress(phys_mem_start + 0x10_0000))
+            .unwrap();
+        assert_eq!(
+            u64_cells(&fdt_property(&blob, "/memory", "reg").unwrap()),
+            [phys_mem_start, 0x100_0000]
+        );
+    }
+
+    #[test]
+    fn create_fdt_options() {
+        let guest_mem = GuestMemory::new(&[(GuestAddress(0x8000_0000), 0x100_0000)]).unwrap();
+        let size = create_test_fdt(
+            &guest_mem,
+            0x8000_0000,
+            0,
+            FdtOptions {
+                cmdline_append: Some(String::from("debug")),
+                psci_conduit: PsciConduit::Smc,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let mut blob = vec![0u8; size];
+        guest_mem
+            .read_at_addr(&mut blob, GuestAddress(0x8000_0000))
+            .unwrap();
+        assert_eq!(
+            fdt_property(&blob, "/chosen", "bootargs").unwrap(),
+            b"console=ttyS0 debug\0"
+        );
+        assert_eq!(fdt_property(&blob, "/psci", "method").unwrap(), b"smc\0");
+    }
+
+    #[test]
+    fn syscon_reboot_node() {
+        let blob = build_fdt(|fdt| {
+            let phandle = create_syscon_node(fdt, 0x9000, 0x1000, &mut PhandleAllocator::new())?;
+            create_syscon_reboot_node(fdt, phandle, 0x10, 0x7777)
+        })
+        .finish_unpadded(0x10000)
+        .unwrap();
+
+        let phandle = fdt_property(&blob, "/syscon@9000", "phandle").unwrap();
+        assert_eq!(fdt_property(&blob, "/reboot", "regmap").unwrap(), phandle);
+        assert_eq!(
+            fdt_property(&blob, "/reboot", "compatible").unwrap(),
+            b"syscon-reboot\0"
+        );
+        assert_eq!(
+            u32_cells(&fdt_property(&blob, "/reboot", "offset").unwrap()),
+            [0x10]
+        );
+        assert_eq!(
+            u32_cells(&fdt_property(&blob, "/reboot", "value").unwrap()),
+            [0x7777]
+        );
+    }
+
+    #[test]
+    fn write_fdt_short_write() {
+        let blob = build_fdt(|fdt| create_psci_node(fdt, &PSCI_0_2, PsciConduit::Hvc))
+            .finish_unpadded(0x10000)
+            .unwrap();
+        let fits = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        write_fdt(&fits, &blob, GuestAddress(0)).unwrap();
+
+        // Only the first 16 bytes of the blob land in guest memory.
+        let short = GuestAddress(0x1000 - 16);
+        assert!(matches!(
+            write_fdt(&fits, &blob, short),
+            Err(Error::FdtGuestMemoryWriteError)
+        ));
+    }
+
+    #[test]
+    fn stdout_path_virtio_console() {
+        let virtio_mmio = VirtioMmioConfig {
+            mmio_base: 0x3000000,
+            irq_base: 40,
+            count: 4,
+        };
+        let uart = stdout_path(None, Some(virtio_mmio)).unwrap();
+        let console = stdout_path(Some(2), Some(virtio_mmio)).unwrap();
+        assert!(stdout_path(Some(4), Some(virtio_mmio)).is_err());
+        assert!(stdout_path(Some(0), None).is_err());
+
+        let blob = build_fdt(|fdt| {
+            create_chosen_node(fdt, "", None, &console, Default::default())?;
+            create_virtio_mmio_nodes(
+                fdt,
+                virtio_mmio.count,
+                virtio_mmio.mmio_base,
+                AARCH64_VIRTIO_MMIO_SIZE,
+                virtio_mmio.irq_base,
+            )
+        })
+        .finish_unpadded(0x10000)
+        .unwrap();
+        let path = fdt_property(&blob, "/chosen", "stdout-path").unwrap();
+        let path = std::str::from_utf8(&path).unwrap().trim_end_matches('\0');
+        assert_ne!(path, uart);
+        // The path names the third virtio-mmio node rather than the UART.
+        assert!(fdt_property(&blob, path, "compatible").is_some());
+        assert_eq!(
+            u64_cells(&fdt_property(&blob, path, "reg").unwrap())[0],
+            virtio_mmio.mmio_base + 2 * AARCH64_VIRTIO_MMIO_SIZE
+        );
+    }
+
+    #[test]
+    fn fdt_placement_conflict() {
+        let kernel = (GuestAddress(0x8008_0000), 0x20_0000);
+        let initrd = (GuestAddress(0x8100_0000), 0x10_0000);
+        check_fdt_placement(GuestAddress(0x9000_0000), 0x1000, &[kernel, initrd]).unwrap();
+        // Ranges that only touch the FDT don't conflict.
+        check_fdt_placement(GuestAddress(0x8007_f000), 0x1000, &[kernel]).unwrap();
+        assert!(matches!(
+            check_fdt_placement(GuestAddress(0x8010_0000), 0x1000, &[initrd, kernel]),
+            Err(Error::FdtPlacementConflict(
+                0x8010_0000,
+                0x1000,
+                0x8008_0000,
+                0x20_0000
+            ))
+        ));
+    }
+
+    #[test]
+    fn ppi_cpu_masks() {
+        // The value create_timer_node used to compute inline for 4 cpus.
+        assert_eq!(
+            ppi_cpu_mask(4),
+            (((1 << 4) - 1) << GIC_FDT_IRQ_PPI_CPU_SHIFT) & GIC_FDT_IRQ_PPI_CPU_MASK
+        );
+        assert_eq!(ppi_cpu_mask(0), 0);
+        // Larger (GICv3) guests saturate at the 8 cpus the field can name.
+        assert_eq!(ppi_cpu_mask(8), GIC_FDT_IRQ_PPI_CPU_MASK);
+        assert_eq!(ppi_cpu_mask(64), GIC_FDT_IRQ_PPI_CPU_MASK);
+        assert_eq!(ppi_affinity(0b0110), 0b0110 << GIC_FDT_IRQ_PPI_CPU_SHIFT);
+    }
+
+    #[test]
+    fn pmu_cpu_subset() {
+        let blob = build_fdt(|fdt| create_pmu_node(fdt, 0b0110))
+            .finish_unpadded(0x10000)
+            .unwrap();
+        let interrupts = u32_cells(&fdt_property(&blob, "/pmu", "interrupts").unwrap());
+        assert_eq!(
+            interrupts,
+            [
+                GIC_FDT_IRQ_TYPE_PPI,
+                AARCH64_PMU_IRQ,
+                (0b0110 << GIC_FDT_IRQ_PPI_CPU_SHIFT) | IRQ_TYPE_LEVEL_HIGH
+            ]
+        );
+
+        let mut fdt = FdtWriter::new(&[]);
+        assert!(create_pmu_node(&mut fdt, 0).is_err());
+    }
+
+    #[test]
+    fn gic_v2m_frame() {
+        let v2m = GicV2mConfig {
+            base: 0x8020000,
+            size: 0x1000,
+            spi_base: 64,
+            num_spis: 32,
+        };
+        let cfg = PciConfigRegion {
+            base: 0x10000,
+            size: PCI_CAM_BUS_SIZE,
+        };
+        let mut phandles = PhandleAllocator::new();
+        let blob = build_fdt(|fdt| {
+            let msi_parent = create_gic_node(fdt, false, 2, Some(v2m), None, None, &mut phandles)?;
+            assert!(msi_parent.is_some());
+            create_pci_nodes(
+                fdt,
+                Vec::new(),
+                cfg,
+                &[],
+                None,
+                msi_parent,
+                0,
+                ConfigAccess::Cam,
+            )
+        })
+        .finish_unpadded(0x10000)
+        .unwrap();
+
+        let node = "/intc/v2m@8020000";
+        assert_eq!(
+            fdt_property(&blob, node, "compatible").unwrap(),
+            b"arm,gic-v2m-frame\0"
+        );
+        assert_eq!(fdt_property(&blob, node, "msi-controller").unwrap(), b"");
+        assert_eq!(
+            u32_cells(&fdt_property(&blob, node, "arm,msi-base-spi").unwrap()),
+            [64]
+        );
+        assert_eq!(
+            u32_cells(&fdt_property(&blob, node, "arm,msi-num-spis").unwrap()),
+            [32]
+        );
+        assert_eq!(
+            u64_cells(&fdt_property(&blob, node, "reg").unwrap()),
+            [0x8020000, 0x1000]
+        );
+        assert_eq!(
+            fdt_property(&blob, "/pci", "msi-parent"),
+            fdt_property(&blob, node, "phandle")
+        );
+
+        let mut fdt = FdtWriter::new(&[]);
+        assert!(create_gic_node(&mut fdt, true, 2, Some(v2m), None, None, &mut phandles).is_err());
+    }
+
+    #[test]
+    fn gic_v3_its() {
+        let its = GicItsConfig {
+            base: 0x8080000,
+            size: 0x20000,
+        };
+        let cfg = PciConfigRegion {
+            base: 0x10000,
+            size: PCI_CAM_BUS_SIZE,
+        };
+        let pci_irqs = vec![(
+            PciAddress {
+                bus: 0,
+                dev: 1,
+                func: 0,
+            },
+            5,
+            PciInterruptPin::IntA,
+        )];
+        let mut phandles = PhandleAllocator::new();
+        let blob = build_fdt(|fdt| {
+            let msi_parent = create_gic_node(fdt, true, 2, None, Some(its), None, &mut phandles)?;
+            create_pci_nodes(
+                fdt,
+                pci_irqs,
+                cfg,
+                &[],
+                None,
+                msi_parent,
+                0,
+                ConfigAccess::Cam,
+            )
+        })
+        .finish_unpadded(0x10000)
+        .unwrap();
+
+        let node = "/intc/msi-controller@8080000";
+        assert_eq!(
+            fdt_property(&blob, node, "compatible").unwrap(),
+            b"arm,gic-v3-its\0"
+        );
+        let its_phandle = u32_cells(&fdt_property(&blob, node, "phandle").unwrap())[0];
+        assert_eq!(
+            u32_cells(&fdt_property(&blob, "/pci", "msi-parent").unwrap()),
+            [its_phandle]
+        );
+        assert_eq!(
+            u32_cells(&fdt_property(&blob, "/pci", "msi-map").unwrap()),
+            [0, its_phandle, 0, 0x10000]
+        );
+        // Devices without MSI still have their INTx routed.
+        assert!(fdt_property(&blob, "/pci", "interrupt-map").is_some());
+
+        let mut fdt = FdtWriter::new(&[]);
+        assert!(create_gic_node(&mut fdt, false, 2, None, Some(its), None, &mut phandles).is_err());
+    }
+
+    #[test]
+    fn gic_maintenance_irq() {
+        let blob = build_fdt(|fdt| {
+            create_gic_node(
+                fdt,
+                true,
+                2,
+                None,
+                None,
+                Some(9),
+                &mut PhandleAllocator::new(),
+            )?;
+            Ok(())
+        })
+        .finish_unpadded(0x10000)
+        .unwrap();
+        assert_eq!(
+            u32_cells(&fdt_property(&blob, "/intc", "interrupts").unwrap()),
+            [
+                GIC_FDT_IRQ_TYPE_PPI,
+                9,
+                ppi_cpu_mask(2) | IRQ_TYPE_LEVEL_HIGH
+            ]
+        );
+
+        let blob = build_fdt(|fdt| {
+            create_gic_node(fdt, true, 2, None, None, None, &mut PhandleAllocator::new())?;
+            Ok(())
+        })
+        .finish_unpadded(0x10000)
+        .unwrap();
+        assert_eq!(fdt_property(&blob, "/intc", "interrupts"), None);
+
+        let mut fdt = FdtWriter::new(&[]);
+        assert!(create_gic_node(
+            &mut fdt,
+            false,
+            2,
+            None,
+            None,
+            Some(9),
+            &mut PhandleAllocator::new()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn psci_smc_conduit() {
+        let version = PsciVersion::new(1, 0).unwrap();
+        let blob = build_fdt(|fdt| create_psci_node(fdt, &version, PsciConduit::Smc))
+            .finish_unpadded(0x10000)
+            .unwrap();
+        assert_eq!(fdt_property(&blob, "/psci", "method").unwrap(), b"smc\0");
+    }
+
+    #[test]
+    fn psci_function_ids() {
+        let blob = build_fdt(|fdt| create_psci_node(fdt, &PSCI_1_0, PsciConduit::Hvc))
+            .finish_unpadded(0x10000)
+            .unwrap();
+        assert_eq!(
+            u32_cells(&fdt_property(&blob, "/psci", "affinity_info").unwrap()),
+            [0xc4000004]
+        );
+        assert_eq!(
+            u32_cells(&fdt_property(&blob, "/psci", "migrate_info_type").unwrap()),
+            [0x84000006]
+        );
+
+        // PSCI 0.2 guests see the same node as before.
+        let blob = build_fdt(|fdt| create_psci_node(fdt, &PSCI_0_2, PsciConduit::Hvc))
+            .finish_unpadded(0x10000)
+            .unwrap();
+        assert!(fdt_property(&blob, "/psci", "affinity_info").is_none());
+        assert!(fdt_property(&blob, "/psci", "migrate_info_type").is_none());
+    }
+
+    #[test]
+    fn no_firmware_node() {
+        let blob = build_fdt(|fdt| create_firmware_node(fdt, None, false))
+            .finish_unpadded(0x10000)
+            .unwrap();
+        assert!(fdt_properties(&blob).is_empty());
+    }
+
+    #[test]
+    fn rtc_custom_location() {
+        let rtc_cfg = RtcConfig {
+            base: 0x9000,
+            size: 0x2000,
+            irq: 7,
+        };
+        let mut phandles = PhandleAllocator::new();
+        let blob = build_fdt(|fdt| create_rtc_node(fdt, rtc_cfg, &mut phandles))
+            .finish_unpadded(0x10000)
+            .unwrap();
+        let reg = fdt_property(&blob, "/rtc@9000", "reg").unwrap();
+        assert_eq!(u64_cells(&reg), [0x9000, 0x2000]);
+        let irq = fdt_property(&blob, "/rtc@9000", "interrupts").unwrap();
+        assert_eq!(
+            u32_cells(&irq),
+            [GIC_FDT_IRQ_TYPE_SPI, 7, IRQ_TYPE_LEVEL_HIGH]
+        );
+        let clocks = fdt_property(&blob, "/rtc@9000", "clocks").unwrap();
+        assert_eq!(u32_cells(&clocks), [PHANDLE_GIC + 1]);
+    }
+
+    #[test]
+    fn unique_phandles() {
+        let mut phandles = PhandleAllocator::new();
+        let rtc_cfg = RtcConfig {
+            base: 0x2000,
+            size: 0x1000,
+            irq: 1,
+        };
+        let cpu_clusters = vec![CpuSet::new([0, 1]), CpuSet::new([2, 3])];
+        let blob = build_fdt(|fdt| {
+            create_resv_memory_node(fdt, Some(0x100000), &mut phandles)?;
+            create_cpu_nodes(
+                fdt,
+                4,
+                cpu_clusters,
+                BTreeMap::new(),
+                CpuEnableMethod::Psci,
+                &[],
+                &mut phandles,
+            )?;
+            create_gic_node(fdt, true, 4, None, None, None, &mut phandles)?;
+            create_rtc_node(fdt, rtc_cfg, &mut phandles)
+        })
+        .finish_unpadded(0x10000)
+        .unwrap();
+        let mut values: Vec<u32> = fdt_properties(&blob)
+            .into_iter()
+            .filter(|(_, name, _)| name == "phandle")
+            .map(|(_, _, value)| u32_cells(&value)[0])
+            .collect();
+        // 1 DMA pool, 4 CPUs, 1 GIC, 1 clock.
+        assert_eq!(values.len(), 7);
+        values.sort_unstable();
+        values.dedup();
+        assert_eq!(values.len(), 7);
+    }
+
+    #[test]
+    fn powered_off_cpus() {
+        let blob = build_fdt(|fdt| {
+            create_cpu_nodes(
+                fdt,
+                4,
+                Vec::new(),
+                BTreeMap::new(),
+                CpuEnableMethod::Psci,
+                &[3],
+                &mut PhandleAllocator::new(),
+            )
+        })
+        .finish_unpadded(0x10000)
+        .unwrap();
+        assert_eq!(
+            fdt_property(&blob, "/cpus/cpu@3", "status").unwrap(),
+            b"disabled\0"
+        );
+        for cpu_id in 0..3 {
+            let path = format!("/cpus/cpu@{:x}", cpu_id);
+            assert!(fdt_property(&blob, &path, "status").is_none());
+        }
+
+        let mut fdt = FdtWriter::new(&[]);
+        assert!(create_cpu_nodes(
+            &mut fdt,
+            4,
+            Vec::new(),
+            BTreeMap::new(),
+            CpuEnableMethod::Psci,
+            &[4],
+            &mut PhandleAllocator::new(),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn spin_table_reserves_release_addrs() {
+        // Straddle a page boundary so the reservation has to span two pages.
+        let page_size = base::pagesize() as u64;
+        let release_addr_base = 0x8000_0000 + page_size - CPU_RELEASE_ADDR_SIZE;
+        let enable_method = CpuEnableMethod::SpinTable { release_addr_base };
+        let mem_reservations: Vec<FdtReserveEntry> = cpu_release_reservation(4, enable_method)
+            .into_iter()
+            .collect();
+        let mut fdt = FdtWriter::new(&mem_reservations);
+        let root_node = fdt.begin_node("").unwrap();
+        create_cpu_nodes(
+            &mut fdt,
+            4,
+            Vec::new(),
+            BTreeMap::new(),
+            enable_method,
+            &[],
+            &mut PhandleAllocator::new(),
+        )
+        .unwrap();
+        fdt.end_node(root_node).unwrap();
+        let blob = fdt.finish_unpadded(0x10000).unwrap();
+
+        let off_mem_rsvmap = u32::from_be_bytes(blob[16..20].try_into().unwrap()) as usize;
+        let rsvmap = u64_cells(&blob[off_mem_rsvmap..off_mem_rsvmap + 32]);
+        assert_eq!(rsvmap, vec![0x8000_0000, 2 * page_size, 0, 0]);
+
+        for cpu_id in 0..4u64 {
+            let path = format!("/cpus/cpu@{:x}", cpu_id);
+            assert_eq!(
+                fdt_property(&blob, &path, "enable-method").unwrap(),
+                b"spin-table\0"
+            );
+            let release_addr = u64_cells(&fdt_property(&blob, &path, "cpu-release-addr").unwrap());
+            assert_eq!(
+                release_addr,
+                vec![release_addr_base + CPU_RELEASE_ADDR_SIZE * cpu_id]
+            );
+        }
+    }
+
+    #[test]
+    fn multiple_serial_ports() {
+        let ports = [
+            SerialPortConfig {
+                addr: 0x3f8,
+                size: 0x8,
+                irq: 0,
+                clock_frequency: 1843200,
+            },
+            SerialPortConfig {
+                addr: 0x9000000,
+                size: 0x1000,
+                irq: 5,
+                clock_frequency: 24000000,
+            },
+        ];
+        let mut phandles = PhandleAllocator::new();
+        let blob = build_fdt(|fdt| {
+            create_serial_nodes(fdt, &ports, SerialClock::Frequency, &mut phandles)
+        })
+        .finish_unpadded(0x10000)
+        .unwrap();
+
+        let reg = fdt_property(&blob, "/U6_16550A@3f8", "reg").unwrap();
+        assert_eq!(u64_cells(&reg), vec![0x3f8, 0x8]);
+        let clock = fdt_property(&blob, "/U6_16550A@3f8", "clock-frequency").unwrap();
+        assert_eq!(u32_cells(&clock), vec![1843200]);
+
+        let reg = fdt_property(&blob, "/U6_16550A@9000000", "reg").unwrap();
+        assert_eq!(u64_cells(&reg), vec![0x9000000, 0x1000]);
+        let clock = fdt_property(&blob, "/U6_16550A@9000000", "clock-frequency").unwrap();
+        assert_eq!(u32_cells(&clock), vec![24000000]);
+        let interrupts = fdt_property(&blob, "/U6_16550A@9000000", "interrupts").unwrap();
+        assert_eq!(
+            u32_cells(&interrupts),
+            vec![GIC_FDT_IRQ_TYPE_SPI, 5, IRQ_TYPE_EDGE_RISING]
+        );
+    }
+
+    #[test]
+    fn duplicate_serial_ports() {
+        let port = SerialPortConfig {
+            addr: 0x3f8,
+            size: 0x8,
+            irq: 0,
+            clock_frequency: 1843200,
+        };
+        let mut fdt = FdtWriter::new(&[]);
+        let mut phandles = PhandleAllocator::new();
+        assert!(create_serial_nodes(
+            &mut fdt,
+            &[port, port],
+            SerialClock::Frequency,
+            &mut phandles
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn serial_fixed_clock() {
+        let port = |addr, clock_frequency| SerialPortConfig {
+            addr,
+            size: 0x8,
+            irq: 0,
+            clock_frequency,
+        };
+        let ports = [
+            port(0x3f8, 1843200),
+            port(0x2f8, 1843200),
+            port(0x9000000, 24000000),
+        ];
+        let mut phandles = PhandleAllocator::new();
+        let blob = build_fdt(|fdt| {
+            create_serial_nodes(fdt, &ports, SerialClock::FixedClock, &mut phandles)
+        })
+        .finish_unpadded(0x10000)
+        .unwrap();
+
+        let clock_phandle = |node: &str| {
+            let phandle = fdt_property(&blob, node, "phandle").unwrap();
+            assert_eq!(
+                fdt_property(&blob, node, "compatible").unwrap(),
+                b"fixed-clock\0"
+            );
+            phandle
+        };
+        let slow = clock_phandle("/uartclk-1843200");
+        let fast = clock_phandle("/uartclk-24000000");
+        assert_ne!(slow, fast);
+        assert_eq!(
+            u32_cells(&fdt_property(&blob, "/uartclk-1843200", "clock-frequency").unwrap()),
+            vec![1843200]
+        );
+
+        // UARTs with the same frequency share a clock node.
+        for (node, phandle) in [
+            ("/U6_16550A@3f8", &slow),
+            ("/U6_16550A@2f8", &slow),
+            ("/U6_16550A@9000000", &fast),
+        ] {
+            assert_eq!(&fdt_property(&blob, node, "clocks").unwrap(), phandle);
+            assert_eq!(
+                fdt_property(&blob, node, "clock-names").unwrap(),
+                b"uartclk\0"
+            );
+            assert!(fdt_property(&blob, node, "clock-frequency").is_none());
+        }
+    }
+
+    #[test]
+    fn pci_too_many_buses() {
+        let cfg = PciConfigRegion {
+            base: 0x10000,
+            size: 2 * PCI_CAM_BUS_SIZE,
+        };
+        let mut fdt = FdtWriter::new(&[]);
+        assert!(create_pci_nodes(
+            &mut fdt,
+            Vec::new(),
+            cfg,
+            &[],
+            None,
+            None,
+            2,
+            ConfigAccess::Cam
+        )
+        .is_err());
+    }
+
     #[test]
     fn psci_compatible_v0_1() {
         assert_eq!(
@@ -633,6 +2572,20 @@ mod tests {
         );
     }
 
+    #[test]
+    fn psci_compatible_property() {
+        let blob = build_fdt(|fdt| {
+            create_psci_node(fdt, &PsciVersion::new(1, 0).unwrap(), PsciConduit::Hvc)
+        })
+        .finish_unpadded(0x10000)
+        .unwrap();
+        // Both strings end up in one property, each terminated by a nul.
+        assert_eq!(
+            fdt_property(&blob, "/psci", "compatible").unwrap(),
+            b"arm,psci-1.0\0arm,psci-0.2\0"
+        );
+    }
+
     #[test]
     fn psci_compatible_v1_5() {
         // Only the 1.0 and 0.2 versions supported by the kernel should be listed.
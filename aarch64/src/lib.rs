@@ -181,6 +181,8 @@ const AARCH64_MMIO_BASE: u64 = 0x2000000;
 const AARCH64_MMIO_SIZE: u64 = 0x2000000;
 // Virtio devices start at SPI interrupt number 4
 const AARCH64_IRQ_BASE: u32 = 4;
+// Size of each virtio-mmio transport device slot.
+const AARCH64_VIRTIO_MMIO_SIZE: u64 = 0x200;
 
 // PMU PPI interrupt, same as qemu
 const AARCH64_PMU_IRQ: u32 = 7;
@@ -280,13 +282,14 @@ pub enum Error {
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-/// Returns the address in guest memory at which the FDT should be located.
-fn fdt_address(memory_end: GuestAddress, has_bios: bool) -> GuestAddress {
+/// Returns the address in guest memory at which the FDT should be located, for RAM starting at
+/// `phys_mem_start` and ending at `memory_end`.
+fn fdt_address(phys_mem_start: u64, memory_end: GuestAddress, has_bios: bool) -> GuestAddress {
     // TODO(rammuthiah) make kernel and BIOS startup use FDT from the same location. ARCVM startup
     // currently expects the kernel at 0x80080000 and the FDT at the end of RAM for unknown reasons.
     // Root cause and figure out how to fold these code paths together.
     if has_bios {
-        GuestAddress(AARCH64_PHYS_MEM_START + AARCH64_FDT_OFFSET_IN_BIOS_MODE)
+        GuestAddress(phys_mem_start + AARCH64_FDT_OFFSET_IN_BIOS_MODE)
     } else {
         // Put fdt up near the top of memory
         // TODO(sonnyrao): will have to handle this differently if there's
@@ -397,7 +400,7 @@ impl arch::LinuxArch for AArch64 {
         };
 
         let memory_end = GuestAddress(AARCH64_PHYS_MEM_START + components.memory_size);
-        let fdt_offset = fdt_address(memory_end, has_bios);
+        let fdt_offset = fdt_address(AARCH64_PHYS_MEM_START, memory_end, has_bios);
 
         let mut use_pmu = vm
             .get_hypervisor()
@@ -620,6 +623,16 @@ impl arch::LinuxArch for AArch64 {
             timeout_sec: VMWDT_DEFAULT_TIMEOUT_SEC,
         };
 
+        let rtc_cfg = fdt::RtcConfig {
+            base: AARCH64_RTC_ADDR,
+            size: AARCH64_RTC_SIZE,
+            irq: AARCH64_RTC_IRQ,
+        };
+
+        // The device tree must not clobber the payload or initrd loaded above.
+        let occupied: Vec<_> = std::iter::once((payload.entry(), payload.size() as usize))
+            .chain(initrd)
+            .collect();
         fdt::create_fdt(
             AARCH64_FDT_MAX_SIZE as usize,
             &mem,
@@ -629,17 +642,26 @@ impl arch::LinuxArch for AArch64 {
             vcpu_count as u32,
             components.cpu_clusters,
             components.cpu_capacity,
-            fdt_offset,
+            AARCH64_PHYS_MEM_START,
+            fdt_offset.offset() - AARCH64_PHYS_MEM_START,
             cmdline.as_str(),
             (payload.entry(), payload.size() as usize),
             initrd,
+            &occupied,
             components.android_fstab,
             irq_chip.get_vgic_version() == DeviceKind::ArmVgicV3,
-            use_pmu,
+            // Every vcpu has a PMU when it is enabled.
+            use_pmu.then(|| {
+                u32::MAX
+                    .checked_shr(32 - vcpu_count.min(32) as u32)
+                    .unwrap_or(0)
+            }),
             psci_version,
             components.swiotlb,
             bat_mmio_base_and_irq,
             vmwdt_cfg,
+            rtc_cfg,
+            Default::default(),
         )
         .map_err(Error::CreateFdt)?;
 
@@ -1014,4 +1036,12 @@ mod tests {
         // X2: image size
         assert_eq!(vcpu_init.regs.get(&VcpuRegAArch64::X(2)), Some(&0x1000));
     }
+
+    #[test]
+    fn fdt_address_phys_mem_start() {
+        assert_eq!(
+            fdt_address(0x4000_0000, GuestAddress(0x8000_0000), true),
+            GuestAddress(0x4000_0000 + AARCH64_FDT_OFFSET_IN_BIOS_MODE)
+        );
+    }
 }
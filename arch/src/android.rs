@@ -26,11 +26,24 @@ fn parse_fstab_line(line: &str) -> Result<Vec<String>> {
 /// * `fdt` - The DTB to modify. The top-most node should be open.
 /// * `android-fstab` - A text file of Android fstab entries to add to the DTB
 pub fn create_android_fdt(fdt: &mut FdtWriter, fstab: File) -> Result<()> {
+    let firmware_node = fdt.begin_node("firmware")?;
+    create_android_firmware_node(fdt, fstab)?;
+    fdt.end_node(firmware_node)?;
+    Ok(())
+}
+
+/// Creates the Android node of the /firmware node, for callers that add other firmware nodes
+/// alongside it.
+///
+/// # Arguments
+///
+/// * `fdt` - The DTB to modify. The /firmware node should be open.
+/// * `android-fstab` - A text file of Android fstab entries to add to the DTB
+pub fn create_android_firmware_node(fdt: &mut FdtWriter, fstab: File) -> Result<()> {
     let vecs = BufReader::new(fstab)
         .lines()
         .map(|l| parse_fstab_line(&l.map_err(Error::FdtIoError)?))
         .collect::<Result<Vec<Vec<String>>>>()?;
-    let firmware_node = fdt.begin_node("firmware")?;
     let android_node = fdt.begin_node("android")?;
     fdt.property_string("compatible", "android,firmware")?;
 
@@ -55,6 +68,5 @@ pub fn create_android_fdt(fdt: &mut FdtWriter, fstab: File) -> Result<()> {
     }
     fdt.end_node(fstab_node)?;
     fdt.end_node(android_node)?;
-    fdt.end_node(firmware_node)?;
     Ok(())
 }
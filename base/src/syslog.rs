@@ -49,10 +49,28 @@
 //!
 //! [log-crate-url]: https://docs.rs/log/
 
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
 use std::fmt::Display;
+use std::fs::File;
+use std::hash::Hash;
+use std::hash::Hasher;
 use std::io;
 use std::io::Write;
+use std::marker::PhantomData;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicU8;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use std::sync::MutexGuard;
+use std::time::Duration;
+use std::time::Instant;
 
 use chrono::Local;
 pub use env_logger::fmt;
@@ -74,7 +92,7 @@ use crate::platform::RawDescriptor;
 ///
 /// See syslog man pages for information on their semantics.
 #[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
-pub(crate) enum Priority {
+pub enum Priority {
     Emergency = 0,
     Alert = 1,
     Critical = 2,
@@ -116,6 +134,20 @@ impl From<log::Level> for Priority {
     }
 }
 
+impl Priority {
+    /// Returns the most severe `log::Level` that maps to this priority or a less severe one.
+    fn level(self) -> log::Level {
+        match self {
+            Priority::Emergency | Priority::Alert | Priority::Critical | Priority::Error => {
+                log::Level::Error
+            }
+            Priority::Warning => log::Level::Warn,
+            Priority::Notice | Priority::Info => log::Level::Info,
+            Priority::Debug => log::Level::Debug,
+        }
+    }
+}
+
 pub const FORMATTER_NONE: Option<fn(&mut fmt::Formatter, &log::Record<'_>) -> std::io::Result<()>> =
     None;
 
@@ -136,10 +168,45 @@ impl TryFrom<&str> for Priority {
         }
     }
 }
+/// Selects which priorities are logged.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PriorityFilter {
+    /// Nothing is logged.
+    Silent,
+    /// Messages of the given priority and every more severe priority are logged.
+    Priority(Priority),
+    /// Everything is logged.
+    ShowAll,
+}
+
+impl PriorityFilter {
+    fn allows(&self, priority: Priority) -> bool {
+        match self {
+            PriorityFilter::Silent => false,
+            PriorityFilter::Priority(p) => priority as u8 <= *p as u8,
+            PriorityFilter::ShowAll => true,
+        }
+    }
+
+    /// Returns the most verbose `LevelFilter` that lets through the same records as this filter.
+    fn level_filter(&self) -> LevelFilter {
+        [
+            log::Level::Trace,
+            log::Level::Debug,
+            log::Level::Info,
+            log::Level::Warn,
+            log::Level::Error,
+        ]
+        .into_iter()
+        .find(|&level| self.allows(Priority::from(level)))
+        .map_or(LevelFilter::Off, |level| level.to_level_filter())
+    }
+}
+
 /// The facility of a syslog message.
 ///
 /// See syslog man pages for information on their semantics.
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Facility {
     Kernel = 0,
     User = 1 << 3,
@@ -160,6 +227,56 @@ pub enum Facility {
     Local7 = 23 << 3,
 }
 
+impl Facility {
+    const ALL: [Facility; 17] = [
+        Facility::Kernel,
+        Facility::User,
+        Facility::Mail,
+        Facility::Daemon,
+        Facility::Auth,
+        Facility::Syslog,
+        Facility::Lpr,
+        Facility::News,
+        Facility::Uucp,
+        Facility::Local0,
+        Facility::Local1,
+        Facility::Local2,
+        Facility::Local3,
+        Facility::Local4,
+        Facility::Local5,
+        Facility::Local6,
+        Facility::Local7,
+    ];
+
+    /// The name syslog uses for the facility, e.g. `daemon` or `local0`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Facility::Kernel => "kern",
+            Facility::User => "user",
+            Facility::Mail => "mail",
+            Facility::Daemon => "daemon",
+            Facility::Auth => "auth",
+            Facility::Syslog => "syslog",
+            Facility::Lpr => "lpr",
+            Facility::News => "news",
+            Facility::Uucp => "uucp",
+            Facility::Local0 => "local0",
+            Facility::Local1 => "local1",
+            Facility::Local2 => "local2",
+            Facility::Local3 => "local3",
+            Facility::Local4 => "local4",
+            Facility::Local5 => "local5",
+            Facility::Local6 => "local6",
+            Facility::Local7 => "local7",
+        }
+    }
+
+    /// Returns the facility named by a record's target, if any.
+    fn from_target(target: &str) -> Option<Facility> {
+        Facility::ALL.into_iter().find(|f| f.name() == target)
+    }
+}
+
 /// Errors returned by `syslog::init()`.
 #[sorted]
 #[derive(ThisError, Debug)]
@@ -194,30 +311,374 @@ pub(crate) trait Syslog {
 pub struct State {
     /// Record filter
     filter: env_logger::filter::Filter,
+    /// Module paths named by the directives of the record filter
+    path_directives: Vec<String>,
+    /// Facility of records whose target does not name one
+    facility: Facility,
+    /// Levels set with `set_facility_log_level`
+    facility_filters: Vec<(Facility, PriorityFilter)>,
     /// All the loggers we have
     loggers: Vec<Box<dyn Log + Send>>,
-    /// Raw Descriptors to preserve
-    descriptors: Vec<RawDescriptor>,
+    /// Raw Descriptors to preserve, with their roles as reported by `describe_descriptors`
+    descriptors: Vec<(RawDescriptor, &'static str)>,
+    /// Loggers added with `add_file_sink` and the descriptors of their files
+    file_sinks: Vec<(Box<dyn Log + Send>, RawDescriptor)>,
     /// True if we have just been initialized with safe startup defaults (stderr logging), false
     /// after detailed initialization has occurred.
     early_init: bool,
+    /// Identical records logged within this window of each other are coalesced into one
+    coalesce_window: Option<Duration>,
+    /// The most recent record, held for coalescing with identical records that follow it
+    coalesced: Mutex<Option<CoalescedRecord>>,
+    /// How the source of a record is rendered by the formatted loggers
+    source_display: Arc<Mutex<SourceDisplay>>,
+    /// Messages longer than this many bytes are truncated before being sent to the loggers
+    max_message_len: Option<usize>,
+    /// Number of records logged at each priority, indexed by `Priority` value
+    stats: [AtomicU64; 8],
+    /// Level that repeats of a coalesced record are demoted to instead of being counted
+    repeat_demotion: Option<log::Level>,
+    /// Sequence number of the record being sent to the loggers
+    sequence: Arc<Sequence>,
+    /// Records at or above this `Priority` value's severity go to stderr, the rest to stdout
+    stderr_threshold: Arc<AtomicU8>,
+    /// Logger set with `echo_unix_socket` and the descriptor of its socket
+    #[cfg(unix)]
+    socket_sink: Option<(Box<dyn Log + Send>, RawDescriptor)>,
+    /// Number of lines the socket sink failed to send
+    #[cfg(unix)]
+    socket_drops: Arc<AtomicU64>,
+    /// Logger set with `echo_journald` and the descriptor of its socket
+    #[cfg(unix)]
+    journald_sink: Option<(Box<dyn Log + Send>, RawDescriptor)>,
+    /// Number of records the journald sink failed to send
+    #[cfg(unix)]
+    journald_drops: Arc<AtomicU64>,
+    /// Identifier records are sent to journald under
+    #[cfg(unix)]
+    proc_name: String,
+    /// Number of records dropped while the syslog could not be connected to
+    syslog_drops: Arc<AtomicU64>,
+    /// Descriptor of the syslog connection made after initialization by a `ReconnectingSyslog`
+    syslog_descriptor: Arc<Mutex<Option<RawDescriptor>>>,
+    /// Most recent warning and more severe lines, kept apart from the loggers
+    error_buffer: Mutex<ErrorBuffer>,
+}
+
+/// A logger with its own configuration and sinks. The free functions of this module configure
+/// the global one the `log` crate macros write to; others are independent of it and of each other.
+pub struct Logger {
+    state: State,
+}
+
+/// Bounded buffer of the most recent warning and more severe lines.
+#[derive(Default)]
+struct ErrorBuffer {
+    /// Maximum number of lines kept; 0 disables the buffer
+    capacity: usize,
+    lines: VecDeque<String>,
+}
+
+impl ErrorBuffer {
+    fn push(&mut self, record: &log::Record) {
+        if self.capacity == 0 || record.level() > log::Level::Warn {
+            return;
+        }
+        if self.lines.len() == self.capacity {
+            self.lines.pop_front();
+        }
+        self.lines
+            .push_back(format!("{} {}", record.level(), record.args()));
+    }
+}
+
+/// Numbers the records sent to the loggers in the order they are emitted.
+#[derive(Default)]
+struct Sequence {
+    /// Number of the record currently being sent to the loggers; the first record is 1
+    current: AtomicU64,
+    /// Whether formatted log lines show the number
+    show: AtomicBool,
+}
+
+/// Selects how the source location of a record is shown in formatted log lines.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SourceDisplay {
+    /// The repo-relative path of the file, followed by the line number.
+    FullPath,
+    /// Only the basename of the file, followed by the line number.
+    FileName,
+    /// The module path of the code that logged the record.
+    #[default]
+    ModulePath,
+}
+
+impl SourceDisplay {
+    fn write_source(&self, buf: &mut impl Write, record: &log::Record) -> io::Result<()> {
+        let file = match self {
+            SourceDisplay::FullPath => record.file(),
+            SourceDisplay::FileName => record.file().map(|f| {
+                Path::new(f)
+                    .file_name()
+                    .and_then(|f| f.to_str())
+                    .unwrap_or(f)
+            }),
+            SourceDisplay::ModulePath => {
+                return write!(
+                    buf,
+                    "{}",
+                    record.module_path().unwrap_or("<missing module path>")
+                )
+            }
+        };
+        match (file, record.line()) {
+            (Some(file), Some(line)) => write!(buf, "{}:{}", file, line),
+            (Some(file), None) => write!(buf, "{}", file),
+            _ => write!(buf, "<missing file>"),
+        }
+    }
+}
+
+/// A logged record that later identical records are being counted against.
+struct CoalescedRecord {
+    hash: u64,
+    level: log::Level,
+    target: String,
+    message: String,
+    count: usize,
+    start: Instant,
+}
+
+/// Sends records at or above the severity of `threshold` to `stderr` and the rest to `stdout`.
+struct ConsoleLogger {
+    stderr: env_logger::Logger,
+    stdout: env_logger::Logger,
+    threshold: Arc<AtomicU8>,
+}
+
+impl Log for ConsoleLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        if Priority::from(record.level()) as u8 <= self.threshold.load(Ordering::Relaxed) {
+            self.stderr.log(record)
+        } else {
+            self.stdout.log(record)
+        }
+    }
+
+    fn flush(&self) {
+        self.stderr.flush();
+        self.stdout.flush();
+    }
+}
+
+/// Sends each write as a single datagram, counting the ones that could not be sent.
+#[cfg(unix)]
+struct DatagramWriter {
+    socket: UnixDatagram,
+    drops: Arc<AtomicU64>,
+}
+
+#[cfg(unix)]
+impl Write for DatagramWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.socket.send(buf).is_err() {
+            self.drops.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Where journald receives records in its native protocol.
+#[cfg(unix)]
+const JOURNALD_SOCKET_PATH: &str = "/run/systemd/journal/socket";
+
+/// Sends each record as a datagram of journald native protocol fields to the socket at `path`,
+/// counting the ones that could not be sent.
+#[cfg(unix)]
+struct JournaldLogger {
+    socket: UnixDatagram,
+    path: PathBuf,
+    identifier: String,
+    drops: Arc<AtomicU64>,
+}
+
+/// Appends the field `name` with `value` to a journald native protocol datagram.
+#[cfg(unix)]
+fn append_journald_field(buf: &mut Vec<u8>, name: &str, value: &[u8]) {
+    buf.extend_from_slice(name.as_bytes());
+    // Values with a newline can't use `NAME=value`; they are sent as the name, a newline, and the
+    // length of the value as a little-endian u64, followed by the value itself.
+    if value.contains(&b'\n') {
+        buf.push(b'\n');
+        buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        buf.push(b'=');
+    }
+    buf.extend_from_slice(value);
+    buf.push(b'\n');
+}
+
+#[cfg(unix)]
+impl Log for JournaldLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let mut buf = Vec::new();
+        let priority = Priority::from(record.level()) as u8;
+        append_journald_field(&mut buf, "PRIORITY", priority.to_string().as_bytes());
+        append_journald_field(&mut buf, "SYSLOG_IDENTIFIER", self.identifier.as_bytes());
+        if let Some(file) = record.file() {
+            append_journald_field(&mut buf, "CODE_FILE", file.as_bytes());
+        }
+        if let Some(line) = record.line() {
+            append_journald_field(&mut buf, "CODE_LINE", line.to_string().as_bytes());
+        }
+        append_journald_field(&mut buf, "MESSAGE", record.args().to_string().as_bytes());
+        if self.socket.send_to(&buf, &self.path).is_err() {
+            self.drops.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Delay before the first reconnection attempt of a `ReconnectingSyslog`.
+const SYSLOG_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+/// Longest delay between two reconnection attempts of a `ReconnectingSyslog`.
+const SYSLOG_MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+/// Number of reconnection attempts after which a `ReconnectingSyslog` gives up.
+const SYSLOG_MAX_RETRIES: u32 = 16;
+
+enum SyslogConnection {
+    Connected(Box<dyn Log + Send>),
+    Disconnected { retries: u32, retry_at: Instant },
+}
+
+/// Logs to the syslog of platform `S` once it can be connected to.
+///
+/// Connecting is retried from `log`, doubling the delay between attempts up to
+/// `SYSLOG_MAX_RETRY_BACKOFF`, for up to `SYSLOG_MAX_RETRIES` attempts. Records logged while
+/// disconnected are dropped and counted.
+struct ReconnectingSyslog<S: Syslog> {
+    proc_name: String,
+    facility: Facility,
+    backoff: Duration,
+    connection: Mutex<SyslogConnection>,
+    drops: Arc<AtomicU64>,
+    descriptor: Arc<Mutex<Option<RawDescriptor>>>,
+    syslog: PhantomData<S>,
+}
+
+impl<S: Syslog> ReconnectingSyslog<S> {
+    /// Creates a disconnected logger that first tries to connect `backoff` from now. The
+    /// descriptor of the connection, if any, is stored in `descriptor` once connected.
+    fn new(
+        proc_name: String,
+        facility: Facility,
+        backoff: Duration,
+        drops: Arc<AtomicU64>,
+        descriptor: Arc<Mutex<Option<RawDescriptor>>>,
+    ) -> Self {
+        ReconnectingSyslog {
+            proc_name,
+            facility,
+            backoff,
+            connection: Mutex::new(SyslogConnection::Disconnected {
+                retries: 0,
+                retry_at: Instant::now() + backoff,
+            }),
+            drops,
+            descriptor,
+            syslog: PhantomData,
+        }
+    }
+
+    /// Tries to connect if the next attempt is due.
+    fn reconnect(&self, connection: &mut SyslogConnection) {
+        let retries = match connection {
+            SyslogConnection::Disconnected { retries, retry_at }
+                if *retries < SYSLOG_MAX_RETRIES && Instant::now() >= *retry_at =>
+            {
+                *retries
+            }
+            _ => return,
+        };
+        match S::new(self.proc_name.clone(), self.facility) {
+            Ok((Some(logger), descriptor)) => {
+                *self.descriptor.lock() = descriptor;
+                *connection = SyslogConnection::Connected(logger);
+            }
+            // The platform has no syslog to connect to.
+            Ok((None, _)) => {
+                *connection = SyslogConnection::Disconnected {
+                    retries: SYSLOG_MAX_RETRIES,
+                    retry_at: Instant::now(),
+                }
+            }
+            Err(_) => {
+                let backoff = self
+                    .backoff
+                    .checked_mul(1 << retries.min(16))
+                    .map_or(SYSLOG_MAX_RETRY_BACKOFF, |b| {
+                        b.min(SYSLOG_MAX_RETRY_BACKOFF)
+                    });
+                *connection = SyslogConnection::Disconnected {
+                    retries: retries + 1,
+                    retry_at: Instant::now() + backoff,
+                }
+            }
+        }
+    }
+}
+
+impl<S: Syslog> Log for ReconnectingSyslog<S> {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let mut connection = self.connection.lock();
+        self.reconnect(&mut connection);
+        match &*connection {
+            SyslogConnection::Connected(logger) => logger.log(record),
+            SyslogConnection::Disconnected { .. } => {
+                self.drops.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let SyslogConnection::Connected(logger) = &*self.connection.lock() {
+            logger.flush();
+        }
+    }
 }
 
-/// The logger that is provided to the `log` crate. Wraps our State struct so that we can
+/// The logger that is provided to the `log` crate. Wraps the global `Logger` so that we can
 /// reconfigure logging sinks on the fly.
 struct LoggingFacade {}
 
 impl Log for LoggingFacade {
     fn enabled(&self, metadata: &log::Metadata) -> bool {
-        STATE.lock().enabled(metadata)
+        LOGGER.lock().enabled(metadata)
     }
 
     fn log(&self, record: &log::Record) {
-        STATE.lock().log(record)
+        LOGGER.lock().log(record)
     }
 
     fn flush(&self) {
-        STATE.lock().flush()
+        LOGGER.lock().flush()
     }
 }
 
@@ -264,6 +725,72 @@ impl<'a> Default
     }
 }
 
+/// Returns the module paths named by the directives of `filter`, in the syntax of
+/// `LogConfig::filter`.
+fn parse_path_directives(filter: &str) -> Vec<String> {
+    let directives = filter.split('/').next().unwrap_or_default();
+    directives
+        .split(',')
+        .filter_map(|directive| {
+            let mut parts = directive.split('=');
+            let name = parts.next()?.trim();
+            // A lone level, e.g. `info`, sets the default rather than naming a path.
+            if name.is_empty() || (parts.next().is_none() && name.parse::<LevelFilter>().is_ok()) {
+                return None;
+            }
+            Some(name.to_owned())
+        })
+        .collect()
+}
+
+fn create_formatted_builder(
+    source_display: Arc<Mutex<SourceDisplay>>,
+    sequence: Arc<Sequence>,
+) -> env_logger::Builder {
+    let mut builder = env_logger::Builder::new();
+
+    // Output log lines w/ local ISO 8601 timestamps.
+    builder.format(move |buf, record| {
+        write!(
+            buf,
+            "[{} {:5} ",
+            Local::now().format("%Y-%m-%dT%H:%M:%S%.9f%:z"),
+            record.level(),
+        )?;
+        if sequence.show.load(Ordering::Relaxed) {
+            write!(buf, "#{} ", sequence.current.load(Ordering::Relaxed))?;
+        }
+        let source_display = *source_display.lock();
+        source_display.write_source(buf, record)?;
+        writeln!(buf, "] {}", record.args())
+    });
+    builder
+}
+
+fn create_console_logger(
+    stderr: env_logger::Target,
+    stdout: env_logger::Target,
+    source_display: &Arc<Mutex<SourceDisplay>>,
+    sequence: &Arc<Sequence>,
+    threshold: Arc<AtomicU8>,
+) -> ConsoleLogger {
+    let build = |target: env_logger::Target| {
+        let mut builder = create_formatted_builder(source_display.clone(), sequence.clone());
+        builder.filter_level(log::LevelFilter::Trace);
+        if matches!(target, env_logger::Target::Pipe(_)) {
+            // https://github.com/env-logger-rs/env_logger/issues/208
+            builder.is_test(true);
+        }
+        builder.target(target);
+        builder.build()
+    };
+    ConsoleLogger {
+        stderr: build(stderr),
+        stdout: build(stdout),
+        threshold,
+    }
+}
+
 impl State {
     pub fn new<F: 'static>(cfg: LogConfig<'_, F>) -> Result<Self, Error>
     where
@@ -271,41 +798,38 @@ impl State {
     {
         let mut loggers: Vec<Box<dyn Log + Send>> = vec![];
         let mut descriptors = vec![];
+        #[cfg(unix)]
+        let proc_name = cfg.proc_name.clone();
         let mut builder = env_logger::filter::Builder::new();
         builder.parse(cfg.filter);
         let filter = builder.build();
-
-        let create_formatted_builder = || {
-            let mut builder = env_logger::Builder::new();
-
-            // Output log lines w/ local ISO 8601 timestamps.
-            builder.format(|buf, record| {
-                writeln!(
-                    buf,
-                    "[{} {:5} {}] {}",
-                    Local::now().format("%Y-%m-%dT%H:%M:%S%.9f%:z"),
-                    record.level(),
-                    record.module_path().unwrap_or("<missing module path>"),
-                    record.args()
-                )
-            });
-            builder
-        };
+        let path_directives = parse_path_directives(cfg.filter);
+        let facility = cfg.syslog_facility;
+        let source_display = Arc::new(Mutex::new(SourceDisplay::default()));
+        let sequence = Arc::new(Sequence::default());
+        // Everything goes to stderr until a threshold is set.
+        let stderr_threshold = Arc::new(AtomicU8::new(Priority::Debug as u8));
+        let syslog_drops = Arc::new(AtomicU64::new(0));
+        let syslog_descriptor = Arc::new(Mutex::new(None));
 
         if cfg.stderr {
-            let mut builder = create_formatted_builder();
-            builder.filter_level(log::LevelFilter::Trace);
-            builder.target(env_logger::Target::Stderr);
-            loggers.push(Box::new(builder.build()));
-            descriptors.push(std::io::stderr().as_raw_descriptor());
+            loggers.push(Box::new(create_console_logger(
+                env_logger::Target::Stderr,
+                env_logger::Target::Stdout,
+                &source_display,
+                &sequence,
+                stderr_threshold.clone(),
+            )));
+            descriptors.push((std::io::stderr().as_raw_descriptor(), "console"));
+            descriptors.push((std::io::stdout().as_raw_descriptor(), "console"));
         }
 
         if let Some(fd) = cfg.pipe_fd {
-            descriptors.push(fd);
+            descriptors.push((fd, "file"));
         }
 
         if let Some(file) = cfg.pipe {
-            let mut builder = create_formatted_builder();
+            let mut builder = create_formatted_builder(source_display.clone(), sequence.clone());
             builder.filter_level(log::LevelFilter::Trace);
             builder.target(env_logger::Target::Pipe(Box::new(file)));
             // https://github.com/env-logger-rs/env_logger/issues/208
@@ -318,10 +842,10 @@ impl State {
         }
 
         if cfg.syslog {
-            match PlatformSyslog::new(cfg.proc_name, cfg.syslog_facility) {
+            match PlatformSyslog::new(cfg.proc_name.clone(), cfg.syslog_facility) {
                 Ok((mut logger, fd)) => {
                     if let Some(fd) = fd {
-                        descriptors.push(fd);
+                        descriptors.push((fd, "syslog"));
                     }
                     if let Some(logger) = logger.take() {
                         loggers.push(logger);
@@ -330,25 +854,466 @@ impl State {
                 Err(e) => {
                     // The default log configuration used in early_init() enables syslog, so we
                     // don't want to terminate the program if syslog can't be initialized. Warn the
-                    // user but continue running.
-                    eprintln!("syslog init failed: {}", e);
+                    // user but continue running, and keep trying in case the syslog daemon has not
+                    // started yet.
+                    eprintln!("syslog init failed, retrying: {}", e);
+                    loggers.push(Box::new(ReconnectingSyslog::<PlatformSyslog>::new(
+                        cfg.proc_name,
+                        cfg.syslog_facility,
+                        SYSLOG_RETRY_BACKOFF,
+                        syslog_drops.clone(),
+                        syslog_descriptor.clone(),
+                    )));
                 }
             }
         }
 
         Ok(State {
             filter,
+            path_directives,
+            facility,
+            facility_filters: Vec::new(),
             loggers,
             descriptors,
+            file_sinks: Vec::new(),
             early_init: false,
+            coalesce_window: None,
+            coalesced: Mutex::new(None),
+            source_display,
+            max_message_len: None,
+            stats: Default::default(),
+            repeat_demotion: None,
+            sequence,
+            stderr_threshold,
+            #[cfg(unix)]
+            socket_sink: None,
+            #[cfg(unix)]
+            socket_drops: Arc::new(AtomicU64::new(0)),
+            #[cfg(unix)]
+            journald_sink: None,
+            #[cfg(unix)]
+            journald_drops: Arc::new(AtomicU64::new(0)),
+            #[cfg(unix)]
+            proc_name,
+            syslog_drops,
+            syslog_descriptor,
+            error_buffer: Default::default(),
         })
     }
+
+    /// Adds `file` as an additional log output that receives the records allowed by `filter`, on
+    /// top of the outputs set up from `LogConfig`.
+    pub fn add_file_sink(&mut self, file: File, filter: PriorityFilter) {
+        let descriptor = file.as_raw_descriptor();
+        let mut builder =
+            create_formatted_builder(self.source_display.clone(), self.sequence.clone());
+        builder.filter_level(filter.level_filter());
+        builder.target(env_logger::Target::Pipe(Box::new(file)));
+        // https://github.com/env-logger-rs/env_logger/issues/208
+        builder.is_test(true);
+        self.file_sinks
+            .push((Box::new(builder.build()), descriptor));
+    }
+
+    /// Returns the descriptors owned by the outputs, each with its role: "syslog", "file",
+    /// "journald" or "unix_socket". The console descriptors are not owned and are left out.
+    pub fn describe_descriptors(&self) -> Vec<(RawDescriptor, &'static str)> {
+        let descriptors = self
+            .descriptors
+            .iter()
+            .copied()
+            .filter(|&(_, role)| role != "console")
+            .chain(
+                self.syslog_descriptor
+                    .lock()
+                    .map(|descriptor| (descriptor, "syslog")),
+            )
+            .chain(
+                self.file_sinks
+                    .iter()
+                    .map(|&(_, descriptor)| (descriptor, "file")),
+            );
+        #[cfg(unix)]
+        let descriptors = descriptors
+            .chain(
+                self.socket_sink
+                    .iter()
+                    .map(|&(_, descriptor)| (descriptor, "unix_socket")),
+            )
+            .chain(
+                self.journald_sink
+                    .iter()
+                    .map(|&(_, descriptor)| (descriptor, "journald")),
+            );
+        descriptors.collect()
+    }
+
+    /// Removes all outputs added with `add_file_sink`.
+    pub fn clear_file_sinks(&mut self) {
+        self.flush();
+        self.file_sinks.clear();
+    }
+
+    /// Sends each formatted line as a datagram to the Unix datagram socket at `path`, in addition
+    /// to the other outputs. `None` stops sending.
+    ///
+    /// Lines that cannot be sent, e.g. because the receiver is gone or its queue is full, are
+    /// dropped and counted in `dropped_socket_lines` instead of failing or blocking the log call.
+    #[cfg(unix)]
+    pub fn echo_unix_socket(&mut self, path: Option<&Path>) -> io::Result<()> {
+        self.flush();
+        self.socket_sink = None;
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        socket.set_nonblocking(true)?;
+        let descriptor = socket.as_raw_descriptor();
+        let mut builder =
+            create_formatted_builder(self.source_display.clone(), self.sequence.clone());
+        builder.filter_level(log::LevelFilter::Trace);
+        builder.target(env_logger::Target::Pipe(Box::new(DatagramWriter {
+            socket,
+            drops: self.socket_drops.clone(),
+        })));
+        // https://github.com/env-logger-rs/env_logger/issues/208
+        builder.is_test(true);
+        self.socket_sink = Some((Box::new(builder.build()), descriptor));
+        Ok(())
+    }
+
+    /// Returns the number of lines the socket set with `echo_unix_socket` failed to send.
+    #[cfg(unix)]
+    pub fn dropped_socket_lines(&self) -> u64 {
+        self.socket_drops.load(Ordering::Relaxed)
+    }
+
+    /// Sends each record to journald through its native protocol when `enable` is true, in addition
+    /// to the other outputs, with the `PRIORITY`, `SYSLOG_IDENTIFIER` (the `proc_name` of the
+    /// `LogConfig`), `CODE_FILE`, `CODE_LINE` and `MESSAGE` fields.
+    ///
+    /// Records that cannot be sent, e.g. because journald isn't running, are dropped and counted
+    /// in `dropped_journald_messages`.
+    #[cfg(unix)]
+    pub fn echo_journald(&mut self, enable: bool) -> io::Result<()> {
+        self.echo_journald_to(enable.then(|| Path::new(JOURNALD_SOCKET_PATH)))
+    }
+
+    #[cfg(unix)]
+    fn echo_journald_to(&mut self, path: Option<&Path>) -> io::Result<()> {
+        self.flush();
+        self.journald_sink = None;
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        // The socket is left unconnected so that records reach journald once it starts, even if
+        // it wasn't running yet when this was called.
+        let socket = UnixDatagram::unbound()?;
+        socket.set_nonblocking(true)?;
+        let descriptor = socket.as_raw_descriptor();
+        let logger = JournaldLogger {
+            socket,
+            path: path.to_owned(),
+            identifier: self.proc_name.clone(),
+            drops: self.journald_drops.clone(),
+        };
+        self.journald_sink = Some((Box::new(logger), descriptor));
+        Ok(())
+    }
+
+    /// Returns the number of records the journald output set with `echo_journald` failed to
+    /// send.
+    #[cfg(unix)]
+    pub fn dropped_journald_messages(&self) -> u64 {
+        self.journald_drops.load(Ordering::Relaxed)
+    }
+
+    /// Returns the process name records are logged under, or `None` for the minimal state in
+    /// place before logging is initialized.
+    #[cfg(unix)]
+    pub fn proc_name(&self) -> Option<String> {
+        if self.early_init {
+            None
+        } else {
+            Some(self.proc_name.clone())
+        }
+    }
+
+    /// Returns the number of records dropped because the syslog could not be connected to when
+    /// they were logged.
+    pub fn dropped_syslog_messages(&self) -> u64 {
+        self.syslog_drops.load(Ordering::Relaxed)
+    }
+
+    /// Returns every logger records are sent to.
+    fn sinks(&self) -> impl Iterator<Item = &(dyn Log + Send)> {
+        let sinks = self
+            .loggers
+            .iter()
+            .map(|logger| logger.as_ref())
+            .chain(self.file_sinks.iter().map(|(logger, _)| logger.as_ref()));
+        #[cfg(unix)]
+        let sinks = sinks
+            .chain(self.socket_sink.iter().map(|(logger, _)| logger.as_ref()))
+            .chain(self.journald_sink.iter().map(|(logger, _)| logger.as_ref()));
+        sinks
+    }
+
+    /// Coalesces identical records logged within `window` of the first one into a single record
+    /// annotated with the number of occurrences. A zero `window` disables coalescing.
+    pub fn set_coalesce_window(&mut self, window: Duration) {
+        self.flush_coalesced();
+        self.coalesce_window = if window.is_zero() { None } else { Some(window) };
+    }
+
+    /// Sets how the source of a record is shown by the formatted loggers, including those
+    /// already created.
+    pub fn set_source_display(&mut self, source_display: SourceDisplay) {
+        *self.source_display.lock() = source_display;
+    }
+
+    /// Truncates messages longer than `max_len` bytes, marking how many bytes were dropped. `None`
+    /// removes the limit.
+    pub fn set_max_message_len(&mut self, max_len: Option<usize>) {
+        self.max_message_len = max_len;
+    }
+
+    /// Returns the number of records logged at each priority since creation or the last
+    /// `reset_log_stats`, indexed by `Priority` value.
+    pub fn log_stats(&self) -> [u64; 8] {
+        let mut stats = [0; 8];
+        for (count, stat) in stats.iter_mut().zip(self.stats.iter()) {
+            *count = stat.load(Ordering::Relaxed);
+        }
+        stats
+    }
+
+    /// Zeroes the counters returned by `log_stats`.
+    pub fn reset_log_stats(&self) {
+        for stat in self.stats.iter() {
+            stat.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Keeps the last `capacity` lines logged at warning or more severe levels in a buffer
+    /// separate from the loggers, to be read with `drain_error_buffer`. Lines already buffered
+    /// beyond the new capacity are dropped, oldest first. A zero `capacity` disables the buffer.
+    pub fn set_error_buffer(&mut self, capacity: usize) {
+        let mut buffer = self.error_buffer.lock();
+        buffer.capacity = capacity;
+        let excess = buffer.lines.len().saturating_sub(capacity);
+        buffer.lines.drain(..excess);
+    }
+
+    /// Removes and returns the lines held by the buffer set with `set_error_buffer`, oldest
+    /// first.
+    pub fn drain_error_buffer(&self) -> Vec<String> {
+        self.error_buffer.lock().lines.drain(..).collect()
+    }
+
+    /// Sends `record` to every logger unless it is being coalesced with the previous record.
+    fn log_to_sinks(&self, record: &log::Record) {
+        if !self.coalesce(record) {
+            self.emit(record);
+        }
+    }
+
+    /// Assigns `record` the next sequence number and sends it to every logger.
+    ///
+    /// `State` is not `Sync`, so records are emitted one at a time and the numbers reflect the
+    /// order the loggers received them in, whichever thread logged them.
+    fn emit(&self, record: &log::Record) {
+        self.sequence.current.fetch_add(1, Ordering::Relaxed);
+        self.error_buffer.lock().push(record);
+        for logger in self.sinks() {
+            logger.log(record)
+        }
+    }
+
+    /// Shows the sequence number of each record in formatted log lines, as `#N` after the level.
+    pub fn set_sequence_numbers(&mut self, show: bool) {
+        self.sequence.show.store(show, Ordering::Relaxed);
+    }
+
+    /// Sends console records at or above the severity of `threshold` to stderr and the rest to
+    /// stdout. `Priority::Debug`, the default, sends everything to stderr.
+    pub fn set_stderr_threshold(&mut self, threshold: Priority) {
+        self.flush();
+        self.stderr_threshold
+            .store(threshold as u8, Ordering::Relaxed);
+    }
+
+    /// Sets the level of records logged under `facility`.
+    ///
+    /// A record is logged under the facility its target names, e.g.
+    /// `warn!(target: "daemon", ...)` for `Facility::Daemon`, and otherwise under the facility
+    /// set in `LogConfig::syslog_facility`. Whether a record is logged is decided by, in order:
+    ///
+    /// 1. the directive of the record filter naming the longest prefix of its target, if any;
+    /// 2. the level set here for its facility, if any;
+    /// 3. the default level of the record filter.
+    pub fn set_facility_log_level(&mut self, facility: Facility, filter: PriorityFilter) {
+        self.flush_coalesced();
+        match self
+            .facility_filters
+            .iter_mut()
+            .find(|(f, _)| *f == facility)
+        {
+            Some((_, f)) => *f = filter,
+            None => self.facility_filters.push((facility, filter)),
+        }
+    }
+
+    /// Replaces the record filter, in the syntax of `LogConfig::filter`. Levels set with
+    /// `set_facility_log_level` are kept.
+    pub fn set_log_level(&mut self, filter: &str) {
+        self.flush_coalesced();
+        let mut builder = env_logger::filter::Builder::new();
+        builder.parse(filter);
+        self.filter = builder.build();
+        self.path_directives = parse_path_directives(filter);
+    }
+
+    /// Returns the level set for the facility of records logged to `target`, unless a path
+    /// directive of the record filter applies to it.
+    fn facility_filter(&self, target: &str) -> Option<PriorityFilter> {
+        if self
+            .path_directives
+            .iter()
+            .any(|path| target.starts_with(path.as_str()))
+        {
+            return None;
+        }
+        let facility = Facility::from_target(target).unwrap_or(self.facility);
+        self.facility_filters
+            .iter()
+            .find(|(f, _)| *f == facility)
+            .map(|(_, filter)| *filter)
+    }
+
+    /// Returns true if `record` passes the precedence described in `set_facility_log_level`.
+    fn log_enabled(&self, record: &log::Record) -> bool {
+        match self.facility_filter(record.target()) {
+            Some(filter) => filter.allows(Priority::from(record.level())),
+            None => self.filter.matches(record),
+        }
+    }
+
+    /// Demotes repeats of a record within the coalescing window to `priority` instead of counting
+    /// them.
+    pub fn set_repeat_demotion(&mut self, priority: Priority) {
+        self.flush_coalesced();
+        self.repeat_demotion = Some(priority.level());
+    }
+
+    /// Restores counting the repeats demoted with `set_repeat_demotion`.
+    pub fn clear_repeat_demotion(&mut self) {
+        self.flush_coalesced();
+        self.repeat_demotion = None;
+    }
+
+    /// Returns true if `record` repeats the record currently being coalesced and should be
+    /// dropped. Otherwise, emits the summary of the previous record and starts coalescing
+    /// `record`.
+    fn coalesce(&self, record: &log::Record) -> bool {
+        let window = match self.coalesce_window {
+            Some(window) => window,
+            None => return false,
+        };
+        let message = record.args().to_string();
+        let mut hasher = DefaultHasher::new();
+        record.level().hash(&mut hasher);
+        record.target().hash(&mut hasher);
+        message.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        // Only the most recent record is kept, so memory use is bounded no matter how many
+        // distinct messages are logged.
+        let mut coalesced = self.coalesced.lock();
+        if let Some(c) = coalesced.as_mut() {
+            if c.hash == hash && c.start.elapsed() < window {
+                match self.repeat_demotion {
+                    // Repeats are re-evaluated at the demoted level instead of being counted, so
+                    // no summary is logged at the original level later.
+                    Some(level) if level < record.level() => {}
+                    Some(level) => {
+                        let demoted = record.to_builder().level(level).build();
+                        if self.log_enabled(&demoted) {
+                            self.emit(&demoted);
+                        }
+                    }
+                    None => c.count += 1,
+                }
+                return true;
+            }
+        }
+        if let Some(c) = coalesced.take() {
+            self.log_coalesced(&c);
+        }
+        *coalesced = Some(CoalescedRecord {
+            hash,
+            level: record.level(),
+            target: record.target().to_owned(),
+            message,
+            count: 1,
+            start: Instant::now(),
+        });
+        false
+    }
+
+    fn flush_coalesced(&self) {
+        if let Some(c) = self.coalesced.lock().take() {
+            self.log_coalesced(&c);
+        }
+    }
+
+    /// Emits the summary of the record being coalesced if its window has closed. Nothing runs in
+    /// the background, so this is done whenever the logger is used.
+    fn flush_expired_coalesced(&self) {
+        let window = match self.coalesce_window {
+            Some(window) => window,
+            None => return,
+        };
+        let mut coalesced = self.coalesced.lock();
+        let expired = coalesced
+            .as_ref()
+            .map_or(false, |c| c.start.elapsed() >= window);
+        if expired {
+            if let Some(c) = coalesced.take() {
+                self.log_coalesced(&c);
+            }
+        }
+    }
+
+    fn log_coalesced(&self, coalesced: &CoalescedRecord) {
+        // The first occurrence has already been logged, so a summary is only needed for repeats.
+        if coalesced.count < 2 {
+            return;
+        }
+        // Match is to explicitly limit lifetime of args
+        // https://github.com/rust-lang/rust/issues/92698
+        // https://github.com/rust-lang/rust/issues/15023
+        #[allow(clippy::match_single_binding)]
+        match format_args!("{} (x{})", coalesced.message, coalesced.count) {
+            args => {
+                let record = log::Record::builder()
+                    .level(coalesced.level)
+                    .target(&coalesced.target)
+                    .args(args)
+                    .build();
+                self.emit(&record);
+            }
+        }
+    }
 }
 
-static STATE: Lazy<Mutex<State>> = Lazy::new(|| {
+static LOGGER: Lazy<Mutex<Logger>> = Lazy::new(|| {
     let mut state = State::new(LogConfig::default()).expect("failed to configure minimal logging");
     state.early_init = true;
-    Mutex::new(state)
+    Mutex::new(Logger { state })
 });
 static LOGGING_FACADE: LoggingFacade = LoggingFacade {};
 static EARLY_INIT_CALLED: OnceCell<()> = OnceCell::new();
@@ -381,11 +1346,11 @@ pub fn init_with<F: 'static>(cfg: LogConfig<'_, F>) -> Result<(), Error>
 where
     F: Fn(&mut fmt::Formatter, &log::Record<'_>) -> std::io::Result<()> + Sync + Send,
 {
-    let mut state = STATE.lock();
-    if !state.early_init {
+    let mut logger = LOGGER.lock();
+    if !logger.state.early_init {
         panic!("double-init of the logging system is not permitted.");
     }
-    *state = State::new(cfg)?;
+    *logger = Logger::new(cfg)?;
 
     // This has no effect if the logging facade was already set.
     apply_logging_state(&LOGGING_FACADE);
@@ -393,6 +1358,15 @@ where
     Ok(())
 }
 
+/// Like `init`, but also returns a `SyslogGuard` that flushes the logger when dropped.
+///
+/// Keep the guard alive in `main` so records held back by the logger are written out even if
+/// the process returns without calling `log::logger().flush()`.
+pub fn init_guarded() -> Result<SyslogGuard<'static>, Error> {
+    init()?;
+    Ok(SyslogGuard::new())
+}
+
 /// Performs early (as in, moment of process start) logging initialization. Any logging prior to
 /// this call will be SILENTLY discarded. Calling more than once per process will panic.
 pub fn early_init() {
@@ -433,42 +1407,346 @@ fn apply_logging_state(facade: &'static LoggingFacade) {
     log::set_max_level(log::LevelFilter::Trace);
 }
 
-/// Retrieves the file descriptors owned by the global syslogger.
+/// Coalesces identical log records logged within `window` of each other into a single record
+/// annotated with the number of occurrences. A zero `window` disables coalescing.
 ///
-/// Does nothing if syslog was never initialized. If their are any file descriptors, they will be
-/// pushed into `fds`.
+/// The first occurrence is logged immediately; a summary line ending in "(xN)" follows once a
+/// different record is logged or the logger is flushed. After a burst followed by silence, the
+/// summary is written the next time the logger is used, even for a record that is filtered out,
+/// rather than the moment the window closes.
+pub fn set_coalesce_window(window: Duration) {
+    LOGGER.lock().state.set_coalesce_window(window);
+}
+
+/// Sets how the source location of records is shown in formatted log lines: the full file path,
+/// only the file name, or the module path (the default).
 ///
-/// Note that the `stderr` file descriptor is never added, as it is not owned by syslog.
-pub fn push_descriptors(fds: &mut Vec<RawDescriptor>) {
-    let state = STATE.lock();
-    fds.extend(state.descriptors.iter());
+/// Syslog output and custom pipe formatters are unaffected.
+pub fn set_source_display(source_display: SourceDisplay) {
+    LOGGER.lock().state.set_source_display(source_display);
 }
 
-impl Log for State {
-    fn enabled(&self, metadata: &log::Metadata) -> bool {
-        self.filter.enabled(metadata)
-    }
+/// Truncates messages longer than `max_len` bytes before they reach any log output, so that outputs
+/// with a fixed frame size never cut a message silently. The cut is made on a UTF-8 character
+/// boundary and followed by a "…[truncated N bytes]" marker. `None`, the default, removes the
+/// limit.
+pub fn set_max_message_len(max_len: Option<usize>) {
+    LOGGER.lock().state.set_max_message_len(max_len);
+}
 
-    fn log(&self, record: &log::Record) {
-        if self.filter.matches(record) {
-            for logger in self.loggers.iter() {
-                logger.log(record)
-            }
-        }
+/// Cuts `message` to at most `max_len` bytes without splitting a character and appends a marker
+/// with the number of bytes dropped.
+fn truncate_message(message: &str, max_len: usize) -> String {
+    let mut end = max_len.min(message.len());
+    while !message.is_char_boundary(end) {
+        end -= 1;
     }
+    format!(
+        "{}\u{2026}[truncated {} bytes]",
+        &message[..end],
+        message.len() - end
+    )
+}
 
-    fn flush(&self) {
-        for logger in self.loggers.iter() {
-            logger.flush()
-        }
-    }
+/// Returns the number of records logged by the global logger at each priority, indexed by
+/// `Priority` value (0 for emergency through 7 for debug). Records dropped by the filter are not
+/// counted.
+pub fn log_stats() -> [u64; 8] {
+    LOGGER.lock().log_stats()
+}
+
+/// Zeroes the counters returned by `log_stats`.
+pub fn reset_log_stats() {
+    LOGGER.lock().state.reset_log_stats();
+}
+
+/// Demotes repeats of an identical record logged within the coalescing window to `priority`, e.g.
+/// so that an error logged every frame is only logged once at the error priority. Repeats are
+/// filtered again at the demoted priority, so they are usually dropped.
+///
+/// Has no effect unless a window has been set with `set_coalesce_window`. A `priority` more severe
+/// than a repeat's own priority leaves that repeat dropped rather than promoted.
+pub fn set_repeat_demotion(priority: Priority) {
+    LOGGER.lock().state.set_repeat_demotion(priority);
+}
+
+/// Restores the default "(xN)" summaries of repeats demoted with `set_repeat_demotion`.
+pub fn clear_repeat_demotion() {
+    LOGGER.lock().state.clear_repeat_demotion();
+}
+
+/// Keeps the last `capacity` lines logged by the global logger at warning or more severe levels,
+/// so a short tail of errors is available for triage even when debug output floods the other
+/// outputs. A zero `capacity`, the default, disables the buffer.
+pub fn set_error_buffer(capacity: usize) {
+    LOGGER.lock().state.set_error_buffer(capacity);
+}
+
+/// Removes and returns the lines held by the buffer set with `set_error_buffer`, oldest first,
+/// each formatted as the level followed by the message.
+pub fn drain_error_buffer() -> Vec<String> {
+    LOGGER.lock().state.drain_error_buffer()
+}
+
+/// Retrieves the file descriptors owned by the global syslogger.
+///
+/// Does nothing if syslog was never initialized. If their are any file descriptors, they will be
+/// pushed into `fds`.
+///
+/// Note that the `stderr` file descriptor is never added, as it is not owned by syslog.
+pub fn push_descriptors(fds: &mut Vec<RawDescriptor>) {
+    let logger = LOGGER.lock();
+    let state = &logger.state;
+    fds.extend(state.descriptors.iter().map(|(descriptor, _)| descriptor));
+    fds.extend(*state.syslog_descriptor.lock());
+    fds.extend(state.file_sinks.iter().map(|(_, descriptor)| descriptor));
+    #[cfg(unix)]
+    fds.extend(state.socket_sink.iter().map(|(_, descriptor)| descriptor));
+    #[cfg(unix)]
+    fds.extend(state.journald_sink.iter().map(|(_, descriptor)| descriptor));
+}
+
+/// Returns the file descriptors owned by the global syslogger, each with its role: "syslog",
+/// "file", "journald" or "unix_socket". Meant for auditing the descriptors kept open across
+/// sandboxing.
+///
+/// Like `push_descriptors`, the `stderr` and `stdout` descriptors are not listed.
+pub fn describe_descriptors() -> Vec<(RawDescriptor, &'static str)> {
+    LOGGER.lock().state.describe_descriptors()
+}
+
+/// Adds `file` as an additional output of the global logger that receives the records allowed by
+/// `filter`.
+///
+/// Any number of file sinks may be added, each with its own filter, e.g. to send only errors to
+/// one file and everything to another.
+pub fn add_file_sink(file: File, filter: PriorityFilter) {
+    LOGGER.lock().add_file_sink(file, filter);
+}
+
+/// Removes all outputs added to the global logger with `add_file_sink`.
+pub fn clear_file_sinks() {
+    LOGGER.lock().clear_file_sinks();
+}
+
+/// Shows the sequence number of each record in lines formatted by the global logger, as `#N` after
+/// the level. Numbers follow the order records reach the outputs, across all threads.
+pub fn set_sequence_numbers(show: bool) {
+    LOGGER.lock().state.set_sequence_numbers(show);
+}
+
+/// Splits the console output of the global logger: records at or above the severity of `threshold`
+/// go to stderr and the rest to stdout, e.g. `Priority::Warning` keeps info and debug lines out of
+/// an init system's error journal. `Priority::Debug`, the default, sends everything to stderr.
+pub fn set_stderr_threshold(threshold: Priority) {
+    LOGGER.lock().state.set_stderr_threshold(threshold);
+}
+
+/// Returns the number of records the global logger dropped because the syslog could not be
+/// connected to when they were logged.
+pub fn dropped_syslog_messages() -> u64 {
+    LOGGER.lock().state.dropped_syslog_messages()
+}
+
+/// Replaces the record filter of the global logger. See `State::set_log_level`.
+pub fn set_log_level(filter: &str) {
+    LOGGER.lock().set_log_level(filter);
+}
+
+/// Sets the level of records logged under `facility` by the global logger. See
+/// `State::set_facility_log_level` for how this combines with the record filter.
+pub fn set_facility_log_level(facility: Facility, filter: PriorityFilter) {
+    LOGGER.lock().state.set_facility_log_level(facility, filter);
+}
+
+/// Sends each line logged by the global logger as a datagram to the Unix datagram socket at
+/// `path`, in addition to the other outputs. `None` stops sending.
+///
+/// Lines that cannot be sent are dropped and counted in `dropped_socket_lines`.
+#[cfg(unix)]
+pub fn echo_unix_socket(path: Option<&Path>) -> io::Result<()> {
+    LOGGER.lock().state.echo_unix_socket(path)
+}
+
+/// Sends each record logged by the global logger to journald through its native protocol when
+/// `enable` is true, in addition to the other outputs. See `State::echo_journald`.
+#[cfg(unix)]
+pub fn echo_journald(enable: bool) -> io::Result<()> {
+    LOGGER.lock().state.echo_journald(enable)
+}
+
+/// Returns the number of records the global logger failed to send to journald.
+#[cfg(unix)]
+pub fn dropped_journald_messages() -> u64 {
+    LOGGER.lock().state.dropped_journald_messages()
+}
+
+/// Returns the process name the global logger logs records under, or `None` if logging has not
+/// been initialized.
+#[cfg(unix)]
+pub fn get_proc_name() -> Option<String> {
+    LOGGER.lock().state.proc_name()
+}
+
+/// Returns the number of lines the global logger failed to send to the socket set with
+/// `echo_unix_socket`.
+#[cfg(unix)]
+pub fn dropped_socket_lines() -> u64 {
+    LOGGER.lock().state.dropped_socket_lines()
+}
+
+/// Logs an error like `error!`, followed by the last OS error, e.g.
+/// `error_errno!("failed to open {}", path)` logs
+/// "failed to open /dev/foo: No such file or directory (os error 2)".
+///
+/// The OS error is read before the message arguments are evaluated, so arguments that allocate or
+/// make system calls cannot clobber it.
+#[macro_export]
+macro_rules! error_errno {
+    ($($arg:tt)+) => {{
+        let os_error = ::std::io::Error::last_os_error();
+        $crate::error!("{}: {}", ::std::format_args!($($arg)+), os_error)
+    }};
+}
+
+impl Logger {
+    pub fn new<F: 'static>(cfg: LogConfig<'_, F>) -> Result<Self, Error>
+    where
+        F: Fn(&mut fmt::Formatter, &log::Record<'_>) -> std::io::Result<()> + Sync + Send,
+    {
+        Ok(Logger {
+            state: State::new(cfg)?,
+        })
+    }
+
+    /// See `State::set_log_level`.
+    pub fn set_log_level(&mut self, filter: &str) {
+        self.state.set_log_level(filter);
+    }
+
+    /// Echoes the records allowed by `filter` to `file`. See `State::add_file_sink`.
+    pub fn add_file_sink(&mut self, file: File, filter: PriorityFilter) {
+        self.state.add_file_sink(file, filter);
+    }
+
+    /// Stops echoing to the files added with `add_file_sink`.
+    pub fn clear_file_sinks(&mut self) {
+        self.state.clear_file_sinks();
+    }
+
+    /// See `State::log_stats`.
+    pub fn log_stats(&self) -> [u64; 8] {
+        self.state.log_stats()
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.state.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.state.log(record)
+    }
+
+    fn flush(&self) {
+        self.state.flush()
+    }
+}
+
+impl Log for State {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.flush_expired_coalesced();
+        match self.facility_filter(metadata.target()) {
+            Some(filter) => filter.allows(Priority::from(metadata.level())),
+            None => self.filter.enabled(metadata),
+        }
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.flush_expired_coalesced();
+        if !self.log_enabled(record) {
+            return;
+        }
+        self.stats[Priority::from(record.level()) as usize].fetch_add(1, Ordering::Relaxed);
+        if let Some(max_len) = self.max_message_len {
+            let message = record.args().to_string();
+            if message.len() > max_len {
+                let message = truncate_message(&message, max_len);
+                // Match is to explicitly limit lifetime of args
+                // https://github.com/rust-lang/rust/issues/92698
+                // https://github.com/rust-lang/rust/issues/15023
+                #[allow(clippy::match_single_binding)]
+                match format_args!("{}", message) {
+                    args => self.log_to_sinks(&record.to_builder().args(args).build()),
+                }
+                return;
+            }
+        }
+        self.log_to_sinks(record)
+    }
+
+    fn flush(&self) {
+        self.flush_coalesced();
+        for logger in self.sinks() {
+            logger.flush()
+        }
+    }
+}
+
+/// Flushes the global logger when dropped.
+///
+/// Records are written synchronously, but coalesced repeats are only summarized once the logger is
+/// flushed, so dropping the guard ensures those summaries and any buffered sink output reach
+/// their destinations. The flush happens at most once, and is harmless if logging was never
+/// initialized.
+#[must_use = "the logger is flushed when the guard is dropped"]
+pub struct SyslogGuard<'a> {
+    get_state_fn: Option<Box<dyn Fn() -> MutexGuard<'a, Logger> + Send + 'a>>,
+}
+
+impl<'a> SyslogGuard<'a> {
+    pub fn new() -> SyslogGuard<'a> {
+        SyslogGuard {
+            get_state_fn: Some(Box::new(|| LOGGER.lock())),
+        }
+    }
+
+    #[cfg(test)]
+    fn from_state<F: 'a + Fn() -> MutexGuard<'a, Logger> + Send>(
+        get_state_fn: F,
+    ) -> SyslogGuard<'a> {
+        SyslogGuard {
+            get_state_fn: Some(Box::new(get_state_fn)),
+        }
+    }
+
+    /// Flushes the logger now instead of when the guard is dropped. Later calls do nothing.
+    pub fn flush(&mut self) {
+        if let Some(get_state_fn) = self.get_state_fn.take() {
+            get_state_fn().flush();
+        }
+    }
+}
+
+impl<'a> Default for SyslogGuard<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> Drop for SyslogGuard<'a> {
+    fn drop(&mut self) {
+        self.flush();
+    }
 }
 
 // Struct that implements io::Write to be used for writing directly to the syslog
 pub struct Syslogger<'a> {
     buf: String,
     level: log::Level,
-    get_state_fn: Box<dyn Fn() -> MutexGuard<'a, State> + Send + 'a>,
+    prefix: String,
+    get_state_fn: Box<dyn Fn() -> MutexGuard<'a, Logger> + Send + 'a>,
 }
 
 impl<'a> Syslogger<'a> {
@@ -476,17 +1754,29 @@ impl<'a> Syslogger<'a> {
         Syslogger {
             buf: String::new(),
             level,
-            get_state_fn: Box::new(|| STATE.lock()),
+            prefix: String::new(),
+            get_state_fn: Box::new(|| LOGGER.lock()),
+        }
+    }
+
+    /// Creates a `Syslogger` that starts every logged line with `prefix`, e.g. to tell which
+    /// subsystem wrote it.
+    pub fn with_prefix(level: log::Level, prefix: String) -> Syslogger<'a> {
+        Syslogger {
+            prefix,
+            ..Syslogger::new(level)
         }
     }
+
     #[cfg(test)]
-    fn from_state<F: 'a + Fn() -> MutexGuard<'a, State> + Send>(
+    fn from_state<F: 'a + Fn() -> MutexGuard<'a, Logger> + Send>(
         level: log::Level,
         get_state_fn: F,
     ) -> Syslogger<'a> {
         Syslogger {
             buf: String::new(),
             level,
+            prefix: String::new(),
             get_state_fn: Box::new(get_state_fn),
         }
     }
@@ -504,7 +1794,7 @@ impl<'a> io::Write for Syslogger<'a> {
                 // https://github.com/rust-lang/rust/issues/92698
                 // https://github.com/rust-lang/rust/issues/15023
                 #[allow(clippy::match_single_binding)]
-                match format_args!("{}", line) {
+                match format_args!("{}{}", self.prefix, line) {
                     args => {
                         let mut record_builder = log::Record::builder();
                         record_builder.level(self.level);
@@ -522,7 +1812,7 @@ impl<'a> io::Write for Syslogger<'a> {
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        STATE.lock().flush();
+        LOGGER.lock().flush();
         Ok(())
     }
 }
@@ -540,7 +1830,6 @@ mod tests {
         }
     }
 
-    use std::sync::Arc;
     #[derive(Clone)]
     struct MockWrite {
         buffer: Arc<Mutex<Vec<u8>>>,
@@ -598,6 +1887,21 @@ mod tests {
         );
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn get_proc_name() {
+        let mut state = State::new(LogConfig {
+            proc_name: String::from("syslog-test"),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(state.proc_name().as_deref(), Some("syslog-test"));
+
+        // Like the global state before `init` replaces it.
+        state.early_init = true;
+        assert_eq!(state.proc_name(), None);
+    }
+
     #[test]
     fn macros() {
         ensure_inited().unwrap();
@@ -607,6 +1911,27 @@ mod tests {
         log::debug!("this is debug info {:?}", Some("helpful stuff"));
     }
 
+    #[test]
+    fn error_errno() {
+        ensure_inited().unwrap();
+        set_error_buffer(64);
+        // Safe because closing an invalid descriptor only sets errno.
+        assert_eq!(unsafe { libc::close(-1) }, -1);
+        error_errno!("close {} failed", -1);
+        set_error_buffer(0);
+
+        // Other tests may log through the global logger at the same time.
+        let lines = drain_error_buffer();
+        assert!(
+            lines.contains(&format!(
+                "ERROR close -1 failed: {}",
+                io::Error::from_raw_os_error(libc::EBADF)
+            )),
+            "{:?}",
+            lines
+        );
+    }
+
     fn pipe_formatter(buf: &mut fmt::Formatter, record: &Record<'_>) -> io::Result<()> {
         writeln!(buf, "{}", record.args())
     }
@@ -617,7 +1942,7 @@ mod tests {
         let mut cfg = LogConfig::default();
         cfg.pipe_formatter = Some(pipe_formatter);
         cfg.pipe = Some(Box::new(output.clone()));
-        let state = Mutex::new(State::new(cfg).unwrap());
+        let state = Mutex::new(Logger::new(cfg).unwrap());
 
         let mut syslogger = Syslogger::from_state(Level::Info, || state.lock());
 
@@ -644,7 +1969,7 @@ mod tests {
         let mut cfg = LogConfig::default();
         cfg.pipe_formatter = Some(pipe_formatter);
         cfg.pipe = Some(Box::new(output.clone()));
-        let state = Mutex::new(State::new(cfg).unwrap());
+        let state = Mutex::new(Logger::new(cfg).unwrap());
 
         let mut syslogger = Syslogger::from_state(Level::Info, || state.lock());
 
@@ -658,11 +1983,33 @@ mod tests {
         assert_eq!(s, String::from_utf8_lossy(&output.into_inner()[..]));
     }
 
+    #[test]
+    fn syslogger_prefix() {
+        let output = MockWrite::new();
+        let mut cfg = LogConfig::default();
+        cfg.pipe_formatter = Some(pipe_formatter);
+        cfg.pipe = Some(Box::new(output.clone()));
+        let state = Mutex::new(Logger::new(cfg).unwrap());
+
+        let mut syslogger = Syslogger::from_state(Level::Info, || state.lock());
+        syslogger.prefix = String::from("[child] ");
+
+        // The first line arrives in two writes but must only be prefixed once.
+        syslogger.write_all(b"first ").unwrap();
+        syslogger.write_all(b"line\nsecond line\n").unwrap();
+
+        std::mem::drop(syslogger);
+        std::mem::drop(state);
+        let output = String::from_utf8(output.into_inner()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines, ["[child] first line", "[child] second line"]);
+    }
+
     #[test]
     fn syslogger_partial() {
         let output = MockWrite::new();
         let state = Mutex::new(
-            State::new(LogConfig {
+            Logger::new(LogConfig {
                 pipe: Some(Box::new(output.clone())),
                 ..Default::default()
             })
@@ -682,6 +2029,624 @@ mod tests {
         assert_eq!(Vec::<u8>::new(), output.into_inner());
     }
 
+    #[test]
+    fn coalesce_identical_messages() {
+        let output = MockWrite::new();
+        let mut cfg = LogConfig::default();
+        cfg.pipe_formatter = Some(pipe_formatter);
+        cfg.pipe = Some(Box::new(output.clone()));
+        let mut state = State::new(cfg).unwrap();
+        state.set_coalesce_window(Duration::from_secs(3600));
+
+        for _ in 0..5 {
+            state.log(
+                &log::RecordBuilder::new()
+                    .level(Level::Warn)
+                    .args(format_args!("device warning"))
+                    .build(),
+            );
+        }
+        state.flush();
+
+        std::mem::drop(state);
+        let output = String::from_utf8(output.into_inner()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines, ["device warning", "device warning (x5)"]);
+    }
+
+    #[test]
+    fn coalesce_burst_then_silence() {
+        let output = MockWrite::new();
+        let mut cfg = LogConfig {
+            filter: "info",
+            ..Default::default()
+        };
+        cfg.pipe_formatter = Some(pipe_formatter);
+        cfg.pipe = Some(Box::new(output.clone()));
+        let mut state = State::new(cfg).unwrap();
+        state.set_coalesce_window(Duration::from_millis(10));
+
+        for _ in 0..3 {
+            state.log(
+                &log::RecordBuilder::new()
+                    .level(Level::Warn)
+                    .args(format_args!("burst"))
+                    .build(),
+            );
+        }
+        assert_eq!(output.buffer.lock().as_slice(), b"burst\n");
+        std::thread::sleep(Duration::from_millis(20));
+
+        // Checking whether a filtered out record is enabled emits the summary of the closed
+        // window without waiting for a flush.
+        assert!(!state.enabled(
+            log::RecordBuilder::new()
+                .level(Level::Debug)
+                .build()
+                .metadata(),
+        ));
+        assert_eq!(output.buffer.lock().as_slice(), b"burst\nburst (x3)\n");
+    }
+
+    #[test]
+    fn syslog_guard_flushes_on_drop() {
+        let output = MockWrite::new();
+        let mut cfg = LogConfig::default();
+        cfg.pipe_formatter = Some(pipe_formatter);
+        cfg.pipe = Some(Box::new(output.clone()));
+        let mut state = State::new(cfg).unwrap();
+        state.set_coalesce_window(Duration::from_secs(3600));
+        let state = Mutex::new(Logger { state });
+
+        let log_queued = || {
+            for _ in 0..3 {
+                state.lock().log(
+                    &log::RecordBuilder::new()
+                        .level(Level::Warn)
+                        .args(format_args!("queued"))
+                        .build(),
+                );
+            }
+        };
+
+        let guard = SyslogGuard::from_state(|| state.lock());
+        log_queued();
+        // Only the first occurrence is written until the logger is flushed.
+        assert_eq!(output.buffer.lock().as_slice(), b"queued\n");
+        std::mem::drop(guard);
+        assert_eq!(output.buffer.lock().as_slice(), b"queued\nqueued (x3)\n");
+
+        // An explicit flush followed by the drop flushes only once.
+        let mut guard = SyslogGuard::from_state(|| state.lock());
+        log_queued();
+        guard.flush();
+        log_queued();
+        std::mem::drop(guard);
+        let expected = b"queued\nqueued (x3)\nqueued\nqueued (x3)\nqueued\n";
+        assert_eq!(output.buffer.lock().as_slice(), expected);
+
+        std::mem::drop(state);
+        assert_eq!(output.into_inner(), expected);
+    }
+
+    #[test]
+    fn source_display_file_name() {
+        let output = MockWrite::new();
+        let mut state = State::new(LogConfig {
+            stderr: false,
+            syslog: false,
+            pipe: Some(Box::new(output.clone())),
+            ..Default::default()
+        })
+        .unwrap();
+        state.set_source_display(SourceDisplay::FileName);
+
+        state.log(
+            &log::RecordBuilder::new()
+                .level(Level::Info)
+                .file(Some(file!()))
+                .line(Some(line!()))
+                .module_path(Some(module_path!()))
+                .args(format_args!("source test"))
+                .build(),
+        );
+
+        std::mem::drop(state);
+        let output = String::from_utf8(output.into_inner()).unwrap();
+        let header = output.split(']').next().unwrap();
+        let source = header.split_whitespace().last().unwrap();
+        assert!(source.starts_with("syslog.rs:"), "{}", source);
+        assert!(!source.contains('/'));
+    }
+
+    #[test]
+    fn max_message_len() {
+        let output = MockWrite::new();
+        let mut cfg = LogConfig::default();
+        cfg.pipe_formatter = Some(pipe_formatter);
+        cfg.pipe = Some(Box::new(output.clone()));
+        let mut state = State::new(cfg).unwrap();
+        state.set_max_message_len(Some(8));
+
+        // "é" is two bytes, so the cut at byte 8 must back off to byte 7.
+        state.log(
+            &log::RecordBuilder::new()
+                .level(Level::Info)
+                .args(format_args!("abcdefgé and more"))
+                .build(),
+        );
+        state.log(
+            &log::RecordBuilder::new()
+                .level(Level::Info)
+                .args(format_args!("short"))
+                .build(),
+        );
+
+        std::mem::drop(state);
+        let output = String::from_utf8(output.into_inner()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines, ["abcdefg\u{2026}[truncated 11 bytes]", "short"]);
+    }
+
+    #[test]
+    fn log_stats() {
+        let state = State::new(LogConfig {
+            filter: "debug",
+            stderr: false,
+            syslog: false,
+            ..Default::default()
+        })
+        .unwrap();
+        let log_at = |level| {
+            state.log(
+                &log::RecordBuilder::new()
+                    .level(level)
+                    .args(format_args!("stats"))
+                    .build(),
+            )
+        };
+        log_at(Level::Error);
+        log_at(Level::Warn);
+        log_at(Level::Warn);
+        log_at(Level::Info);
+        log_at(Level::Debug);
+        log_at(Level::Trace);
+
+        let mut expected = [0; 8];
+        expected[Priority::Error as usize] = 1;
+        expected[Priority::Warning as usize] = 2;
+        expected[Priority::Info as usize] = 1;
+        // Trace is filtered out.
+        expected[Priority::Debug as usize] = 1;
+        assert_eq!(state.log_stats(), expected);
+
+        state.reset_log_stats();
+        assert_eq!(state.log_stats(), [0; 8]);
+    }
+
+    fn level_formatter(buf: &mut fmt::Formatter, record: &Record<'_>) -> io::Result<()> {
+        writeln!(buf, "{} {}", record.level(), record.args())
+    }
+
+    #[test]
+    fn error_buffer() {
+        let mut state = State::new(LogConfig {
+            filter: "debug",
+            stderr: false,
+            syslog: false,
+            ..Default::default()
+        })
+        .unwrap();
+        state.set_error_buffer(2);
+        for (level, message) in [
+            (Level::Error, "first error"),
+            (Level::Debug, "debug"),
+            (Level::Warn, "warning"),
+            (Level::Info, "info"),
+            (Level::Error, "second error"),
+        ] {
+            state.log(
+                &log::RecordBuilder::new()
+                    .level(level)
+                    .args(format_args!("{}", message))
+                    .build(),
+            );
+        }
+
+        // The oldest error is pushed out by the capacity.
+        assert_eq!(
+            state.drain_error_buffer(),
+            ["WARN warning", "ERROR second error"]
+        );
+        assert!(state.drain_error_buffer().is_empty());
+    }
+
+    #[test]
+    fn repeat_demotion() {
+        for (filter, expected) in [
+            ("info", &["ERROR frame error"][..]),
+            ("debug", &["ERROR frame error", "DEBUG frame error"][..]),
+        ] {
+            let output = MockWrite::new();
+            let mut cfg = LogConfig {
+                filter,
+                stderr: false,
+                syslog: false,
+                ..Default::default()
+            };
+            cfg.pipe_formatter = Some(level_formatter);
+            cfg.pipe = Some(Box::new(output.clone()));
+            let mut state = State::new(cfg).unwrap();
+            state.set_coalesce_window(Duration::from_secs(3600));
+            state.set_repeat_demotion(Priority::Debug);
+
+            for _ in 0..2 {
+                state.log(
+                    &log::RecordBuilder::new()
+                        .level(Level::Error)
+                        .args(format_args!("frame error"))
+                        .build(),
+                );
+            }
+            state.flush();
+
+            std::mem::drop(state);
+            let output = String::from_utf8(output.into_inner()).unwrap();
+            assert_eq!(output.lines().collect::<Vec<_>>(), expected);
+        }
+    }
+
+    #[test]
+    fn clear_repeat_demotion() {
+        let output = MockWrite::new();
+        let mut cfg = LogConfig::default();
+        cfg.pipe_formatter = Some(pipe_formatter);
+        cfg.pipe = Some(Box::new(output.clone()));
+        let mut state = State::new(cfg).unwrap();
+        state.set_coalesce_window(Duration::from_secs(3600));
+        state.set_repeat_demotion(Priority::Debug);
+        state.clear_repeat_demotion();
+
+        for _ in 0..3 {
+            state.log(
+                &log::RecordBuilder::new()
+                    .level(Level::Error)
+                    .args(format_args!("frame error"))
+                    .build(),
+            );
+        }
+        state.flush();
+
+        std::mem::drop(state);
+        let output = String::from_utf8(output.into_inner()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines, ["frame error", "frame error (x3)"]);
+    }
+
+    #[test]
+    fn describe_descriptors() {
+        let mut state = State::new(LogConfig {
+            stderr: true,
+            syslog: false,
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(state.describe_descriptors().is_empty());
+
+        let file = tempfile::tempfile().unwrap();
+        let descriptor = file.as_raw_descriptor();
+        state.add_file_sink(file, PriorityFilter::ShowAll);
+        assert_eq!(state.describe_descriptors(), [(descriptor, "file")]);
+    }
+
+    #[test]
+    fn priority_filter_level_filter() {
+        assert_eq!(PriorityFilter::Silent.level_filter(), LevelFilter::Off);
+        assert_eq!(PriorityFilter::ShowAll.level_filter(), LevelFilter::Trace);
+        let level_filter = |priority| PriorityFilter::Priority(priority).level_filter();
+        assert_eq!(level_filter(Priority::Critical), LevelFilter::Off);
+        assert_eq!(level_filter(Priority::Error), LevelFilter::Error);
+        assert_eq!(level_filter(Priority::Notice), LevelFilter::Warn);
+        assert_eq!(level_filter(Priority::Info), LevelFilter::Info);
+        assert_eq!(level_filter(Priority::Debug), LevelFilter::Trace);
+    }
+
+    #[test]
+    fn multiple_file_sinks() {
+        use std::io::Read;
+        use std::io::Seek;
+        use std::io::SeekFrom;
+
+        let mut state = State::new(LogConfig {
+            filter: "trace",
+            stderr: false,
+            syslog: false,
+            ..Default::default()
+        })
+        .unwrap();
+        let mut errors = tempfile::tempfile().unwrap();
+        let mut all = tempfile::tempfile().unwrap();
+        state.add_file_sink(
+            errors.try_clone().unwrap(),
+            PriorityFilter::Priority(Priority::Error),
+        );
+        state.add_file_sink(all.try_clone().unwrap(), PriorityFilter::ShowAll);
+
+        state.log(
+            &log::RecordBuilder::new()
+                .level(Level::Error)
+                .args(format_args!("an error"))
+                .build(),
+        );
+        state.log(
+            &log::RecordBuilder::new()
+                .level(Level::Info)
+                .args(format_args!("some info"))
+                .build(),
+        );
+        state.clear_file_sinks();
+
+        let read_all = |file: &mut File| {
+            let mut buf = String::new();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.read_to_string(&mut buf).unwrap();
+            buf
+        };
+        let errors = read_all(&mut errors);
+        assert!(errors.contains("an error"));
+        assert!(!errors.contains("some info"));
+        let all = read_all(&mut all);
+        assert!(all.contains("an error"));
+        assert!(all.contains("some info"));
+    }
+
+    #[test]
+    fn sequence_numbers() {
+        let output = MockWrite::new();
+        let mut state = State::new(LogConfig {
+            stderr: false,
+            syslog: false,
+            pipe: Some(Box::new(output.clone())),
+            ..Default::default()
+        })
+        .unwrap();
+        state.set_sequence_numbers(true);
+        let state = Arc::new(Mutex::new(state));
+
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                let state = state.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        state.lock().log(
+                            &log::RecordBuilder::new()
+                                .level(Level::Info)
+                                .args(format_args!("numbered"))
+                                .build(),
+                        );
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        std::mem::drop(state);
+        let output = String::from_utf8(output.into_inner()).unwrap();
+        let seqs: Vec<u64> = output
+            .lines()
+            .map(|line| {
+                let seq = line.split_whitespace().nth(2).unwrap();
+                seq.strip_prefix('#').unwrap().parse().unwrap()
+            })
+            .collect();
+        assert_eq!(seqs.len(), 200);
+        assert!(seqs.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", seqs);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn echo_unix_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log.sock");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+
+        let mut state = State::new(LogConfig {
+            stderr: false,
+            syslog: false,
+            ..Default::default()
+        })
+        .unwrap();
+        state.echo_unix_socket(Some(&path)).unwrap();
+        state.log(
+            &log::RecordBuilder::new()
+                .level(Level::Info)
+                .args(format_args!("to the collector"))
+                .build(),
+        );
+
+        let mut buf = [0u8; 1024];
+        let len = receiver.recv(&mut buf).unwrap();
+        let line = std::str::from_utf8(&buf[..len]).unwrap();
+        assert!(line.ends_with("to the collector\n"), "{}", line);
+        assert_eq!(state.dropped_socket_lines(), 0);
+
+        // Lines that cannot be delivered are counted rather than failing the log call.
+        std::mem::drop(receiver);
+        state.log(
+            &log::RecordBuilder::new()
+                .level(Level::Info)
+                .args(format_args!("nobody listening"))
+                .build(),
+        );
+        assert_eq!(state.dropped_socket_lines(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn echo_journald() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.sock");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+
+        let mut state = State::new(LogConfig {
+            stderr: false,
+            syslog: false,
+            proc_name: String::from("test-vm"),
+            ..Default::default()
+        })
+        .unwrap();
+        state.echo_journald_to(Some(&path)).unwrap();
+        state.log(
+            &log::RecordBuilder::new()
+                .level(Level::Warn)
+                .file(Some("src/main.rs"))
+                .line(Some(42))
+                .args(format_args!("disk almost full"))
+                .build(),
+        );
+
+        let mut buf = [0u8; 1024];
+        let len = receiver.recv(&mut buf).unwrap();
+        let fields: Vec<&str> = std::str::from_utf8(&buf[..len]).unwrap().lines().collect();
+        assert_eq!(
+            fields,
+            [
+                "PRIORITY=4",
+                "SYSLOG_IDENTIFIER=test-vm",
+                "CODE_FILE=src/main.rs",
+                "CODE_LINE=42",
+                "MESSAGE=disk almost full",
+            ]
+        );
+
+        // Records that cannot be delivered are counted rather than failing the log call.
+        std::mem::drop(receiver);
+        state.log(
+            &log::RecordBuilder::new()
+                .level(Level::Info)
+                .args(format_args!("nobody listening"))
+                .build(),
+        );
+        assert_eq!(state.dropped_journald_messages(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn journald_multiline_field() {
+        let mut buf = Vec::new();
+        append_journald_field(&mut buf, "MESSAGE", b"a\nb");
+        assert_eq!(buf, b"MESSAGE\n\x03\0\0\0\0\0\0\0a\nb\n");
+    }
+
+    #[test]
+    fn independent_loggers() {
+        // Each `Logger` is a complete logger of its own, so tests can use one without touching
+        // the global logger or each other.
+        let quiet_output = MockWrite::new();
+        let mut quiet = Logger::new(LogConfig {
+            filter: "error",
+            stderr: false,
+            syslog: false,
+            pipe: Some(Box::new(quiet_output.clone())),
+            ..Default::default()
+        })
+        .unwrap();
+        let verbose_output = MockWrite::new();
+        let verbose = Logger::new(LogConfig {
+            filter: "trace",
+            stderr: false,
+            syslog: false,
+            pipe: Some(Box::new(verbose_output.clone())),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let debug_record = |logger: &Logger| {
+            logger.log(
+                &log::RecordBuilder::new()
+                    .level(Level::Debug)
+                    .args(format_args!("details"))
+                    .build(),
+            )
+        };
+        debug_record(&quiet);
+        debug_record(&verbose);
+        assert_eq!(quiet.log_stats()[Priority::Debug as usize], 0);
+        assert_eq!(verbose.log_stats()[Priority::Debug as usize], 1);
+
+        // Reconfiguring one logger leaves the other alone.
+        quiet.set_log_level("debug");
+        debug_record(&quiet);
+        assert_eq!(quiet.log_stats()[Priority::Debug as usize], 1);
+        assert_eq!(verbose.log_stats()[Priority::Debug as usize], 1);
+
+        std::mem::drop(quiet);
+        std::mem::drop(verbose);
+        let quiet_output = String::from_utf8(quiet_output.into_inner()).unwrap();
+        assert_eq!(
+            quiet_output.matches("details").count(),
+            1,
+            "{}",
+            quiet_output
+        );
+        let verbose_output = String::from_utf8(verbose_output.into_inner()).unwrap();
+        assert_eq!(
+            verbose_output.matches("details").count(),
+            1,
+            "{}",
+            verbose_output
+        );
+    }
+
+    #[test]
+    fn stderr_threshold() {
+        let stderr = MockWrite::new();
+        let stdout = MockWrite::new();
+        let mut state = State::new(LogConfig {
+            stderr: false,
+            syslog: false,
+            ..Default::default()
+        })
+        .unwrap();
+        state.loggers.push(Box::new(create_console_logger(
+            env_logger::Target::Pipe(Box::new(stderr.clone())),
+            env_logger::Target::Pipe(Box::new(stdout.clone())),
+            &state.source_display,
+            &state.sequence,
+            state.stderr_threshold.clone(),
+        )));
+        state.set_stderr_threshold(Priority::Warning);
+
+        state.log(
+            &log::RecordBuilder::new()
+                .level(Level::Info)
+                .args(format_args!("routine"))
+                .build(),
+        );
+        state.log(
+            &log::RecordBuilder::new()
+                .level(Level::Error)
+                .args(format_args!("broken"))
+                .build(),
+        );
+
+        std::mem::drop(state);
+        let stderr = String::from_utf8(stderr.into_inner()).unwrap();
+        let stdout = String::from_utf8(stdout.into_inner()).unwrap();
+        assert!(
+            stderr.contains("broken") && !stderr.contains("routine"),
+            "{}",
+            stderr
+        );
+        assert!(
+            stdout.contains("routine") && !stdout.contains("broken"),
+            "{}",
+            stdout
+        );
+    }
+
     #[test]
     fn log_priority_try_from_number() {
         assert_eq!("0".try_into(), Ok(Priority::Emergency));
@@ -787,6 +2752,129 @@ mod tests {
         ));
     }
 
+    static SYSLOG_UP: AtomicBool = AtomicBool::new(false);
+    static SYSLOG_LINES: Lazy<Mutex<Vec<String>>> = Lazy::new(Default::default);
+
+    struct LineLogger;
+
+    impl Log for LineLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            SYSLOG_LINES.lock().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Descriptor `FlakySyslog` reports for its connection.
+    const FLAKY_SYSLOG_DESCRIPTOR: RawDescriptor = 42;
+
+    /// A syslog that can only be connected to once `SYSLOG_UP` is set.
+    struct FlakySyslog;
+
+    impl Syslog for FlakySyslog {
+        fn new(
+            _proc_name: String,
+            _facility: Facility,
+        ) -> Result<(Option<Box<dyn Log + Send>>, Option<RawDescriptor>), Error> {
+            if SYSLOG_UP.load(Ordering::Relaxed) {
+                Ok((Some(Box::new(LineLogger)), Some(FLAKY_SYSLOG_DESCRIPTOR)))
+            } else {
+                Err(Error::Connect(io::Error::from(
+                    io::ErrorKind::ConnectionRefused,
+                )))
+            }
+        }
+    }
+
+    #[test]
+    fn syslog_reconnects() {
+        let drops = Arc::new(AtomicU64::new(0));
+        let descriptor = Arc::new(Mutex::new(None));
+        let logger = ReconnectingSyslog::<FlakySyslog>::new(
+            String::from("crosvm"),
+            Facility::User,
+            Duration::ZERO,
+            drops.clone(),
+            descriptor.clone(),
+        );
+        let log = |message: &str| {
+            logger.log(
+                &log::RecordBuilder::new()
+                    .level(Level::Info)
+                    .args(format_args!("{}", message))
+                    .build(),
+            )
+        };
+
+        log("before");
+        assert_eq!(drops.load(Ordering::Relaxed), 1);
+        assert_eq!(*descriptor.lock(), None);
+        SYSLOG_UP.store(true, Ordering::Relaxed);
+        log("after");
+
+        assert_eq!(drops.load(Ordering::Relaxed), 1);
+        assert_eq!(*SYSLOG_LINES.lock(), ["after"]);
+        assert_eq!(*descriptor.lock(), Some(FLAKY_SYSLOG_DESCRIPTOR));
+    }
+
+    #[test]
+    fn facility_log_level() {
+        let pipe = MockWrite::new();
+        let mut state = State::new(LogConfig {
+            filter: "info",
+            stderr: false,
+            syslog: false,
+            pipe: Some(Box::new(pipe.clone())),
+            ..Default::default()
+        })
+        .unwrap();
+        state.set_facility_log_level(Facility::Daemon, PriorityFilter::Silent);
+
+        for target in ["daemon", "user"] {
+            state.log(
+                &log::RecordBuilder::new()
+                    .level(Level::Warn)
+                    .target(target)
+                    .args(format_args!("from {}", target))
+                    .build(),
+            );
+        }
+        std::mem::drop(state);
+
+        let output = String::from_utf8(pipe.into_inner()).unwrap();
+        assert!(!output.contains("from daemon"));
+        assert!(output.contains("from user"));
+    }
+
+    #[test]
+    fn path_directives_take_precedence_over_facility_log_level() {
+        let mut state = State::new(LogConfig {
+            filter: "info,user=error",
+            ..Default::default()
+        })
+        .unwrap();
+        state.set_facility_log_level(Facility::User, PriorityFilter::ShowAll);
+
+        assert!(state.enabled(
+            log::RecordBuilder::new()
+                .level(Level::Debug)
+                .target("other")
+                .build()
+                .metadata(),
+        ));
+        assert!(!state.enabled(
+            log::RecordBuilder::new()
+                .level(Level::Warn)
+                .target("user")
+                .build()
+                .metadata(),
+        ));
+    }
+
     #[test]
     fn longest_path_prefix_match_should_apply_if_multiple_filters_match() {
         let state = State::new(LogConfig {
@@ -23,8 +23,14 @@ pub enum Error {
     FdtGuestMemoryWriteError,
     #[error("I/O error reading FDT parameters code={0}")]
     FdtIoError(io::Error),
+    #[error("FDT at {0:#x} of size {1:#x} overlaps an occupied range at {2:#x} of size {3:#x}")]
+    FdtPlacementConflict(u64, usize, u64, usize),
+    #[error("Invalid property value: {0}")]
+    InvalidPropertyValue(String),
     #[error("Strings cannot contain NUL")]
     InvalidString,
+    #[error("Nodes may not be nested more than {0} deep")]
+    NodeTooDeep(usize),
     #[error("Attempted to end a node that was not the most recent")]
     OutOfOrderEndNode,
     #[error("Properties may not be added after a node has been ended")]
@@ -45,6 +51,10 @@ const FDT_LAST_COMP_VERSION: u32 = 16;
 
 const FDT_MAGIC: u32 = 0xd00dfeed;
 
+// Deepest nesting of nodes accepted by `begin_node`, the same limit the Linux kernel's device tree
+// unflattening applies.
+const FDT_MAX_NODE_DEPTH: usize = 64;
+
 const FDT_BEGIN_NODE: u32 = 0x00000001;
 const FDT_END_NODE: u32 = 0x00000002;
 const FDT_PROP: u32 = 0x00000003;
@@ -183,7 +193,12 @@ impl FdtWriter {
     /// # Arguments
     ///
     /// `name` - name of the node; must not contain any NUL bytes.
+    ///
+    /// Fails if the node would be nested more than 64 deep, counting the root node.
     pub fn begin_node(&mut self, name: &str) -> Result<FdtWriterNode> {
+        if self.node_depth >= FDT_MAX_NODE_DEPTH {
+            return Err(Error::NodeTooDeep(FDT_MAX_NODE_DEPTH));
+        }
         let name_cstr = CString::new(name).map_err(|_| Error::InvalidString)?;
         self.append_u32(FDT_BEGIN_NODE);
         self.data.extend(name_cstr.to_bytes_with_nul());
@@ -306,7 +321,23 @@ impl FdtWriter {
     /// # Arguments
     ///
     /// `max_size` - Maximum size of the finished DTB in bytes.
-    pub fn finish(mut self, max_size: usize) -> Result<Vec<u8>> {
+    pub fn finish(self, max_size: usize) -> Result<Vec<u8>> {
+        let mut data = self.finish_unpadded(max_size)?;
+        // Fill remaining data up to `max_size` with zeroes.
+        data.resize(max_size, 0);
+        Ok(data)
+    }
+
+    /// Finish writing the Devicetree Blob (DTB) without padding.
+    ///
+    /// Returns the DTB as a vector of bytes, consuming the `FdtWriter`. Unlike `finish`, the
+    /// returned value is exactly `totalsize` bytes long, which avoids allocating and copying
+    /// `max_size` bytes when the tree is much smaller than the space reserved for it.
+    ///
+    /// # Arguments
+    ///
+    /// `max_size` - Maximum size of the finished DTB in bytes.
+    pub fn finish_unpadded(mut self, max_size: usize) -> Result<Vec<u8>> {
         if self.node_depth > 0 {
             return Err(Error::UnclosedNode);
         }
@@ -346,8 +377,6 @@ impl FdtWriter {
         if self.data.len() > max_size {
             Err(Error::TotalSizeTooLarge)
         } else {
-            // Fill remaining data up to `max_size` with zeroes.
-            self.pad(max_size - self.data.len());
             Ok(self.data)
         }
     }
@@ -387,6 +416,29 @@ mod tests {
         );
     }
 
+    #[test]
+    fn unpadded() {
+        let build = || {
+            let mut fdt = FdtWriter::new(&[]);
+            let root_node = fdt.begin_node("").unwrap();
+            fdt.property_u32("u32", 0x12345678).unwrap();
+            fdt.property_string("str", "hello").unwrap();
+            fdt.end_node(root_node).unwrap();
+            fdt
+        };
+        let padded = build().finish(0x1000).unwrap();
+        let unpadded = build().finish_unpadded(0x1000).unwrap();
+        assert_eq!(padded.len(), 0x1000);
+        assert!(unpadded.len() < padded.len());
+        assert_eq!(unpadded[..], padded[..unpadded.len()]);
+        assert!(padded[unpadded.len()..].iter().all(|&b| b == 0));
+        assert_eq!(
+            u32::from_be_bytes(unpadded[4..8].try_into().unwrap()) as usize,
+            unpadded.len()
+        );
+        assert!(build().finish_unpadded(unpadded.len() - 1).is_err());
+    }
+
     #[test]
     fn reservemap() {
         let mut fdt = FdtWriter::new(&[
@@ -745,4 +797,33 @@ mod tests {
         fdt.finish(0x100)
             .expect_err("finish without ending all nodes");
     }
+
+    #[test]
+    fn finish_with_forgotten_end_node() {
+        let mut fdt = FdtWriter::new(&[]);
+        let root_node = fdt.begin_node("").unwrap();
+        let _nested_node = fdt.begin_node("mynode").unwrap();
+        // The nested node is never ended, so ending the root node fails as well.
+        assert!(matches!(
+            fdt.end_node(root_node),
+            Err(Error::OutOfOrderEndNode)
+        ));
+        assert!(matches!(fdt.finish(0x100), Err(Error::UnclosedNode)));
+    }
+
+    #[test]
+    fn invalid_node_too_deep() {
+        let mut fdt = FdtWriter::new(&[]);
+        let nodes: Vec<FdtWriterNode> = (0..FDT_MAX_NODE_DEPTH)
+            .map(|i| fdt.begin_node(&format!("n{}", i)).unwrap())
+            .collect();
+        assert!(matches!(
+            fdt.begin_node("deeper"),
+            Err(Error::NodeTooDeep(FDT_MAX_NODE_DEPTH))
+        ));
+        for node in nodes.into_iter().rev() {
+            fdt.end_node(node).unwrap();
+        }
+        fdt.finish(0x1000).unwrap();
+    }
 }
@@ -7,5 +7,6 @@
 mod fdt;
 
 pub use fdt::Error;
+pub use fdt::FdtReserveEntry;
 pub use fdt::FdtWriter;
 pub use fdt::Result;
@@ -155,7 +155,9 @@ impl BusDevice for Ioapic {
                 let val = u32::from_ne_bytes(data_arr);
                 self.ioapic_write(val);
             }
-            IOEOIR_OFF => self.end_of_interrupt(data[0]),
+            IOEOIR_OFF => {
+                self.end_of_interrupt(data[0]);
+            }
             _ => {
                 warn!("IOAPIC: Bad write to {}", info);
             }
@@ -252,8 +254,9 @@ impl Ioapic {
     }
 
     // The ioapic must be informed about EOIs in order to avoid sending multiple interrupts of the
-    // same type at the same time.
-    pub fn end_of_interrupt(&mut self, vector: u8) {
+    // same type at the same time. Returns the level-triggered pins that were using `vector`.
+    pub fn end_of_interrupt(&mut self, vector: u8) -> Vec<usize> {
+        let mut pins = Vec::new();
         if self.redirect_table[RTC_IRQ].get_vector() == vector && self.rtc_remote_irr {
             // Specifically clear RTC IRQ field
             self.rtc_remote_irr = false;
@@ -277,6 +280,7 @@ impl Ioapic {
                     }
                 }
                 self.redirect_table[i].set_remote_irr(false);
+                pins.push(i);
             }
             // There is an inherent race condition in hardware if the OS is finished processing an
             // interrupt and a new interrupt is delivered between issuing an EOI and the EOI being
@@ -285,6 +289,7 @@ impl Ioapic {
                 self.service_irq(i, true);
             }
         }
+        pins
     }
 
     pub fn service_irq(&mut self, irq: usize, level: bool) -> bool {
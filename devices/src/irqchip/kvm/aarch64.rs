@@ -6,10 +6,13 @@ use std::sync::Arc;
 
 use base::errno_result;
 use base::ioctl_with_ref;
+use base::Error;
 use base::Result;
 use base::SafeDescriptor;
 use hypervisor::kvm::KvmVcpu;
+use hypervisor::kvm::KvmVgicDevice;
 use hypervisor::kvm::KvmVm;
+use hypervisor::kvm::TypedKvmDevice;
 use hypervisor::DeviceKind;
 use hypervisor::IrqRoute;
 use hypervisor::Vm;
@@ -30,6 +33,14 @@ fn kvm_default_irq_routing_table() -> Vec<IrqRoute> {
     routes
 }
 
+/// Creates an in-kernel vGIC of `kind`.
+fn create_vgic(vm: &KvmVm, kind: DeviceKind) -> Result<KvmVgicDevice> {
+    match vm.create_device_typed(kind)? {
+        TypedKvmDevice::ArmVgic(vgic) => Ok(vgic),
+        _ => Err(Error::new(libc::ENXIO)),
+    }
+}
+
 /// IrqChip implementation where the entire IrqChip is emulated by KVM.
 ///
 /// This implementation will use the KVM API to create and configure the in-kernel irqchip.
@@ -65,72 +76,25 @@ impl KvmKernelIrqChip {
         let cpu_if_addr: u64 = AARCH64_GIC_CPUI_BASE;
         let dist_if_addr: u64 = AARCH64_GIC_DIST_BASE;
         let redist_addr: u64 = dist_if_addr - (AARCH64_GIC_REDIST_SIZE * num_vcpus as u64);
-        let raw_cpu_if_addr = &cpu_if_addr as *const u64;
-        let raw_dist_if_addr = &dist_if_addr as *const u64;
-        let raw_redist_addr = &redist_addr as *const u64;
-
-        let cpu_if_attr = kvm_device_attr {
-            group: KVM_DEV_ARM_VGIC_GRP_ADDR,
-            attr: KVM_VGIC_V2_ADDR_TYPE_CPU as u64,
-            addr: raw_cpu_if_addr as u64,
-            flags: 0,
-        };
-        let redist_attr = kvm_device_attr {
-            group: KVM_DEV_ARM_VGIC_GRP_ADDR,
-            attr: KVM_VGIC_V3_ADDR_TYPE_REDIST as u64,
-            addr: raw_redist_addr as u64,
-            flags: 0,
-        };
-        let mut dist_attr = kvm_device_attr {
-            group: KVM_DEV_ARM_VGIC_GRP_ADDR,
-            addr: raw_dist_if_addr as u64,
-            attr: 0,
-            flags: 0,
-        };
-
-        let (vgic, device_kind, cpu_redist_attr, dist_attr_attr) =
-            match vm.create_device(DeviceKind::ArmVgicV3) {
-                Err(_) => (
-                    vm.create_device(DeviceKind::ArmVgicV2)?,
-                    DeviceKind::ArmVgicV2,
-                    cpu_if_attr,
-                    KVM_VGIC_V2_ADDR_TYPE_DIST as u64,
-                ),
-                Ok(vgic) => (
-                    vgic,
-                    DeviceKind::ArmVgicV3,
-                    redist_attr,
-                    KVM_VGIC_V3_ADDR_TYPE_DIST as u64,
-                ),
-            };
-        dist_attr.attr = dist_attr_attr;
-
-        // Safe because we allocated the struct that's being passed in
-        let ret = unsafe { ioctl_with_ref(&vgic, KVM_SET_DEVICE_ATTR(), &cpu_redist_attr) };
-        if ret != 0 {
-            return errno_result();
-        }
 
-        // Safe because we allocated the struct that's being passed in
-        let ret = unsafe { ioctl_with_ref(&vgic, KVM_SET_DEVICE_ATTR(), &dist_attr) };
-        if ret != 0 {
-            return errno_result();
-        }
+        let vgic = match create_vgic(&vm, DeviceKind::ArmVgicV3) {
+            Err(_) => {
+                let vgic = create_vgic(&vm, DeviceKind::ArmVgicV2)?;
+                vgic.set_addr(KVM_VGIC_V2_ADDR_TYPE_CPU, cpu_if_addr)?;
+                vgic.set_addr(KVM_VGIC_V2_ADDR_TYPE_DIST, dist_if_addr)?;
+                vgic
+            }
+            Ok(vgic) => {
+                vgic.set_addr(KVM_VGIC_V3_ADDR_TYPE_REDIST, redist_addr)?;
+                vgic.set_addr(KVM_VGIC_V3_ADDR_TYPE_DIST, dist_if_addr)?;
+                vgic
+            }
+        };
+        let device_kind = vgic.kind();
 
         // We need to tell the kernel how many irqs to support with this vgic
-        let nr_irqs: u32 = AARCH64_GIC_NR_IRQS;
-        let nr_irqs_ptr = &nr_irqs as *const u32;
-        let nr_irqs_attr = kvm_device_attr {
-            group: KVM_DEV_ARM_VGIC_GRP_NR_IRQS,
-            attr: 0,
-            addr: nr_irqs_ptr as u64,
-            flags: 0,
-        };
-        // Safe because we allocated the struct that's being passed in
-        let ret = unsafe { ioctl_with_ref(&vgic, KVM_SET_DEVICE_ATTR(), &nr_irqs_attr) };
-        if ret != 0 {
-            return errno_result();
-        }
+        vgic.set_nr_irqs(AARCH64_GIC_NR_IRQS)?;
+        let vgic = vgic.into_descriptor();
 
         Ok(KvmKernelIrqChip {
             vm,
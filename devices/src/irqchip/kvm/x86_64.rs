@@ -226,7 +226,7 @@ impl KvmSplitIrqChip {
         ioapic_pins: Option<usize>,
     ) -> Result<Self> {
         let ioapic_pins = ioapic_pins.unwrap_or(vm.get_ioapic_num_pins()?);
-        vm.enable_split_irqchip(ioapic_pins)?;
+        vm.enable_split_irqchip(ioapic_pins as u32)?;
         let pit_evt = IrqEdgeEvent::new()?;
         let pit = Pit::new(pit_evt.try_clone()?, Arc::new(Mutex::new(Clock::new()))).map_err(
             |e| match e {
@@ -543,9 +543,13 @@ impl IrqChip for KvmSplitIrqChip {
         Ok(())
     }
 
-    /// Broadcast an end of interrupt. For KvmSplitIrqChip this sends the EOI to the ioapic
+    /// Broadcast an end of interrupt. For KvmSplitIrqChip this sends the EOI to the ioapic and
+    /// signals the ack notifiers registered with the VM for the pins it ends.
     fn broadcast_eoi(&self, vector: u8) -> Result<()> {
-        self.ioapic.lock().end_of_interrupt(vector);
+        let pins = self.ioapic.lock().end_of_interrupt(vector);
+        for pin in pins {
+            self.vm.notify_irq_ack(pin as u32)?;
+        }
         Ok(())
     }
 
@@ -3,6 +3,7 @@
 // found in the LICENSE file.
 
 #![cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use base::Event;
 use base::EventWaitResult;
 use base::Tube;
 use devices::Bus;
@@ -457,3 +458,44 @@ fn broadcast_eoi() {
         EventWaitResult::Signaled
     );
 }
+
+#[test]
+fn broadcast_eoi_notifies_ack() {
+    let kvm = Kvm::new().expect("failed to instantiate Kvm");
+    let mem = GuestMemory::new(&[]).unwrap();
+    let vm = KvmVm::new(&kvm, mem, Default::default()).expect("failed to instantiate vm");
+    let (_, device_tube) = Tube::pair().expect("failed to create irq tube");
+    let mut chip = KvmSplitIrqChip::new(
+        vm.try_clone().expect("failed to clone vm"),
+        1,
+        device_tube,
+        None,
+    )
+    .expect("failed to instantiate KvmSplitIrqChip");
+
+    let ack = Event::new().expect("failed to create event");
+    vm.register_ioapic_ack_notifier(1, &ack)
+        .expect("failed to register ack notifier");
+
+    // route ioapic pin 1 to vector 123, level triggered
+    let mut state = chip.get_ioapic_state().expect("failed to get ioapic state");
+    state.redirect_table[1].set_vector(123);
+    state.redirect_table[1].set_trigger_mode(TriggerMode::Level);
+    chip.set_ioapic_state(&state)
+        .expect("failed to set ioapic state");
+
+    // an eoi for another vector leaves the notifier alone
+    chip.broadcast_eoi(124).expect("failed to broadcast eoi");
+    assert_eq!(
+        ack.wait_timeout(std::time::Duration::from_millis(10))
+            .expect("failed to read_timeout"),
+        EventWaitResult::TimedOut
+    );
+
+    chip.broadcast_eoi(123).expect("failed to broadcast eoi");
+    assert_eq!(
+        ack.wait_timeout(std::time::Duration::from_millis(10))
+            .expect("failed to read_timeout"),
+        EventWaitResult::Signaled
+    );
+}
@@ -20,6 +20,7 @@ use crate::IrqRoute;
 use crate::IrqSource;
 use crate::IrqSourceChip;
 use crate::Vcpu;
+use crate::VcpuExit;
 use crate::Vm;
 
 /// Represents a version of Power State Coordination Interface (PSCI).
@@ -50,6 +51,57 @@ impl TryFrom<u32> for PsciVersion {
 pub const PSCI_0_2: PsciVersion = PsciVersion { major: 0, minor: 2 };
 pub const PSCI_1_0: PsciVersion = PsciVersion { major: 1, minor: 0 };
 
+// PSCI function ids, from the Arm Power State Coordination Interface specification (DEN0022).
+const PSCI_CPU_OFF: u32 = 0x8400_0002;
+const PSCI_CPU_ON: u32 = 0x8400_0003;
+const PSCI_CPU_ON_64: u32 = 0xc400_0003;
+const PSCI_SYSTEM_OFF: u32 = 0x8400_0008;
+const PSCI_SYSTEM_RESET: u32 = 0x8400_0009;
+
+/// A PSCI call made by the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PsciCall {
+    /// Power up the core with MPIDR `target_mpidr`, starting it at `entry` with `context` in x0.
+    CpuOn {
+        target_mpidr: u64,
+        entry: u64,
+        context: u64,
+    },
+    /// Power down the calling core.
+    CpuOff,
+    /// Reset the whole system.
+    SystemReset,
+    /// Shut down the whole system.
+    SystemOff,
+}
+
+/// Decodes the PSCI call carried by a `VcpuExit::Hvc`.
+///
+/// Returns `None` if `exit` is not an HVC exit or is not one of the calls in `PsciCall`. The
+/// arguments of the SMC32 calling convention variant of CPU_ON are truncated to 32 bits.
+pub fn decode_psci_hvc(exit: &VcpuExit) -> Option<PsciCall> {
+    let (function_id, args) = match *exit {
+        VcpuExit::Hvc { function_id, args } => (function_id, args),
+        _ => return None,
+    };
+    match function_id {
+        PSCI_CPU_ON => Some(PsciCall::CpuOn {
+            target_mpidr: args[0] as u32 as u64,
+            entry: args[1] as u32 as u64,
+            context: args[2] as u32 as u64,
+        }),
+        PSCI_CPU_ON_64 => Some(PsciCall::CpuOn {
+            target_mpidr: args[0],
+            entry: args[1],
+            context: args[2],
+        }),
+        PSCI_CPU_OFF => Some(PsciCall::CpuOff),
+        PSCI_SYSTEM_RESET => Some(PsciCall::SystemReset),
+        PSCI_SYSTEM_OFF => Some(PsciCall::SystemOff),
+        _ => None,
+    }
+}
+
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub enum VcpuRegAArch64 {
     X(u8),
@@ -161,3 +213,59 @@ pub enum VcpuFeature {
     /// Starts the VCPU in a power-off state.
     PowerOff,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_psci_cpu_on() {
+        let exit = VcpuExit::Hvc {
+            function_id: PSCI_CPU_ON_64,
+            args: [0x8000_0101, 0x8008_0000, 0x1234],
+        };
+        assert_eq!(
+            decode_psci_hvc(&exit),
+            Some(PsciCall::CpuOn {
+                target_mpidr: 0x8000_0101,
+                entry: 0x8008_0000,
+                context: 0x1234,
+            })
+        );
+    }
+
+    #[test]
+    fn decode_psci_cpu_on_32() {
+        let exit = VcpuExit::Hvc {
+            function_id: PSCI_CPU_ON,
+            args: [0xffff_ffff_0000_0001, 0xffff_ffff_8008_0000, 0],
+        };
+        assert_eq!(
+            decode_psci_hvc(&exit),
+            Some(PsciCall::CpuOn {
+                target_mpidr: 1,
+                entry: 0x8008_0000,
+                context: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn decode_psci_other() {
+        let hvc = |function_id| VcpuExit::Hvc {
+            function_id,
+            args: [0; 3],
+        };
+        assert_eq!(decode_psci_hvc(&hvc(PSCI_CPU_OFF)), Some(PsciCall::CpuOff));
+        assert_eq!(
+            decode_psci_hvc(&hvc(PSCI_SYSTEM_RESET)),
+            Some(PsciCall::SystemReset)
+        );
+        assert_eq!(
+            decode_psci_hvc(&hvc(PSCI_SYSTEM_OFF)),
+            Some(PsciCall::SystemOff)
+        );
+        assert_eq!(decode_psci_hvc(&hvc(0x8400_0000)), None);
+        assert_eq!(decode_psci_hvc(&VcpuExit::Hypercall), None);
+    }
+}
@@ -3,6 +3,7 @@
 // found in the LICENSE file.
 
 use std::convert::TryFrom;
+use std::sync::Arc;
 
 use base::errno_result;
 use base::error;
@@ -10,8 +11,11 @@ use base::ioctl_with_mut_ref;
 use base::ioctl_with_ref;
 use base::ioctl_with_val;
 use base::warn;
+use base::AsRawDescriptor;
 use base::Error;
+use base::RawDescriptor;
 use base::Result;
+use base::SafeDescriptor;
 #[cfg(feature = "gdb")]
 use gdbstub::arch::Arch;
 #[cfg(feature = "gdb")]
@@ -19,6 +23,7 @@ use gdbstub_arch::aarch64::reg::id::AArch64RegId;
 #[cfg(feature = "gdb")]
 use gdbstub_arch::aarch64::AArch64 as GdbArch;
 use kvm_sys::*;
+use libc::EBUSY;
 use libc::EINVAL;
 #[cfg(feature = "gdb")]
 use libc::ENOBUFS;
@@ -29,8 +34,10 @@ use libc::ENOTSUP;
 #[cfg(feature = "gdb")]
 use libc::ENOTUNIQ;
 use libc::ENXIO;
+use sync::Mutex;
 use vm_memory::GuestAddress;
 
+use super::set_device_attr;
 use super::Config;
 use super::Kvm;
 use super::KvmCap;
@@ -39,6 +46,7 @@ use super::KvmVm;
 use crate::ClockState;
 use crate::DeviceKind;
 use crate::Hypervisor;
+use crate::IrqRoute;
 use crate::IrqSourceChip;
 use crate::ProtectionType;
 use crate::PsciVersion;
@@ -46,10 +54,108 @@ use crate::VcpuAArch64;
 use crate::VcpuExit;
 use crate::VcpuFeature;
 use crate::VcpuRegAArch64;
+use crate::Vm;
 use crate::VmAArch64;
 use crate::VmCap;
 use crate::PSCI_0_2;
 
+/// An in-kernel ARM virtual GIC, created with `KvmVm::create_device_typed`.
+pub struct KvmVgicDevice {
+    kind: DeviceKind,
+    descriptor: SafeDescriptor,
+    /// The GSI routing table cached by the VM the GIC belongs to.
+    routes: Arc<Mutex<Vec<IrqRoute>>>,
+}
+
+impl KvmVgicDevice {
+    pub(super) fn new(
+        kind: DeviceKind,
+        descriptor: SafeDescriptor,
+        routes: Arc<Mutex<Vec<IrqRoute>>>,
+    ) -> Self {
+        KvmVgicDevice {
+            kind,
+            descriptor,
+            routes,
+        }
+    }
+
+    /// Returns whether this is a GICv2 or GICv3.
+    pub fn kind(&self) -> DeviceKind {
+        self.kind
+    }
+
+    /// Sets the guest physical base address of the region selected by `addr_type`, one of the
+    /// `KVM_VGIC_V2_ADDR_TYPE_*` or `KVM_VGIC_V3_ADDR_TYPE_*` values matching the GIC version.
+    pub fn set_addr(&self, addr_type: u32, addr: u64) -> Result<()> {
+        // Safe because the kernel reads a u64 for KVM_DEV_ARM_VGIC_GRP_ADDR and `addr` outlives
+        // the call.
+        unsafe {
+            set_device_attr(
+                &self.descriptor,
+                KVM_DEV_ARM_VGIC_GRP_ADDR,
+                addr_type as u64,
+                &addr as *const u64 as u64,
+            )
+        }
+    }
+
+    /// Sets the number of interrupts supported by the GIC, including SGIs and PPIs.
+    ///
+    /// The VM's cached GSI routing table is reseeded with the default table KVM installs for that
+    /// many SPIs.
+    pub fn set_nr_irqs(&self, nr_irqs: u32) -> Result<()> {
+        // Safe because the kernel reads a u32 for KVM_DEV_ARM_VGIC_GRP_NR_IRQS and `nr_irqs`
+        // outlives the call.
+        unsafe {
+            set_device_attr(
+                &self.descriptor,
+                KVM_DEV_ARM_VGIC_GRP_NR_IRQS,
+                0,
+                &nr_irqs as *const u32 as u64,
+            )
+        }?;
+        *self.routes.lock() = vgic_irq_routes(nr_irqs.saturating_sub(VGIC_PRIVATE_IRQS));
+        Ok(())
+    }
+
+    /// Initializes the GIC. Must be called after its addresses and interrupt count are set.
+    pub fn init(&self) -> Result<()> {
+        // Safe because KVM_DEV_ARM_VGIC_CTRL_INIT does not read from `addr`.
+        unsafe {
+            set_device_attr(
+                &self.descriptor,
+                KVM_DEV_ARM_VGIC_GRP_CTRL,
+                KVM_DEV_ARM_VGIC_CTRL_INIT as u64,
+                0,
+            )
+        }
+    }
+
+    /// Returns the underlying device descriptor.
+    pub fn into_descriptor(self) -> SafeDescriptor {
+        self.descriptor
+    }
+}
+
+impl AsRawDescriptor for KvmVgicDevice {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.descriptor.as_raw_descriptor()
+    }
+}
+
+// Ensures every id names a 64-bit register, since the batched one-reg accessors transfer exactly a
+// u64 per register.
+fn check_one_reg_ids(mut ids: impl Iterator<Item = u64>) -> Result<()> {
+    match ids.find(|id| id & KVM_REG_SIZE_MASK != KVM_REG_SIZE_U64) {
+        Some(id) => {
+            error!("register {:#x} is not 64 bits wide", id);
+            Err(Error::new(EINVAL))
+        }
+        None => Ok(()),
+    }
+}
+
 impl Kvm {
     // Compute the machine type, which should be the IPA range for the VM
     // Ideally, this would take a description of the memory map and return
@@ -164,10 +270,54 @@ impl KvmVm {
         }
     }
 
+    /// Creates an in-kernel GICv2 with its distributor at `dist_base` and its CPU interface at
+    /// `cpu_base`, returning the device descriptor. The caller still has to set the number of
+    /// interrupts and initialize the device.
+    pub fn create_gic_v2(&self, dist_base: u64, cpu_base: u64) -> Result<SafeDescriptor> {
+        let gic = KvmVgicDevice::new(
+            DeviceKind::ArmVgicV2,
+            self.create_device(DeviceKind::ArmVgicV2)?,
+            self.routes.clone(),
+        );
+        gic.set_addr(KVM_VGIC_V2_ADDR_TYPE_DIST, dist_base)?;
+        gic.set_addr(KVM_VGIC_V2_ADDR_TYPE_CPU, cpu_base)?;
+        Ok(gic.into_descriptor())
+    }
+
     /// Enable userspace msr. This is not available on ARM, just succeed.
     pub fn enable_userspace_msr(&self) -> Result<()> {
         Ok(())
     }
+
+    /// Prepares the VM for a GIC emulated in user space, returning the value KVM reports for
+    /// KVM_CAP_ARM_USER_IRQ. It is positive when KVM reflects the levels of its device interrupt
+    /// lines (timers and PMU) to user space through `kvm_run`.
+    ///
+    /// KVM has no separate enable step for this capability; the lines are reported whenever no
+    /// in-kernel vGIC exists. Returns `ENOTSUP` if the kernel lacks the capability, and `EBUSY` if
+    /// an in-kernel vGIC has already been created.
+    pub fn enable_arm_user_irq(&self) -> Result<u32> {
+        if self
+            .kernel_irqchip
+            .load(std::sync::atomic::Ordering::Acquire)
+        {
+            return Err(Error::new(EBUSY));
+        }
+        // Safe because we know that our file is a VM fd, and if the cap is invalid KVM assumes
+        // it's an unavailable extension and returns 0.
+        let ret = unsafe {
+            ioctl_with_val(
+                self,
+                KVM_CHECK_EXTENSION(),
+                KvmCap::ArmUserIrq as libc::c_ulong,
+            )
+        };
+        if ret > 0 {
+            Ok(ret as u32)
+        } else {
+            Err(Error::new(ENOTSUP))
+        }
+    }
 }
 
 #[repr(C)]
@@ -228,6 +378,21 @@ impl KvmVcpu {
         Ok(VcpuExit::SystemEventReset)
     }
 
+    /// Handles a `KVM_EXIT_HYPERCALL` and returns the appropriate `VcpuExit` value for the run
+    /// loop to handle.
+    ///
+    /// KVM leaves the SMCCC function id and arguments in the guest's registers, so they are read
+    /// back here.
+    pub fn hypercall_exit(&self) -> Result<VcpuExit> {
+        let function_id = self.get_one_reg(VcpuRegAArch64::X(0))? as u32;
+        let args = [
+            self.get_one_reg(VcpuRegAArch64::X(1))?,
+            self.get_one_reg(VcpuRegAArch64::X(2))?,
+            self.get_one_reg(VcpuRegAArch64::X(3))?,
+        ];
+        Ok(VcpuExit::Hvc { function_id, args })
+    }
+
     fn set_one_kvm_reg_u64(&self, kvm_reg_id: KvmVcpuRegister, data: u64) -> Result<()> {
         self.set_one_kvm_reg(kvm_reg_id, data.to_ne_bytes().as_slice())
     }
@@ -272,6 +437,74 @@ impl KvmVcpu {
             errno_result()
         }
     }
+
+    /// Reads the core register file of this VCPU, returning each register with its value.
+    pub fn core_regs(&self) -> Result<Vec<(Aarch64CoreReg, u64)>> {
+        Aarch64CoreReg::all()
+            .map(|reg| Ok((reg, self.get_one_kvm_reg_u64(reg.into())?)))
+            .collect()
+    }
+
+    /// Reads the 64-bit registers identified by the raw KVM register ids in `ids`, returning their
+    /// values in the same order.
+    ///
+    /// Each register still takes one KVM_GET_ONE_REG, but the whole batch shares one request and
+    /// one result buffer. On failure, the id of the first register that could not be read is
+    /// logged and its error returned.
+    pub fn get_one_regs(&self, ids: &[u64]) -> Result<Vec<u64>> {
+        check_one_reg_ids(ids.iter().copied())?;
+        let mut values = vec![0u64; ids.len()];
+        let mut onereg = kvm_one_reg { id: 0, addr: 0 };
+        for (&id, value) in ids.iter().zip(values.iter_mut()) {
+            onereg.id = id;
+            onereg.addr = value as *mut u64 as u64;
+            // Safe because `id` was checked to name a 64-bit register, so the kernel writes
+            // exactly one u64 to `value`, which outlives the call.
+            let ret = unsafe { ioctl_with_ref(self, KVM_GET_ONE_REG(), &onereg) };
+            if ret != 0 {
+                let err = Error::last();
+                error!("failed to get register {:#x}: {}", id, err);
+                return Err(err);
+            }
+        }
+        Ok(values)
+    }
+
+    /// Writes each `(id, value)` pair in `regs`, where `id` is the raw KVM id of a 64-bit
+    /// register, in order.
+    ///
+    /// Like `get_one_regs`, this issues one KVM_SET_ONE_REG per register; on failure, the id of
+    /// the first register that could not be written is logged and its error returned. Registers
+    /// before it have already been written.
+    pub fn set_one_regs(&self, regs: &[(u64, u64)]) -> Result<()> {
+        check_one_reg_ids(regs.iter().map(|&(id, _)| id))?;
+        let mut onereg = kvm_one_reg { id: 0, addr: 0 };
+        for (id, value) in regs {
+            onereg.id = *id;
+            onereg.addr = value as *const u64 as u64;
+            // Safe because `id` was checked to name a 64-bit register, so the kernel reads exactly
+            // one u64 from `value`, which outlives the call.
+            let ret = unsafe { ioctl_with_ref(self, KVM_SET_ONE_REG(), &onereg) };
+            if ret != 0 {
+                let err = Error::last();
+                error!("failed to set register {:#x}: {}", id, err);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the registers the arm64 boot protocol expects on entry to a kernel at `entry`, with
+    /// the device tree at `dtb_addr`.
+    ///
+    /// PSTATE is EL1h (EL1 using SP_EL1) with debug exceptions, SErrors, IRQs and FIQs all masked
+    /// (the D, A, I and F bits), as the kernel's booting.rst requires.
+    pub fn setup_boot_regs(&self, entry: u64, dtb_addr: u64) -> Result<()> {
+        let pstate = PSR_D_BIT | PSR_A_BIT | PSR_I_BIT | PSR_F_BIT | PSR_MODE_EL1h;
+        self.set_one_reg(VcpuRegAArch64::Pstate, pstate.into())?;
+        self.set_one_reg(VcpuRegAArch64::Pc, entry)?;
+        self.set_one_reg(VcpuRegAArch64::X(0), dtb_addr)
+    }
 }
 
 #[cfg(feature = "gdb")]
@@ -316,6 +549,46 @@ impl KvmVcpu {
     }
 }
 
+/// Core registers of an AArch64 VCPU, as returned by `KvmVcpu::core_regs`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Aarch64CoreReg {
+    /// General Purpose Registers X0-X30
+    X(u8),
+    /// Stack Pointer
+    Sp,
+    /// Program Counter
+    Pc,
+    /// Processor State
+    Pstate,
+    /// Stack Pointer (EL1)
+    SpEl1,
+    /// Exception Link Register (EL1)
+    ElrEl1,
+}
+
+impl Aarch64CoreReg {
+    /// Returns an iterator over every core register, in register file order.
+    pub fn all() -> impl Iterator<Item = Self> {
+        (0..=30)
+            .map(Self::X)
+            .chain([Self::Sp, Self::Pc, Self::Pstate, Self::SpEl1, Self::ElrEl1])
+    }
+}
+
+impl From<Aarch64CoreReg> for KvmVcpuRegister {
+    fn from(reg: Aarch64CoreReg) -> Self {
+        match reg {
+            Aarch64CoreReg::X(n @ 0..=30) => Self::X(n),
+            Aarch64CoreReg::X(n) => unreachable!("invalid Aarch64CoreReg index: {n}"),
+            Aarch64CoreReg::Sp => Self::Sp,
+            Aarch64CoreReg::Pc => Self::Pc,
+            Aarch64CoreReg::Pstate => Self::Pstate,
+            Aarch64CoreReg::SpEl1 => Self::SpEl1,
+            Aarch64CoreReg::ElrEl1 => Self::ElrEl1,
+        }
+    }
+}
+
 #[allow(dead_code)]
 /// KVM registers as used by the `GET_ONE_REG`/`SET_ONE_REG` ioctl API
 ///
@@ -830,3 +1103,22 @@ pub(super) fn chip_to_kvm_chip(chip: IrqSourceChip) -> u32 {
         }
     }
 }
+
+// Number of SGIs and PPIs, which precede the SPIs in the interrupt count of a vGIC.
+const VGIC_PRIVATE_IRQS: u32 = 32;
+
+// Number of SPIs a vGIC has unless KVM_DEV_ARM_VGIC_GRP_NR_IRQS sets otherwise: the kernel's
+// VGIC_NR_IRQS_LEGACY less the private interrupts.
+const KVM_DEFAULT_VGIC_SPIS: u32 = 256 - VGIC_PRIVATE_IRQS;
+
+// Returns the routing table KVM installs for an in-kernel vGIC with `nr_spis` SPIs, routing each
+// GSI to the SPI of the same number.
+fn vgic_irq_routes(nr_spis: u32) -> Vec<IrqRoute> {
+    (0..nr_spis).map(IrqRoute::gic_irq_route).collect()
+}
+
+// Returns the routing table KVM installs for an in-kernel vGIC with the default number of SPIs.
+// `KvmVgicDevice::set_nr_irqs` replaces it with the table for the number of SPIs it sets.
+pub(super) fn default_irq_routes() -> Vec<IrqRoute> {
+    vgic_irq_routes(KVM_DEFAULT_VGIC_SPIS)
+}
@@ -16,22 +16,32 @@ use std::collections::BTreeMap;
 use std::collections::BinaryHeap;
 use std::convert::TryFrom;
 use std::ffi::CString;
+use std::io;
+use std::io::Read;
+use std::io::Write;
 use std::mem::size_of;
 use std::mem::ManuallyDrop;
 use std::os::raw::c_int;
+use std::os::raw::c_uint;
 use std::os::raw::c_ulong;
 use std::os::raw::c_void;
 use std::os::unix::prelude::OsStrExt;
 use std::path::Path;
 use std::path::PathBuf;
 use std::ptr::copy_nonoverlapping;
+use std::sync::atomic::AtomicBool;
 use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicU8;
 use std::sync::Arc;
+use std::sync::MutexGuard;
+use std::time::Duration;
 
 use base::block_signal;
 use base::errno_result;
 use base::error;
 use base::ioctl;
+use base::ioctl_ior_nr;
+use base::ioctl_iowr_nr;
 use base::ioctl_with_mut_ref;
 use base::ioctl_with_ref;
 use base::ioctl_with_val;
@@ -52,18 +62,27 @@ use base::RawDescriptor;
 use base::Result;
 use base::SafeDescriptor;
 use data_model::vec_with_array_field;
+use data_model::VolatileSlice;
 use kvm_sys::*;
 use libc::open64;
 use libc::sigset_t;
+use libc::E2BIG;
+use libc::EBADF;
 use libc::EBUSY;
+use libc::EEXIST;
 use libc::EFAULT;
+use libc::EINTR;
 use libc::EINVAL;
 use libc::EIO;
 use libc::ENOENT;
 use libc::ENOSPC;
+use libc::ENOTSUP;
 use libc::EOVERFLOW;
 use libc::O_CLOEXEC;
 use libc::O_RDWR;
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use serde::Serialize;
 use sync::Mutex;
 use vm_memory::GuestAddress;
 use vm_memory::GuestMemory;
@@ -72,6 +91,8 @@ pub use x86_64::*;
 
 use crate::ClockState;
 use crate::Config;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::CpuIdEntry;
 use crate::Datamatch;
 use crate::DeviceKind;
 use crate::HypervHypercall;
@@ -123,6 +144,121 @@ unsafe fn set_user_memory_region(
     }
 }
 
+// Wrapper around KVM_SET_DEVICE_ATTR ioctl, which sets the attribute `attr` in `group` of a device
+// created with KVM_CREATE_DEVICE.
+//
+// Safe when `addr` is 0 or points to a value of the type the kernel expects for the attribute.
+unsafe fn set_device_attr(
+    descriptor: &SafeDescriptor,
+    group: u32,
+    attr: u64,
+    addr: u64,
+) -> Result<()> {
+    let device_attr = kvm_device_attr {
+        flags: 0,
+        group,
+        attr,
+        addr,
+    };
+
+    let ret = ioctl_with_ref(descriptor, KVM_SET_DEVICE_ATTR(), &device_attr);
+    if ret == 0 {
+        Ok(())
+    } else {
+        errno_result()
+    }
+}
+
+// Number of times an ioctl interrupted by a signal is retried before its EINTR is returned, so that
+// a flood of signals cannot hang the caller.
+const MAX_EINTR_RETRIES: usize = 16;
+
+static EINTR_RETRIES: AtomicU64 = AtomicU64::new(0);
+
+/// Calls `ioctl`, which returns the result of a KVM ioctl, again for as long as it fails with
+/// `EINTR`, up to `MAX_EINTR_RETRIES` times. Returns the errno of the last call if it failed.
+///
+/// Only for ioctls whose interruption carries no meaning; `Vcpu::run` must not use this.
+fn ioctl_retry_eintr(mut ioctl: impl FnMut() -> c_int) -> Result<()> {
+    let mut retries = 0;
+    loop {
+        let ret = ioctl();
+        if ret == 0 {
+            return Ok(());
+        }
+        let err = Error::last();
+        if err.errno() != EINTR || retries == MAX_EINTR_RETRIES {
+            return Err(err);
+        }
+        retries += 1;
+        EINTR_RETRIES.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+/// Returns the number of times a KVM ioctl was retried in this process after being interrupted by
+/// a signal.
+pub fn eintr_retries() -> u64 {
+    EINTR_RETRIES.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+// Returns whether a GSI can't be routed to both `a` and `b`. KVM only allows a GSI to have several
+// routes if they are all to pins of distinct irqchips.
+fn routes_conflict(a: &IrqSource, b: &IrqSource) -> bool {
+    match (a, b) {
+        (IrqSource::Irqchip { chip: a, .. }, IrqSource::Irqchip { chip: b, .. }) => a == b,
+        _ => true,
+    }
+}
+
+// Not yet exported by the libc crate; available since Linux 5.14.
+const MADV_POPULATE_READ: c_int = 22;
+const MADV_POPULATE_WRITE: c_int = 23;
+
+// The userfaultfd registration ABI from <linux/userfaultfd.h>, not yet exported by the libc crate.
+const UFFDIO: c_uint = 0xAA;
+const UFFDIO_REGISTER_MODE_MISSING: u64 = 1 << 0;
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Default)]
+struct uffdio_range {
+    start: u64,
+    len: u64,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Default)]
+struct uffdio_register {
+    range: uffdio_range,
+    mode: u64,
+    ioctls: u64,
+}
+
+ioctl_iowr_nr!(UFFDIO_REGISTER, UFFDIO, 0x00, uffdio_register);
+ioctl_ior_nr!(UFFDIO_UNREGISTER, UFFDIO, 0x01, uffdio_range);
+
+static HOST_PAGE_SIZE: OnceCell<usize> = OnceCell::new();
+
+// Returns the host page size, which is only queried from the system once.
+fn host_page_size() -> usize {
+    *HOST_PAGE_SIZE.get_or_init(pagesize)
+}
+
+// Returns the userfaultfd range covering the host mapping of the region in `slot`, rounded up to
+// whole pages as the kernel requires.
+fn userfaultfd_range(
+    regions: &BTreeMap<MemSlot, KvmMemRegion>,
+    slot: MemSlot,
+) -> Result<uffdio_range> {
+    let region = regions.get(&slot).ok_or_else(|| Error::new(ENOENT))?;
+    let page_size = host_page_size();
+    Ok(uffdio_range {
+        start: region.mem.as_ptr() as u64,
+        len: ((region.mem.size() + page_size - 1) / page_size * page_size) as u64,
+    })
+}
+
 /// Helper function to determine the size in bytes of a dirty log bitmap for the given memory region
 /// size.
 ///
@@ -130,12 +266,81 @@ unsafe fn set_user_memory_region(
 ///
 /// * `size` - Number of bytes in the memory region being queried.
 pub fn dirty_log_bitmap_size(size: usize) -> usize {
-    let page_size = pagesize();
+    let page_size = host_page_size();
     (((size + page_size - 1) / page_size) + 7) / 8
 }
 
+// Largest number of bytes stored in a single memory snapshot record, bounding the buffer needed to
+// write one.
+const MEMORY_SNAPSHOT_MAX_RECORD_LEN: usize = 1 << 20;
+
+fn snapshot_io_error(e: io::Error) -> Error {
+    Error::new(e.raw_os_error().unwrap_or(EIO))
+}
+
+// Writes one memory snapshot record holding `data`, or nothing if `data` is empty.
+fn write_snapshot_record<W: Write>(
+    out: &mut W,
+    slot: u32,
+    offset: usize,
+    data: &[u8],
+) -> Result<()> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    out.write_all(&slot.to_le_bytes())
+        .and_then(|_| out.write_all(&(offset as u64).to_le_bytes()))
+        .and_then(|_| out.write_all(&(data.len() as u64).to_le_bytes()))
+        .and_then(|_| out.write_all(data))
+        .map_err(snapshot_io_error)
+}
+
+/// Restores a snapshot written by `KvmVm::write_memory_snapshot` into `guest_mem`, which must have
+/// the same regions as the snapshotted VM's guest memory.
+///
+/// Pages not present in the snapshot are left untouched, so `guest_mem` should be freshly created
+/// for them to read as zero. Returns `EINVAL` if a record does not fit in `guest_mem`.
+pub fn read_memory_snapshot<R: Read>(guest_mem: &GuestMemory, input: &mut R) -> Result<()> {
+    let regions = guest_mem.guest_memory_regions();
+    let mut header = [0u8; 20];
+    let mut data = Vec::new();
+    loop {
+        // A snapshot ends cleanly only between records.
+        match input.read(&mut header[..1]) {
+            Ok(0) => return Ok(()),
+            Ok(_) => {}
+            Err(e) => return Err(snapshot_io_error(e)),
+        }
+        input
+            .read_exact(&mut header[1..])
+            .map_err(snapshot_io_error)?;
+        let slot = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let offset = u64::from_le_bytes(header[4..12].try_into().unwrap());
+        let len = u64::from_le_bytes(header[12..20].try_into().unwrap());
+
+        let (region_addr, region_size) = *regions
+            .get(slot as usize)
+            .ok_or_else(|| Error::new(EINVAL))?;
+        if len > MEMORY_SNAPSHOT_MAX_RECORD_LEN as u64
+            || offset
+                .checked_add(len)
+                .map_or(true, |end| end > region_size as u64)
+        {
+            return Err(Error::new(EINVAL));
+        }
+        data.resize(len as usize, 0);
+        input.read_exact(&mut data).map_err(snapshot_io_error)?;
+        guest_mem
+            .write_all_at_addr(&data, region_addr.unchecked_add(offset))
+            .map_err(|_| Error::new(EFAULT))?;
+    }
+}
+
 pub struct Kvm {
     kvm: SafeDescriptor,
+    /// KVM's supported CPUID, fetched on first use by `get_cpuid_entry`.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    supported_cpuid: Arc<OnceCell<Vec<CpuIdEntry>>>,
 }
 
 pub type KvmCap = kvm::Cap;
@@ -151,6 +356,8 @@ impl Kvm {
         // Safe because we verify that ret is valid and we own the fd.
         Ok(Kvm {
             kvm: unsafe { SafeDescriptor::from_raw_descriptor(ret) },
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            supported_cpuid: Arc::new(OnceCell::new()),
         })
     }
 
@@ -169,6 +376,69 @@ impl Kvm {
             errno_result()
         }
     }
+
+    /// Returns the maximum number of vcpus a VM may have.
+    ///
+    /// Falls back to KVM_CAP_NR_VCPUS, the recommended limit, on kernels that don't report
+    /// KVM_CAP_MAX_VCPUS, and to 4 if neither is reported, as documented for KVM_CREATE_VCPU.
+    pub fn max_vcpus(&self) -> usize {
+        match self.check_extension_int(KVM_CAP_MAX_VCPUS) {
+            0 => match self.check_extension_int(KVM_CAP_NR_VCPUS) {
+                0 => 4,
+                nr_vcpus => nr_vcpus,
+            },
+            max_vcpus => max_vcpus,
+        }
+    }
+
+    /// Returns the exclusive upper bound on the vcpu ids accepted by KVM_CREATE_VCPU.
+    ///
+    /// Without KVM_CAP_MAX_VCPU_ID, this is the same as `max_vcpus`.
+    pub fn max_vcpu_id(&self) -> usize {
+        match self.check_extension_int(KVM_CAP_MAX_VCPU_ID) {
+            0 => self.max_vcpus(),
+            max_vcpu_id => max_vcpu_id,
+        }
+    }
+
+    /// Reports which of a list of capabilities commonly needed by crosvm the host's KVM supports,
+    /// keyed by the capability's name without the `KVM_CAP_` prefix. Meant for logging when
+    /// diagnosing a VM that fails to start.
+    ///
+    /// Capabilities are probed on the KVM device rather than a VM, so ones KVM only reports per VM
+    /// show up as unsupported.
+    pub fn capability_report(&self) -> BTreeMap<String, bool> {
+        let caps = [
+            ("USER_MEMORY", KvmCap::UserMemory),
+            ("READONLY_MEM", KvmCap::ReadonlyMem),
+            ("IRQCHIP", KvmCap::Irqchip),
+            ("IRQ_ROUTING", KvmCap::IrqRouting),
+            ("IRQFD", KvmCap::Irqfd),
+            ("IRQFD_RESAMPLE", KvmCap::IrqfdResample),
+            ("IOEVENTFD", KvmCap::Ioeventfd),
+            ("IOEVENTFD_NO_LENGTH", KvmCap::IoeventfdNoLength),
+            ("SIGNAL_MSI", KvmCap::SignalMsi),
+            ("IMMEDIATE_EXIT", KvmCap::ImmediateExit),
+            ("MANUAL_DIRTY_LOG_PROTECT2", KvmCap::ManualDirtyLogProtect2),
+            ("HALT_POLL", KvmCap::HaltPoll),
+            ("NR_MEMSLOTS", KvmCap::NrMemslots),
+        ];
+        caps.iter()
+            .map(|&(name, cap)| (name.to_string(), self.check_extension_int(cap as u32) > 0))
+            .collect()
+    }
+
+    // Returns the value KVM_CHECK_EXTENSION reports for `cap`, or 0 if it isn't supported.
+    fn check_extension_int(&self, cap: u32) -> usize {
+        // Safe because we know that our file is a KVM fd, and if the cap is invalid KVM assumes
+        // it's an unavailable extension and returns 0.
+        let ret = unsafe { ioctl_with_val(self, KVM_CHECK_EXTENSION(), cap as c_ulong) };
+        if ret > 0 {
+            ret as usize
+        } else {
+            0
+        }
+    }
 }
 
 impl AsRawDescriptor for Kvm {
@@ -181,6 +451,8 @@ impl Hypervisor for Kvm {
     fn try_clone(&self) -> Result<Self> {
         Ok(Kvm {
             kvm: self.kvm.try_clone()?,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            supported_cpuid: self.supported_cpuid.clone(),
         })
     }
 
@@ -196,14 +468,170 @@ impl Hypervisor for Kvm {
     }
 }
 
+/// A memory region added to a `KvmVm` with `add_memory_region`.
+struct KvmMemRegion {
+    guest_addr: GuestAddress,
+    mem: Box<dyn MappedRegion>,
+    read_only: bool,
+    log_dirty_pages: bool,
+}
+
+/// Description of one KVM memory slot, as returned by `KvmVm::memory_layout`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemRegionDescriptor {
+    pub slot: MemSlot,
+    pub guest_addr: GuestAddress,
+    /// Size of the region in bytes.
+    pub size: u64,
+    pub read_only: bool,
+    pub log_dirty_pages: bool,
+}
+
+/// The pages of a memory slot that were dirty when its log was fetched, returned by
+/// `KvmVm::iter_dirty_pages`.
+///
+/// Holds the VM's memory region lock, so the slot stays mapped, and regions can't be added to or
+/// removed from the VM on any clone, until this is dropped.
+pub struct DirtyPages<'a> {
+    _regions: MutexGuard<'a, BTreeMap<MemSlot, KvmMemRegion>>,
+    guest_addr: GuestAddress,
+    host_addr: *mut u8,
+    size: usize,
+    dirty_log: Vec<u8>,
+}
+
+impl<'a> DirtyPages<'a> {
+    /// Returns the guest address and contents of each dirty page, in ascending address order.
+    ///
+    /// Vcpus may still be writing the pages, so their contents are only accessible through
+    /// volatile copies.
+    pub fn iter(&self) -> impl Iterator<Item = (GuestAddress, VolatileSlice)> + '_ {
+        let page_size = host_page_size();
+        let num_pages = (self.size + page_size - 1) / page_size;
+        (0..num_pages)
+            .filter(move |page| self.dirty_log[page / 8] & (1 << (page % 8)) != 0)
+            .map(move |page| {
+                let offset = page * page_size;
+                let len = min(page_size, self.size - offset);
+                // Safe because `offset + len` is within the mapping, which stays mapped while
+                // `_regions` holds the lock, and `VolatileSlice` tolerates concurrent writes by
+                // vcpus.
+                let data =
+                    unsafe { VolatileSlice::from_raw_parts(self.host_addr.add(offset), len) };
+                (self.guest_addr.unchecked_add(offset as u64), data)
+            })
+    }
+}
+
+/// Parameters for registering an irqfd with `KvmVm::register_irqfd_config`.
+pub struct IrqfdConfig<'a> {
+    /// The GSI that is triggered when `evt` is signalled.
+    pub gsi: u32,
+    /// The event that triggers the interrupt.
+    pub evt: &'a Event,
+    /// When set, the irqfd is level-triggered: the interrupt stays asserted until the guest
+    /// acknowledges it, at which point this event is signalled so the source can be resampled.
+    /// When `None`, the irqfd is edge-triggered.
+    pub resample: Option<&'a Event>,
+}
+
+/// A device created with `KvmVm::create_device_typed`. Each variant only exposes the attributes
+/// that are valid for its kind of device.
+pub enum TypedKvmDevice {
+    Vfio(KvmVfioDevice),
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    ArmVgic(KvmVgicDevice),
+}
+
+impl TypedKvmDevice {
+    /// Returns the kind of device this was created as.
+    pub fn kind(&self) -> DeviceKind {
+        match self {
+            TypedKvmDevice::Vfio(_) => DeviceKind::Vfio,
+            #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+            TypedKvmDevice::ArmVgic(vgic) => vgic.kind(),
+        }
+    }
+}
+
+impl AsRawDescriptor for TypedKvmDevice {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        match self {
+            TypedKvmDevice::Vfio(vfio) => vfio.as_raw_descriptor(),
+            #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+            TypedKvmDevice::ArmVgic(vgic) => vgic.as_raw_descriptor(),
+        }
+    }
+}
+
+/// The KVM VFIO pseudo device, which tells KVM which VFIO groups are in use by the VM.
+pub struct KvmVfioDevice {
+    descriptor: SafeDescriptor,
+}
+
+impl KvmVfioDevice {
+    /// Adds the VFIO group `group` to the device.
+    ///
+    /// Returns `EBADF` without calling into KVM if `group` is not an open descriptor.
+    pub fn add_group(&self, group: &dyn AsRawDescriptor) -> Result<()> {
+        self.set_group_attr(KVM_DEV_VFIO_GROUP_ADD, group)
+    }
+
+    /// Removes the VFIO group `group` from the device.
+    pub fn delete_group(&self, group: &dyn AsRawDescriptor) -> Result<()> {
+        self.set_group_attr(KVM_DEV_VFIO_GROUP_DEL, group)
+    }
+
+    fn set_group_attr(&self, attr: u32, group: &dyn AsRawDescriptor) -> Result<()> {
+        let group_descriptor: RawDescriptor = group.as_raw_descriptor();
+        // Safe because F_GETFD doesn't touch memory and we check the return value.
+        if unsafe { libc::fcntl(group_descriptor, libc::F_GETFD) } < 0 {
+            return Err(Error::new(EBADF));
+        }
+        // Safe because the kernel reads a descriptor for the VFIO group attributes and
+        // `group_descriptor` outlives the call.
+        unsafe {
+            set_device_attr(
+                &self.descriptor,
+                KVM_DEV_VFIO_GROUP,
+                attr as u64,
+                &group_descriptor as *const RawDescriptor as u64,
+            )
+        }
+    }
+
+    /// Returns the underlying device descriptor.
+    pub fn into_descriptor(self) -> SafeDescriptor {
+        self.descriptor
+    }
+}
+
+impl AsRawDescriptor for KvmVfioDevice {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.descriptor.as_raw_descriptor()
+    }
+}
+
 /// A wrapper around creating and using a KVM VM.
 pub struct KvmVm {
     kvm: Kvm,
     vm: SafeDescriptor,
     guest_mem: GuestMemory,
-    mem_regions: Arc<Mutex<BTreeMap<MemSlot, Box<dyn MappedRegion>>>>,
+    mem_regions: Arc<Mutex<BTreeMap<MemSlot, KvmMemRegion>>>,
     /// A min heap of MemSlot numbers that were used and then removed and can now be re-used
     mem_slot_gaps: Arc<Mutex<BinaryHeap<Reverse<MemSlot>>>>,
+    /// The GSI routing table most recently accepted by `set_gsi_routing`.
+    routes: Arc<Mutex<Vec<IrqRoute>>>,
+    /// Whether KVM_CAP_READONLY_MEM is supported, probed once at creation.
+    readonly_mem_supported: bool,
+    /// Whether an in-kernel irqchip that services resamples itself has been created.
+    kernel_irqchip: Arc<AtomicBool>,
+    /// Whether KVM_CAP_SPLIT_IRQCHIP has been enabled, leaving the IOAPIC to user space.
+    split_irqchip: Arc<AtomicBool>,
+    /// Events signalled by `notify_irq_ack` when a user-space irqchip sees an EOI, keyed by GSI.
+    ack_notifiers: Arc<Mutex<BTreeMap<u32, Event>>>,
+    /// Registered irqfds keyed by GSI and event descriptor, and whether each has a resample event.
+    irqfds: Arc<Mutex<BTreeMap<(u32, RawDescriptor), bool>>>,
 }
 
 impl KvmVm {
@@ -238,18 +666,34 @@ impl KvmVm {
             }
         })?;
 
-        let vm = KvmVm {
+        let mut vm = KvmVm {
             kvm: kvm.try_clone()?,
             vm: vm_descriptor,
             guest_mem,
             mem_regions: Arc::new(Mutex::new(BTreeMap::new())),
             mem_slot_gaps: Arc::new(Mutex::new(BinaryHeap::new())),
+            routes: Arc::new(Mutex::new(Vec::new())),
+            readonly_mem_supported: false,
+            kernel_irqchip: Arc::new(AtomicBool::new(false)),
+            split_irqchip: Arc::new(AtomicBool::new(false)),
+            ack_notifiers: Arc::new(Mutex::new(BTreeMap::new())),
+            irqfds: Arc::new(Mutex::new(BTreeMap::new())),
         };
+        vm.readonly_mem_supported = vm.check_raw_capability(KvmCap::ReadonlyMem);
         vm.init_arch(&cfg)?;
         Ok(vm)
     }
 
     pub fn create_kvm_vcpu(&self, id: usize) -> Result<KvmVcpu> {
+        let max_vcpu_id = self.kvm.max_vcpu_id();
+        if id >= max_vcpu_id {
+            error!(
+                "vcpu id {} exceeds the maximum of {} supported by KVM",
+                id,
+                max_vcpu_id - 1
+            );
+            return Err(Error::new(EINVAL));
+        }
         let run_mmap_size = self.kvm.get_vcpu_mmap_size()?;
 
         // Safe because we know that our file is a VM fd and we verify the return result.
@@ -273,16 +717,22 @@ impl KvmVm {
             id,
             run_mmap,
             vcpu_run_handle_fingerprint: Default::default(),
+            pvclock_suspend: self.check_capability(VmCap::PvClockSuspend),
         })
     }
 
     /// Creates an in kernel interrupt controller.
     ///
-    /// See the documentation on the KVM_CREATE_IRQCHIP ioctl.
+    /// See the documentation on the KVM_CREATE_IRQCHIP ioctl. The cached GSI routing table is
+    /// seeded with the default table KVM installs along with the irqchip.
     pub fn create_irq_chip(&self) -> Result<()> {
+        let mut cached_routes = self.routes.lock();
         // Safe because we know that our file is a VM fd and we verify the return result.
         let ret = unsafe { ioctl(self, KVM_CREATE_IRQCHIP()) };
         if ret == 0 {
+            self.kernel_irqchip
+                .store(true, std::sync::atomic::Ordering::Release);
+            *cached_routes = default_irq_routes();
             Ok(())
         } else {
             errno_result()
@@ -297,12 +747,7 @@ impl KvmVm {
 
         // Safe because we know that our file is a VM fd, we know the kernel will only read the
         // correct amount of memory from our pointer, and we verify the return result.
-        let ret = unsafe { ioctl_with_ref(self, KVM_IRQ_LINE(), &irq_level) };
-        if ret == 0 {
-            Ok(())
-        } else {
-            errno_result()
-        }
+        ioctl_retry_eintr(|| unsafe { ioctl_with_ref(self, KVM_IRQ_LINE(), &irq_level) })
     }
 
     /// Registers an event that will, when signalled, trigger the `gsi` irq, and `resample_evt`
@@ -313,42 +758,414 @@ impl KvmVm {
         evt: &Event,
         resample_evt: Option<&Event>,
     ) -> Result<()> {
-        let mut irqfd = kvm_irqfd {
-            fd: evt.as_raw_descriptor() as u32,
+        self.register_irqfd_config(&IrqfdConfig {
             gsi,
+            evt,
+            resample: resample_evt,
+        })
+    }
+
+    /// Registers `evt` to be signalled when the guest acknowledges the interrupt on `gsi`.
+    ///
+    /// KVM only reports EOIs to user space when the irqchip, or its IOAPIC in split-irqchip mode,
+    /// is emulated there, so there is no kernel mechanism to wire this to. Instead the user-space
+    /// irqchip calls `notify_irq_ack` when it handles an EOI, e.g. on `VcpuExit::IoapicEoi`.
+    ///
+    /// Returns `ENOTSUP` if an in-kernel irqchip has been created, since it services resamples
+    /// itself; use `register_irqfd_config` with a resample event instead. Returns `EEXIST` if a
+    /// notifier is already registered for `gsi`.
+    pub fn register_irq_ack_notifier(&self, gsi: u32, evt: &Event) -> Result<()> {
+        if self
+            .kernel_irqchip
+            .load(std::sync::atomic::Ordering::Acquire)
+        {
+            return Err(Error::new(ENOTSUP));
+        }
+        let mut notifiers = self.ack_notifiers.lock();
+        if notifiers.contains_key(&gsi) {
+            return Err(Error::new(EEXIST));
+        }
+        notifiers.insert(gsi, evt.try_clone()?);
+        Ok(())
+    }
+
+    /// Removes the notifier registered for `gsi` with `register_irq_ack_notifier`.
+    pub fn unregister_irq_ack_notifier(&self, gsi: u32) -> Result<()> {
+        match self.ack_notifiers.lock().remove(&gsi) {
+            Some(_) => Ok(()),
+            None => Err(Error::new(ENOENT)),
+        }
+    }
+
+    /// Signals the notifier registered for `gsi`, if any. Called by the user-space irqchip when
+    /// the guest acknowledges the interrupt on `gsi`.
+    pub fn notify_irq_ack(&self, gsi: u32) -> Result<()> {
+        match self.ack_notifiers.lock().get(&gsi) {
+            Some(evt) => evt.signal(),
+            None => Ok(()),
+        }
+    }
+
+    /// Registers an irqfd described by `config`.
+    ///
+    /// See `IrqfdConfig` for the meaning of each field.
+    pub fn register_irqfd_config(&self, config: &IrqfdConfig) -> Result<()> {
+        let mut irqfd = kvm_irqfd {
+            fd: config.evt.as_raw_descriptor() as u32,
+            gsi: config.gsi,
             ..Default::default()
         };
 
-        if let Some(r_evt) = resample_evt {
+        if let Some(r_evt) = config.resample {
             irqfd.flags = KVM_IRQFD_FLAG_RESAMPLE;
             irqfd.resamplefd = r_evt.as_raw_descriptor() as u32;
         }
 
         // Safe because we know that our file is a VM fd, we know the kernel will only read the
         // correct amount of memory from our pointer, and we verify the return result.
-        let ret = unsafe { ioctl_with_ref(self, KVM_IRQFD(), &irqfd) };
-        if ret == 0 {
-            Ok(())
-        } else {
-            errno_result()
-        }
+        ioctl_retry_eintr(|| unsafe { ioctl_with_ref(self, KVM_IRQFD(), &irqfd) })?;
+        self.irqfds.lock().insert(
+            (config.gsi, config.evt.as_raw_descriptor()),
+            config.resample.is_some(),
+        );
+        Ok(())
     }
 
     /// Unregisters an event that was previously registered with
     /// `register_irqfd`.
     ///
     /// The `evt` and `gsi` pair must be the same as the ones passed into
-    /// `register_irqfd`.
+    /// `register_irqfd`. If a resample event was registered with them, the association with it is
+    /// torn down as well, so `gsi` can be registered again immediately.
     pub fn unregister_irqfd(&self, gsi: u32, evt: &Event) -> Result<()> {
+        let mut irqfds = self.irqfds.lock();
+        let resample = irqfds
+            .get(&(gsi, evt.as_raw_descriptor()))
+            .copied()
+            .unwrap_or(false);
+        // KVM expects the resample flag and fd to be zeroed on deassign even when the irqfd was
+        // assigned with them. It releases the irqfd's resampler along with the irqfd and waits for
+        // that to complete before returning.
         let irqfd = kvm_irqfd {
             fd: evt.as_raw_descriptor() as u32,
             gsi,
             flags: KVM_IRQFD_FLAG_DEASSIGN,
+            resamplefd: 0,
             ..Default::default()
         };
         // Safe because we know that our file is a VM fd, we know the kernel will only read the
         // correct amount of memory from our pointer, and we verify the return result.
-        let ret = unsafe { ioctl_with_ref(self, KVM_IRQFD(), &irqfd) };
+        let res = ioctl_retry_eintr(|| unsafe { ioctl_with_ref(self, KVM_IRQFD(), &irqfd) });
+        if res.is_ok() {
+            irqfds.remove(&(gsi, evt.as_raw_descriptor()));
+        } else if resample {
+            error!(
+                "failed to deassign resampling irqfd for gsi {}; its EOI path may be stale",
+                gsi
+            );
+        }
+        res
+    }
+
+    /// Sets the GSI routing table, replacing any table set with previous calls to
+    /// `set_gsi_routing`. An empty `routes` clears the table.
+    pub fn set_gsi_routing(&self, routes: &[IrqRoute]) -> Result<()> {
+        // Hold the cache lock across the ioctl so the cached table always matches the kernel's.
+        let mut cached_routes = self.routes.lock();
+        self.set_gsi_routing_raw(routes)?;
+        *cached_routes = routes.to_vec();
+        Ok(())
+    }
+
+    /// Adds `route` to the current GSI routing table and reprograms the table.
+    ///
+    /// Existing routes for the same GSI are kept if they and `route` are to pins of distinct
+    /// irqchips, like the PIC and IOAPIC routes of GSIs 0-15 on x86, and replaced otherwise.
+    pub fn add_irq_route(&self, route: IrqRoute) -> Result<()> {
+        let mut cached_routes = self.routes.lock();
+        let mut routes = cached_routes.clone();
+        routes.retain(|r| r.gsi != route.gsi || !routes_conflict(&r.source, &route.source));
+        routes.push(route);
+        self.set_gsi_routing_raw(&routes)?;
+        *cached_routes = routes;
+        Ok(())
+    }
+
+    /// Removes the routes for `gsi` from the current GSI routing table and reprograms the table.
+    ///
+    /// Returns `ENOENT` if there is no route for `gsi`.
+    pub fn remove_irq_route(&self, gsi: u32) -> Result<()> {
+        let mut cached_routes = self.routes.lock();
+        let mut routes = cached_routes.clone();
+        routes.retain(|r| r.gsi != gsi);
+        if routes.len() == cached_routes.len() {
+            return Err(Error::new(ENOENT));
+        }
+        self.set_gsi_routing_raw(&routes)?;
+        *cached_routes = routes;
+        Ok(())
+    }
+
+    /// Returns the route for `gsi` from the current GSI routing table, if any.
+    pub fn get_irq_route(&self, gsi: u32) -> Option<IrqRoute> {
+        self.routes.lock().iter().find(|r| r.gsi == gsi).copied()
+    }
+
+    /// Checks that KVM will accept `routes` as a GSI routing table.
+    ///
+    /// Returns `E2BIG` if the table has more entries, or uses a higher GSI, than KVM supports, and
+    /// `EINVAL` if a GSI has conflicting routes. KVM only allows a GSI to have several routes if
+    /// they are all to pins of distinct irqchips. The offending GSI is logged.
+    pub fn validate_routes(&self, routes: &[IrqRoute]) -> Result<()> {
+        let max_routes = self.kvm.check_extension_int(KVM_CAP_IRQ_ROUTING);
+        if routes.len() > max_routes {
+            error!(
+                "GSI routing table has {} routes but KVM supports at most {}",
+                routes.len(),
+                max_routes
+            );
+            return Err(Error::new(E2BIG));
+        }
+
+        let mut gsi_sources: BTreeMap<u32, Vec<&IrqSource>> = BTreeMap::new();
+        for route in routes {
+            if route.gsi as usize >= max_routes {
+                error!(
+                    "GSI {} is beyond the {} GSIs supported by KVM",
+                    route.gsi, max_routes
+                );
+                return Err(Error::new(E2BIG));
+            }
+            let sources = gsi_sources.entry(route.gsi).or_default();
+            let conflict = sources
+                .iter()
+                .find(|&&other| routes_conflict(other, &route.source));
+            if let Some(other) = conflict {
+                error!(
+                    "GSI {} has conflicting routes {:?} and {:?}",
+                    route.gsi, other, route.source
+                );
+                return Err(Error::new(EINVAL));
+            }
+            sources.push(&route.source);
+        }
+        Ok(())
+    }
+
+    /// Returns the page size of the host, which KVM tracks guest memory in units of, e.g. for the
+    /// dirty log.
+    pub fn host_page_size(&self) -> usize {
+        host_page_size()
+    }
+
+    /// Returns a copy of the current GSI routing table.
+    pub fn current_routes(&self) -> Vec<IrqRoute> {
+        self.routes.lock().clone()
+    }
+
+    /// Adds `mem` at `guest_addr` like `Vm::add_memory_region` with dirty page logging enabled
+    /// from the start, and returns its slot along with a zeroed buffer sized for
+    /// `Vm::get_dirty_log` of that slot.
+    pub fn add_memory_region_tracked(
+        &mut self,
+        guest_addr: GuestAddress,
+        mem: Box<dyn MappedRegion>,
+        read_only: bool,
+    ) -> Result<(MemSlot, Vec<u8>)> {
+        let dirty_log = vec![0u8; dirty_log_bitmap_size(mem.size())];
+        let slot = self.add_memory_region(guest_addr, mem, read_only, true)?;
+        Ok((slot, dirty_log))
+    }
+
+    /// Adds a region of private guest memory at `guest_addr`, backed by the guest_memfd `gmem`
+    /// from `gmem_offset` on, with `mem` as its shared counterpart. Returns the new slot.
+    ///
+    /// Meant for confidential computing experiments: the guest's accesses to pages it marks
+    /// private are served from `gmem`, which the host cannot map. Private regions cannot log dirty
+    /// pages. Returns `ENOTSUP` if KVM_CAP_USER_MEMORY2 or KVM_CAP_GUEST_MEMFD is not available
+    /// for this VM.
+    pub fn add_private_memory_region(
+        &mut self,
+        guest_addr: GuestAddress,
+        mem: Box<dyn MappedRegion>,
+        gmem: &dyn AsRawDescriptor,
+        gmem_offset: u64,
+    ) -> Result<MemSlot> {
+        if !self.check_raw_capability(KvmCap::UserMemory2)
+            || !self.check_raw_capability(KvmCap::GuestMemfd)
+        {
+            return Err(Error::new(ENOTSUP));
+        }
+        let pgsz = host_page_size() as u64;
+        // Safe to round up for the same reason as in `set_memory_region_slot`.
+        let size = (mem.size() as u64 + pgsz - 1) / pgsz * pgsz;
+        let end_addr = guest_addr
+            .checked_add(size)
+            .ok_or_else(|| Error::new(EOVERFLOW))?;
+        if self.guest_mem.range_overlap(guest_addr, end_addr) {
+            return Err(Error::new(ENOSPC));
+        }
+
+        let mut regions = self.mem_regions.lock();
+        let mut gaps = self.mem_slot_gaps.lock();
+        let slot = match gaps.pop() {
+            Some(gap) => gap.0,
+            None => (regions.len() + self.guest_mem.num_regions() as usize) as MemSlot,
+        };
+        let region = kvm_userspace_memory_region2 {
+            slot,
+            flags: KVM_MEM_GUEST_MEMFD,
+            guest_phys_addr: guest_addr.offset(),
+            memory_size: size,
+            userspace_addr: mem.as_ptr() as u64,
+            guest_memfd_offset: gmem_offset,
+            guest_memfd: gmem.as_raw_descriptor() as u32,
+            ..Default::default()
+        };
+        // Safe because the guest range does not overlap the static guest memory, KVM checks it
+        // against the other slots, and `mem` is kept mapped until the slot is removed. KVM takes
+        // its own reference to `gmem`.
+        let ret = unsafe { ioctl_with_ref(&self.vm, KVM_SET_USER_MEMORY_REGION2(), &region) };
+        if ret != 0 {
+            gaps.push(Reverse(slot));
+            return errno_result();
+        }
+        regions.insert(
+            slot,
+            KvmMemRegion {
+                guest_addr,
+                mem,
+                read_only: false,
+                log_dirty_pages: false,
+            },
+        );
+        Ok(slot)
+    }
+
+    /// Fetches the dirty log for `slot` and returns the pages written since the log was last
+    /// fetched.
+    ///
+    /// Fetching the log also clears it, so pages dirtied after this call are reported by the next
+    /// one; re-fetching until the set of dirty pages converges is left to the caller. Memory
+    /// regions can't be added or removed while the returned `DirtyPages` is alive.
+    pub fn iter_dirty_pages(&self, slot: MemSlot) -> Result<DirtyPages> {
+        let regions = self.mem_regions.lock();
+        let region = regions.get(&slot).ok_or_else(|| Error::new(ENOENT))?;
+        let guest_addr = region.guest_addr;
+        let host_addr = region.mem.as_ptr();
+        let size = region.mem.size();
+        let mut dirty_log = vec![0u8; dirty_log_bitmap_size(size)];
+        self.get_dirty_log_raw(slot, &mut dirty_log)?;
+
+        Ok(DirtyPages {
+            _regions: regions,
+            guest_addr,
+            host_addr,
+            size,
+            dirty_log,
+        })
+    }
+
+    /// Fetches the dirty log for `slot` and checks it against the contents of the slot, returning
+    /// the indices of the pages whose dirty bit disagrees with whether they differ from
+    /// `reference`, a copy of the whole slot taken when the log was last fetched.
+    ///
+    /// Meant for testing and debugging the dirty log. Pages rewritten with the same contents are
+    /// reported dirty by KVM but match `reference`, so they are returned too. Fetching the log
+    /// clears it, like `iter_dirty_pages`. Returns `EINVAL` if `reference` isn't the size of the
+    /// slot.
+    pub fn verify_dirty_log_matches(&self, slot: MemSlot, reference: &[u8]) -> Result<Vec<u64>> {
+        let regions = self.mem_regions.lock();
+        let region = regions.get(&slot).ok_or_else(|| Error::new(ENOENT))?;
+        let size = region.mem.size();
+        if reference.len() != size {
+            return Err(Error::new(EINVAL));
+        }
+        let mut dirty_log = vec![0u8; dirty_log_bitmap_size(size)];
+        self.get_dirty_log_raw(slot, &mut dirty_log)?;
+
+        // Safe because the mapping is `size` bytes long and stays mapped while the `mem_regions`
+        // lock is held.
+        let contents = unsafe { std::slice::from_raw_parts(region.mem.as_ptr(), size) };
+        let page_size = host_page_size();
+        Ok(contents
+            .chunks(page_size)
+            .zip(reference.chunks(page_size))
+            .enumerate()
+            .filter(|(page, (current, reference))| {
+                let dirty = dirty_log[page / 8] & (1 << (page % 8)) != 0;
+                dirty != (current != reference)
+            })
+            .map(|(page, _)| page as u64)
+            .collect())
+    }
+
+    /// Populates every page of the region in `slot` so the guest's first access to it does not
+    /// fault on the host.
+    ///
+    /// Uses MADV_POPULATE_WRITE, or MADV_POPULATE_READ for regions added read-only, falling back
+    /// to touching each page on kernels that lack them. Regions not added read-only are assumed to
+    /// be mapped writable on the host, as the guest needs to write them. Populating them for
+    /// writing breaks copy-on-write sharing of private file mappings, though the contents are
+    /// unchanged.
+    pub fn prefault_region(&mut self, slot: MemSlot) -> Result<()> {
+        let regions = self.mem_regions.lock();
+        let region = regions.get(&slot).ok_or_else(|| Error::new(ENOENT))?;
+        let host_addr = region.mem.as_ptr();
+        let size = region.mem.size();
+        let advice = if region.read_only {
+            MADV_POPULATE_READ
+        } else {
+            MADV_POPULATE_WRITE
+        };
+
+        // Safe because the range is exactly the mapping owned by the region, and populating pages
+        // does not change their contents.
+        let ret = unsafe { libc::madvise(host_addr as *mut c_void, size, advice) };
+        if ret == 0 {
+            return Ok(());
+        }
+        let err = Error::last();
+        if err.errno() != EINVAL {
+            return Err(err);
+        }
+
+        for offset in (0..size).step_by(host_page_size()) {
+            // Safe because `offset` is within the mapping and `AtomicU8` has the same layout as
+            // `u8`. Loading forces a read fault, and adding zero atomically forces a write fault
+            // without racing with vcpus that may be writing the same byte. Read-only regions are
+            // never written.
+            unsafe {
+                let byte = &*(host_addr.add(offset) as *const AtomicU8);
+                if region.read_only {
+                    byte.load(std::sync::atomic::Ordering::Relaxed);
+                } else {
+                    byte.fetch_add(0, std::sync::atomic::Ordering::Relaxed);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Registers the host mapping of the region in `slot` with the userfaultfd `uffd` for missing
+    /// page faults, so that the first access to each page not yet populated is reported to the
+    /// reader of `uffd` instead of being zero-filled, e.g. to fetch guest memory on demand during
+    /// post-copy migration.
+    ///
+    /// `uffd` must have completed the UFFDIO_API handshake. Accesses by KVM on behalf of the guest
+    /// are only reported if `uffd` was not created with UFFD_USER_MODE_ONLY. The registration ends
+    /// when the region is removed, or with `deregister_userfaultfd`.
+    pub fn register_userfaultfd(&self, slot: MemSlot, uffd: &dyn AsRawDescriptor) -> Result<()> {
+        let regions = self.mem_regions.lock();
+        let mut register = uffdio_register {
+            range: userfaultfd_range(&regions, slot)?,
+            mode: UFFDIO_REGISTER_MODE_MISSING,
+            ioctls: 0,
+        };
+        // Safe because the kernel only writes to the `ioctls` field of the struct, and the range
+        // covers exactly the pages of the mapping owned by the region, which stays mapped while
+        // the `mem_regions` lock is held.
+        let ret = unsafe { ioctl_with_mut_ref(uffd, UFFDIO_REGISTER(), &mut register) };
         if ret == 0 {
             Ok(())
         } else {
@@ -356,21 +1173,341 @@ impl KvmVm {
         }
     }
 
-    /// Sets the GSI routing table, replacing any table set with previous calls to
-    /// `set_gsi_routing`.
-    pub fn set_gsi_routing(&self, routes: &[IrqRoute]) -> Result<()> {
-        let mut irq_routing =
-            vec_with_array_field::<kvm_irq_routing, kvm_irq_routing_entry>(routes.len());
-        irq_routing[0].nr = routes.len() as u32;
+    /// Stops reporting faults on the region in `slot` to `uffd`, undoing `register_userfaultfd`.
+    pub fn deregister_userfaultfd(&self, slot: MemSlot, uffd: &dyn AsRawDescriptor) -> Result<()> {
+        let regions = self.mem_regions.lock();
+        let range = userfaultfd_range(&regions, slot)?;
+        // Safe because the kernel only reads the struct, and the range is the mapping owned by the
+        // region.
+        let ret = unsafe { ioctl_with_ref(uffd, UFFDIO_UNREGISTER(), &range) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            errno_result()
+        }
+    }
+
+    /// Returns true if every byte of `[addr, addr + len)` is backed by guest memory, either the
+    /// static regions of `guest_mem` or regions added with `add_memory_region`. The range may span
+    /// several contiguous regions. An empty range is never backed.
+    pub fn is_range_backed(&self, addr: GuestAddress, len: usize) -> bool {
+        let end = match addr.offset().checked_add(len as u64) {
+            Some(end) if len > 0 => end,
+            _ => return false,
+        };
+
+        let mut spans = Vec::new();
+        let _ = self
+            .guest_mem
+            .with_regions::<_, ()>(|_, guest_addr, size, _, _, _| {
+                spans.push((guest_addr.offset(), guest_addr.offset() + size as u64));
+                Ok(())
+            });
+        spans.extend(self.mem_regions.lock().values().map(|region| {
+            let start = region.guest_addr.offset();
+            (start, start + region.mem.size() as u64)
+        }));
+        spans.sort_unstable();
+
+        // Walk the spans in address order, extending the covered prefix of the range until it
+        // reaches `end` or a gap is found.
+        let mut covered = addr.offset();
+        for (start, span_end) in spans {
+            if span_end <= covered {
+                continue;
+            }
+            if start > covered {
+                break;
+            }
+            covered = span_end;
+            if covered >= end {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Writes the contents of the guest memory regions passed to `KvmVm::new` to `out` in a sparse
+    /// format that `read_memory_snapshot` restores.
+    ///
+    /// The snapshot is a sequence of records, each a little-endian u32 slot, u64 offset into the
+    /// slot and u64 length, followed by that many bytes. Pages that read as zero, including ones
+    /// the guest never touched, are skipped. Regions added with `add_memory_region` are owned by
+    /// the devices that added them and are not included. The guest must not be running.
+    pub fn write_memory_snapshot<W: Write>(&self, out: &mut W) -> Result<()> {
+        let page_size = host_page_size();
+        let mut page = vec![0u8; page_size];
+        let mut run = Vec::new();
+        for (slot, (region_addr, region_size)) in self
+            .guest_mem
+            .guest_memory_regions()
+            .into_iter()
+            .enumerate()
+        {
+            let mut run_offset = 0;
+            for offset in (0..region_size).step_by(page_size) {
+                let len = min(page_size, region_size - offset);
+                self.guest_mem
+                    .read_exact_at_addr(&mut page[..len], region_addr.unchecked_add(offset as u64))
+                    .map_err(|_| Error::new(EFAULT))?;
+                let zero = page[..len].iter().all(|&b| b == 0);
+                if zero || run.len() + len > MEMORY_SNAPSHOT_MAX_RECORD_LEN {
+                    write_snapshot_record(out, slot as u32, run_offset, &run)?;
+                    run.clear();
+                }
+                if !zero {
+                    if run.is_empty() {
+                        run_offset = offset;
+                    }
+                    run.extend_from_slice(&page[..len]);
+                }
+            }
+            write_snapshot_record(out, slot as u32, run_offset, &run)?;
+            run.clear();
+        }
+        out.flush().map_err(snapshot_io_error)
+    }
+
+    /// Returns a description of every memory slot currently registered with KVM, both the static
+    /// regions of the `GuestMemory` the VM was created with and those added with
+    /// `add_memory_region`, ordered by slot.
+    pub fn memory_layout(&self) -> Vec<MemRegionDescriptor> {
+        let mut layout: Vec<MemRegionDescriptor> = self
+            .guest_mem
+            .guest_memory_regions()
+            .into_iter()
+            .enumerate()
+            .map(|(index, (guest_addr, size))| MemRegionDescriptor {
+                slot: index as MemSlot,
+                guest_addr,
+                size: size as u64,
+                read_only: false,
+                log_dirty_pages: false,
+            })
+            .collect();
+        layout.extend(
+            self.mem_regions
+                .lock()
+                .iter()
+                .map(|(&slot, region)| MemRegionDescriptor {
+                    slot,
+                    guest_addr: region.guest_addr,
+                    size: region.mem.size() as u64,
+                    read_only: region.read_only,
+                    log_dirty_pages: region.log_dirty_pages,
+                }),
+        );
+        layout
+    }
+
+    /// Returns `(slot, base, size, read_only)` for each region added with `add_memory_region`,
+    /// sorted by guest base address. The static regions of the VM's `GuestMemory` are excluded.
+    pub fn iter_regions(&self) -> Vec<(MemSlot, GuestAddress, usize, bool)> {
+        let mut regions: Vec<(MemSlot, GuestAddress, usize, bool)> = self
+            .mem_regions
+            .lock()
+            .iter()
+            .map(|(&slot, region)| (slot, region.guest_addr, region.mem.size(), region.read_only))
+            .collect();
+        regions.sort_by_key(|&(_, base, _, _)| base);
+        regions
+    }
 
-        // Safe because we ensured there is enough space in irq_routing to hold the number of
-        // route entries.
-        let irq_routes = unsafe { irq_routing[0].entries.as_mut_slice(routes.len()) };
-        for (route, irq_route) in routes.iter().zip(irq_routes.iter_mut()) {
-            *irq_route = kvm_irq_routing_entry::from(route);
+    /// Recreates the regions of a layout captured with `memory_layout`, keeping their slot numbers.
+    ///
+    /// Static regions are part of the `GuestMemory` the VM was created with and are skipped. For
+    /// every other region, `backing` is called to provide the memory, which must be exactly
+    /// `size` bytes. Returns `EBUSY` if regions were already added to this VM. On error, the
+    /// regions restored so far are left in place.
+    pub fn restore_memory_layout<F>(
+        &mut self,
+        layout: &[MemRegionDescriptor],
+        mut backing: F,
+    ) -> Result<()>
+    where
+        F: FnMut(&MemRegionDescriptor) -> Result<Box<dyn MappedRegion>>,
+    {
+        let num_static = self.guest_mem.num_regions() as MemSlot;
+        let mut regions = self.mem_regions.lock();
+        let mut gaps = self.mem_slot_gaps.lock();
+        if !regions.is_empty() {
+            return Err(Error::new(EBUSY));
+        }
+
+        for desc in layout.iter().filter(|desc| desc.slot >= num_static) {
+            if regions.contains_key(&desc.slot) {
+                return Err(Error::new(EEXIST));
+            }
+            let mem = backing(desc)?;
+            if mem.size() as u64 != desc.size {
+                return Err(Error::new(EINVAL));
+            }
+            self.set_memory_region_slot(
+                desc.slot,
+                desc.guest_addr,
+                mem.as_ref(),
+                desc.read_only,
+                desc.log_dirty_pages,
+            )?;
+            regions.insert(
+                desc.slot,
+                KvmMemRegion {
+                    guest_addr: desc.guest_addr,
+                    mem,
+                    read_only: desc.read_only,
+                    log_dirty_pages: desc.log_dirty_pages,
+                },
+            );
+        }
+
+        // Slots are handed out lowest gap first, so every unused slot below the highest restored
+        // one must be a gap for new regions to land after the restored ones.
+        gaps.clear();
+        if let Some(&max_slot) = regions.keys().next_back() {
+            gaps.extend(
+                (num_static..max_slot)
+                    .filter(|slot| !regions.contains_key(slot))
+                    .map(Reverse),
+            );
         }
+        Ok(())
+    }
 
-        let ret = unsafe { ioctl_with_ref(self, KVM_SET_GSI_ROUTING(), &irq_routing[0]) };
+    /// Maps `size` bytes of `fd` starting at `fd_offset` and adds them to the guest at
+    /// `guest_addr` like `Vm::add_memory_region`, returning the new slot. The mapping is owned by
+    /// the VM and unmapped when the slot is removed.
+    ///
+    /// `fd_offset` must be page aligned. When `read_only` is set the host mapping is read-only as
+    /// well as the guest's.
+    pub fn add_fd_mapping(
+        &mut self,
+        guest_addr: GuestAddress,
+        fd: &dyn AsRawDescriptor,
+        fd_offset: u64,
+        size: usize,
+        read_only: bool,
+    ) -> Result<MemSlot> {
+        if fd_offset % host_page_size() as u64 != 0 {
+            return Err(Error::new(EINVAL));
+        }
+        let protection = if read_only {
+            Protection::read()
+        } else {
+            Protection::read_write()
+        };
+        let mem = MemoryMappingBuilder::new(size)
+            .from_descriptor(fd)
+            .offset(fd_offset)
+            .protection(protection)
+            .build()
+            .map_err(|e| match e {
+                MmapError::SystemCallFailed(e) => e,
+                _ => Error::new(EINVAL),
+            })?;
+        self.add_memory_region(guest_addr, Box::new(mem), read_only, false)
+    }
+
+    /// Enables dirty page logging on every memory slot, both the static guest memory slots
+    /// registered by `KvmVm::new` and the regions added with `add_memory_region`.
+    ///
+    /// Static slots are re-registered in place at the same slot indices, so they need not be torn
+    /// down first.
+    pub fn enable_dirty_log_all(&self) -> Result<()> {
+        self.set_dirty_log_all(true)
+    }
+
+    /// Disables dirty page logging on every memory slot, undoing `enable_dirty_log_all`.
+    pub fn disable_dirty_log_all(&self) -> Result<()> {
+        self.set_dirty_log_all(false)
+    }
+
+    fn set_dirty_log_all(&self, log_dirty_pages: bool) -> Result<()> {
+        let mut regions = self.mem_regions.lock();
+        self.guest_mem
+            .with_regions(|index, guest_addr, size, host_addr, _, _| {
+                // Safe because this re-registers a static slot with the same address, size and
+                // backing memory that `KvmVm::new` gave it; only the flags change.
+                unsafe {
+                    set_user_memory_region(
+                        &self.vm,
+                        index as MemSlot,
+                        false,
+                        log_dirty_pages,
+                        guest_addr.offset(),
+                        size as u64,
+                        host_addr as *mut u8,
+                    )
+                }
+            })?;
+        for (&slot, region) in regions.iter_mut() {
+            self.set_memory_region_slot(
+                slot,
+                region.guest_addr,
+                region.mem.as_ref(),
+                region.read_only,
+                log_dirty_pages,
+            )?;
+            region.log_dirty_pages = log_dirty_pages;
+        }
+        Ok(())
+    }
+
+    // Registers `mem` with KVM at `slot`. The caller must hold the `mem_regions` lock and keep
+    // `mem` mapped until the slot is removed.
+    fn set_memory_region_slot(
+        &self,
+        slot: MemSlot,
+        guest_addr: GuestAddress,
+        mem: &dyn MappedRegion,
+        read_only: bool,
+        log_dirty_pages: bool,
+    ) -> Result<()> {
+        // Without KVM_CAP_READONLY_MEM the kernel rejects KVM_MEM_READONLY with an unhelpful
+        // EINVAL, so report the missing support up front.
+        if read_only && !self.readonly_mem_supported {
+            return Err(Error::new(ENOTSUP));
+        }
+        let pgsz = host_page_size() as u64;
+        // KVM require to set the user memory region with page size aligned size. Safe to extend
+        // the mem.size() to be page size aligned because the mmap will round up the size to be
+        // page size aligned if it is not.
+        let size = (mem.size() as u64 + pgsz - 1) / pgsz * pgsz;
+        let end_addr = guest_addr
+            .checked_add(size)
+            .ok_or_else(|| Error::new(EOVERFLOW))?;
+        if self.guest_mem.range_overlap(guest_addr, end_addr) {
+            return Err(Error::new(ENOSPC));
+        }
+
+        // Safe because we check that the given guest address is valid and has no overlaps. We also
+        // know that the pointer and size are correct because the MemoryMapping interface ensures
+        // this. The caller keeps the memory mapping alive so that it won't be unmapped until the
+        // slot is removed.
+        unsafe {
+            set_user_memory_region(
+                &self.vm,
+                slot,
+                read_only,
+                log_dirty_pages,
+                guest_addr.offset() as u64,
+                size,
+                mem.as_ptr(),
+            )
+        }
+    }
+
+    // Issues KVM_GET_DIRTY_LOG for `slot`. The caller must have checked that `dirty_log` has room
+    // for one bit per page of the slot.
+    fn get_dirty_log_raw(&self, slot: MemSlot, dirty_log: &mut [u8]) -> Result<()> {
+        let mut dirty_log_kvm = kvm_dirty_log {
+            slot,
+            ..Default::default()
+        };
+        dirty_log_kvm.__bindgen_anon_1.dirty_bitmap = dirty_log.as_ptr() as *mut c_void;
+        // Safe because the `dirty_bitmap` pointer assigned above is guaranteed to be valid (because
+        // it's from a slice) and the caller checked that it will be large enough to hold the
+        // entire log.
+        let ret = unsafe { ioctl_with_ref(self, KVM_GET_DIRTY_LOG(), &dirty_log_kvm) };
         if ret == 0 {
             Ok(())
         } else {
@@ -378,6 +1515,32 @@ impl KvmVm {
         }
     }
 
+    // Issues KVM_SET_GSI_ROUTING without touching the route cache. Callers must hold the
+    // `routes` lock.
+    fn set_gsi_routing_raw(&self, routes: &[IrqRoute]) -> Result<()> {
+        self.validate_routes(routes)?;
+        // validate_routes already bounds the count by KVM's limit; this only guards the cast.
+        let nr = u32::try_from(routes.len()).map_err(|_| Error::new(E2BIG))?;
+        // vec_with_array_field always allocates the header, so an empty table is sent as a
+        // header with nr = 0, which clears the kernel's routes.
+        let mut irq_routing =
+            vec_with_array_field::<kvm_irq_routing, kvm_irq_routing_entry>(routes.len());
+        irq_routing[0].nr = nr;
+
+        if !routes.is_empty() {
+            // Safe because we ensured there is enough space in irq_routing to hold the number of
+            // route entries.
+            let irq_routes = unsafe { irq_routing[0].entries.as_mut_slice(routes.len()) };
+            for (route, irq_route) in routes.iter().zip(irq_routes.iter_mut()) {
+                *irq_route = kvm_irq_routing_entry::from(route);
+            }
+        }
+
+        ioctl_retry_eintr(|| unsafe {
+            ioctl_with_ref(self, KVM_SET_GSI_ROUTING(), &irq_routing[0])
+        })
+    }
+
     fn ioeventfd(
         &self,
         evt: &Event,
@@ -427,12 +1590,7 @@ impl KvmVm {
         };
         // Safe because we know that our file is a VM fd, we know the kernel will only read the
         // correct amount of memory from our pointer, and we verify the return result.
-        let ret = unsafe { ioctl_with_ref(self, KVM_IOEVENTFD(), &ioeventfd) };
-        if ret == 0 {
-            Ok(())
-        } else {
-            errno_result()
-        }
+        ioctl_retry_eintr(|| unsafe { ioctl_with_ref(self, KVM_IOEVENTFD(), &ioeventfd) })
     }
 
     /// Checks whether a particular KVM-specific capability is available for this VM.
@@ -453,8 +1611,6 @@ impl KvmVm {
         }
     }
 
-    // Currently only used on aarch64, but works on any architecture.
-    #[allow(dead_code)]
     /// Enables a KVM-specific capability for this VM, with the given arguments.
     ///
     /// # Safety
@@ -482,6 +1638,58 @@ impl KvmVm {
             errno_result()
         }
     }
+
+    /// Sets the maximum time in nanoseconds a halted vcpu of this VM polls for a wakeup before
+    /// yielding its host cpu, overriding the `halt_poll_ns` module parameter. 0 disables polling.
+    ///
+    /// Returns `ENOTSUP` if the kernel lacks KVM_CAP_HALT_POLL.
+    pub fn set_halt_poll_ns(&self, ns: u32) -> Result<()> {
+        if !self.check_raw_capability(KvmCap::HaltPoll) {
+            return Err(Error::new(ENOTSUP));
+        }
+        // Safe because KVM_CAP_HALT_POLL takes no pointer arguments.
+        unsafe { self.enable_raw_capability(KvmCap::HaltPoll, 0, &[ns as u64, 0, 0, 0]) }
+    }
+
+    /// Adds the VFIO group `group` to `vfio_dev`, a device created with `DeviceKind::Vfio`, so
+    /// that KVM can coordinate with the IOMMU for the group's devices. See
+    /// `KvmVfioDevice::add_group`.
+    pub fn vfio_group_add(
+        &self,
+        vfio_dev: &SafeDescriptor,
+        group: &dyn AsRawDescriptor,
+    ) -> Result<()> {
+        KvmVfioDevice {
+            descriptor: vfio_dev.try_clone()?,
+        }
+        .add_group(group)
+    }
+
+    /// Removes the VFIO group `group` from `vfio_dev`, undoing `vfio_group_add`. See
+    /// `KvmVfioDevice::delete_group`.
+    pub fn vfio_group_remove(
+        &self,
+        vfio_dev: &SafeDescriptor,
+        group: &dyn AsRawDescriptor,
+    ) -> Result<()> {
+        KvmVfioDevice {
+            descriptor: vfio_dev.try_clone()?,
+        }
+        .delete_group(group)
+    }
+
+    /// Creates a device of `kind` like `Vm::create_device`, but returns a handle that only allows
+    /// setting the attributes that are valid for that kind of device.
+    pub fn create_device_typed(&self, kind: DeviceKind) -> Result<TypedKvmDevice> {
+        let descriptor = self.create_device(kind)?;
+        Ok(match kind {
+            DeviceKind::Vfio => TypedKvmDevice::Vfio(KvmVfioDevice { descriptor }),
+            #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+            DeviceKind::ArmVgicV2 | DeviceKind::ArmVgicV3 => {
+                TypedKvmDevice::ArmVgic(KvmVgicDevice::new(kind, descriptor, self.routes.clone()))
+            }
+        })
+    }
 }
 
 impl Vm for KvmVm {
@@ -492,6 +1700,12 @@ impl Vm for KvmVm {
             guest_mem: self.guest_mem.clone(),
             mem_regions: self.mem_regions.clone(),
             mem_slot_gaps: self.mem_slot_gaps.clone(),
+            routes: self.routes.clone(),
+            readonly_mem_supported: self.readonly_mem_supported,
+            kernel_irqchip: self.kernel_irqchip.clone(),
+            split_irqchip: self.split_irqchip.clone(),
+            ack_notifiers: self.ack_notifiers.clone(),
+            irqfds: self.irqfds.clone(),
         })
     }
 
@@ -538,17 +1752,6 @@ impl Vm for KvmVm {
         read_only: bool,
         log_dirty_pages: bool,
     ) -> Result<MemSlot> {
-        let pgsz = pagesize() as u64;
-        // KVM require to set the user memory region with page size aligned size. Safe to extend
-        // the mem.size() to be page size aligned because the mmap will round up the size to be
-        // page size aligned if it is not.
-        let size = (mem.size() as u64 + pgsz - 1) / pgsz * pgsz;
-        let end_addr = guest_addr
-            .checked_add(size)
-            .ok_or_else(|| Error::new(EOVERFLOW))?;
-        if self.guest_mem.range_overlap(guest_addr, end_addr) {
-            return Err(Error::new(ENOSPC));
-        }
         let mut regions = self.mem_regions.lock();
         let mut gaps = self.mem_slot_gaps.lock();
         let slot = match gaps.pop() {
@@ -556,35 +1759,29 @@ impl Vm for KvmVm {
             None => (regions.len() + self.guest_mem.num_regions() as usize) as MemSlot,
         };
 
-        // Safe because we check that the given guest address is valid and has no overlaps. We also
-        // know that the pointer and size are correct because the MemoryMapping interface ensures
-        // this. We take ownership of the memory mapping so that it won't be unmapped until the slot
-        // is removed.
-        let res = unsafe {
-            set_user_memory_region(
-                &self.vm,
-                slot,
-                read_only,
-                log_dirty_pages,
-                guest_addr.offset() as u64,
-                size,
-                mem.as_ptr(),
-            )
-        };
-
-        if let Err(e) = res {
+        if let Err(e) =
+            self.set_memory_region_slot(slot, guest_addr, mem.as_ref(), read_only, log_dirty_pages)
+        {
             gaps.push(Reverse(slot));
             return Err(e);
         }
-        regions.insert(slot, mem);
+        regions.insert(
+            slot,
+            KvmMemRegion {
+                guest_addr,
+                mem,
+                read_only,
+                log_dirty_pages,
+            },
+        );
         Ok(slot)
     }
 
     fn msync_memory_region(&mut self, slot: MemSlot, offset: usize, size: usize) -> Result<()> {
         let mut regions = self.mem_regions.lock();
-        let mem = regions.get_mut(&slot).ok_or_else(|| Error::new(ENOENT))?;
+        let region = regions.get_mut(&slot).ok_or_else(|| Error::new(ENOENT))?;
 
-        mem.msync(offset, size).map_err(|err| match err {
+        region.mem.msync(offset, size).map_err(|err| match err {
             MmapError::InvalidAddress => Error::new(EFAULT),
             MmapError::NotPageAligned => Error::new(EINVAL),
             MmapError::SystemCallFailed(e) => e,
@@ -603,7 +1800,7 @@ impl Vm for KvmVm {
         }
         self.mem_slot_gaps.lock().push(Reverse(slot));
         // This remove will always succeed because of the contains_key check above.
-        Ok(regions.remove(&slot).unwrap())
+        Ok(regions.remove(&slot).unwrap().mem)
     }
 
     fn create_device(&self, kind: DeviceKind) -> Result<SafeDescriptor> {
@@ -627,6 +1824,12 @@ impl Vm for KvmVm {
         // amount of memory to our pointer, and we verify the return result.
         let ret = unsafe { base::ioctl_with_ref(self, KVM_CREATE_DEVICE(), &device) };
         if ret == 0 {
+            #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+            if matches!(kind, DeviceKind::ArmVgicV2 | DeviceKind::ArmVgicV3) {
+                self.kernel_irqchip
+                    .store(true, std::sync::atomic::Ordering::Release);
+                *self.routes.lock() = default_irq_routes();
+            }
             // Safe because we verify that ret is valid and we own the fd.
             Ok(unsafe { SafeDescriptor::from_raw_descriptor(device.fd as i32) })
         } else {
@@ -636,25 +1839,12 @@ impl Vm for KvmVm {
 
     fn get_dirty_log(&self, slot: MemSlot, dirty_log: &mut [u8]) -> Result<()> {
         let regions = self.mem_regions.lock();
-        let mmap = regions.get(&slot).ok_or_else(|| Error::new(ENOENT))?;
+        let region = regions.get(&slot).ok_or_else(|| Error::new(ENOENT))?;
         // Ensures that there are as many bytes in dirty_log as there are pages in the mmap.
-        if dirty_log_bitmap_size(mmap.size()) > dirty_log.len() {
+        if dirty_log_bitmap_size(region.mem.size()) > dirty_log.len() {
             return Err(Error::new(EINVAL));
         }
-
-        let mut dirty_log_kvm = kvm_dirty_log {
-            slot,
-            ..Default::default()
-        };
-        dirty_log_kvm.__bindgen_anon_1.dirty_bitmap = dirty_log.as_ptr() as *mut c_void;
-        // Safe because the `dirty_bitmap` pointer assigned above is guaranteed to be valid (because
-        // it's from a slice) and we checked that it will be large enough to hold the entire log.
-        let ret = unsafe { ioctl_with_ref(self, KVM_GET_DIRTY_LOG(), &dirty_log_kvm) };
-        if ret == 0 {
-            Ok(())
-        } else {
-            errno_result()
-        }
+        self.get_dirty_log_raw(slot, dirty_log)
     }
 
     fn register_ioevent(
@@ -700,7 +1890,7 @@ impl Vm for KvmVm {
         let mut regions = self.mem_regions.lock();
         let region = regions.get_mut(&slot).ok_or_else(|| Error::new(EINVAL))?;
 
-        match region.add_fd_mapping(offset, size, fd, fd_offset, prot) {
+        match region.mem.add_fd_mapping(offset, size, fd, fd_offset, prot) {
             Ok(()) => Ok(()),
             Err(MmapError::SystemCallFailed(e)) => Err(e),
             Err(_) => Err(Error::new(EIO)),
@@ -711,7 +1901,7 @@ impl Vm for KvmVm {
         let mut regions = self.mem_regions.lock();
         let region = regions.get_mut(&slot).ok_or_else(|| Error::new(EINVAL))?;
 
-        match region.remove_mapping(offset, size) {
+        match region.mem.remove_mapping(offset, size) {
             Ok(()) => Ok(()),
             Err(MmapError::SystemCallFailed(e)) => Err(e),
             Err(_) => Err(Error::new(EIO)),
@@ -745,6 +1935,8 @@ pub struct KvmVcpu {
     id: usize,
     run_mmap: MemoryMapping,
     vcpu_run_handle_fingerprint: Arc<AtomicU64>,
+    /// Whether the VM reported `VmCap::PvClockSuspend` when the vcpu was created.
+    pvclock_suspend: bool,
 }
 
 pub(super) struct VcpuThread {
@@ -754,6 +1946,70 @@ pub(super) struct VcpuThread {
 
 thread_local!(static VCPU_THREAD: RefCell<Option<VcpuThread>> = RefCell::new(None));
 
+/// A POSIX timer that sends a signal to the thread that created it when it expires. The timer is
+/// deleted when dropped.
+struct ThreadSignalTimer(libc::timer_t);
+
+impl ThreadSignalTimer {
+    fn new(signal_num: c_int) -> Result<ThreadSignalTimer> {
+        // Safe because `sigevent` is plain data for which all zeroes is a valid value.
+        let mut event: libc::sigevent = unsafe { std::mem::zeroed() };
+        event.sigev_notify = libc::SIGEV_THREAD_ID;
+        event.sigev_signo = signal_num;
+        event.sigev_notify_thread_id = base::gettid();
+        let mut timer: libc::timer_t = std::ptr::null_mut();
+        // Safe because both pointers are valid for the duration of the call and we check the
+        // return value.
+        let ret = unsafe { libc::timer_create(libc::CLOCK_MONOTONIC, &mut event, &mut timer) };
+        if ret == 0 {
+            Ok(ThreadSignalTimer(timer))
+        } else {
+            errno_result()
+        }
+    }
+
+    /// Arms the timer to fire once after `timeout`.
+    fn arm(&self, timeout: Duration) -> Result<()> {
+        let spec = libc::itimerspec {
+            it_interval: libc::timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            },
+            it_value: libc::timespec {
+                tv_sec: timeout.as_secs() as libc::time_t,
+                tv_nsec: timeout.subsec_nanos() as libc::c_long,
+            },
+        };
+        // Safe because we own the timer and the kernel only reads `spec`.
+        let ret = unsafe { libc::timer_settime(self.0, 0, &spec, std::ptr::null_mut()) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            errno_result()
+        }
+    }
+
+    /// Returns true if the armed timer has fired.
+    fn expired(&self) -> Result<bool> {
+        // Safe because `itimerspec` is plain data for which all zeroes is a valid value.
+        let mut spec: libc::itimerspec = unsafe { std::mem::zeroed() };
+        // Safe because we own the timer and the kernel only writes to `spec`.
+        let ret = unsafe { libc::timer_gettime(self.0, &mut spec) };
+        if ret == 0 {
+            Ok(spec.it_value.tv_sec == 0 && spec.it_value.tv_nsec == 0)
+        } else {
+            errno_result()
+        }
+    }
+}
+
+impl Drop for ThreadSignalTimer {
+    fn drop(&mut self) {
+        // Safe because we own the timer and it is not used after this.
+        unsafe { libc::timer_delete(self.0) };
+    }
+}
+
 impl Vcpu for KvmVcpu {
     fn try_clone(&self) -> Result<Self> {
         let vm = self.vm.try_clone()?;
@@ -770,6 +2026,7 @@ impl Vcpu for KvmVcpu {
             id: self.id,
             run_mmap,
             vcpu_run_handle_fingerprint,
+            pvclock_suspend: self.pvclock_suspend,
         })
     }
 
@@ -949,7 +2206,7 @@ impl Vcpu for KvmVcpu {
             KVM_EXIT_HYPERV => Ok(VcpuExit::HypervHypercall),
             KVM_EXIT_UNKNOWN => Ok(VcpuExit::Unknown),
             KVM_EXIT_EXCEPTION => Ok(VcpuExit::Exception),
-            KVM_EXIT_HYPERCALL => Ok(VcpuExit::Hypercall),
+            KVM_EXIT_HYPERCALL => self.hypercall_exit(),
             KVM_EXIT_DEBUG => Ok(VcpuExit::Debug),
             KVM_EXIT_HLT => Ok(VcpuExit::Hlt),
             KVM_EXIT_IRQ_WINDOW_OPEN => Ok(VcpuExit::IrqWindowOpen),
@@ -957,13 +2214,10 @@ impl Vcpu for KvmVcpu {
             KVM_EXIT_FAIL_ENTRY => {
                 // Safe because the exit_reason (which comes from the kernel) told us which
                 // union field to use.
-                let hardware_entry_failure_reason = unsafe {
-                    run.__bindgen_anon_1
-                        .fail_entry
-                        .hardware_entry_failure_reason
-                };
+                let fail_entry = unsafe { &run.__bindgen_anon_1.fail_entry };
                 Ok(VcpuExit::FailEntry {
-                    hardware_entry_failure_reason,
+                    hardware_entry_failure_reason: fail_entry.hardware_entry_failure_reason,
+                    cpu: fail_entry.cpu,
                 })
             }
             KVM_EXIT_INTR => Ok(VcpuExit::Intr),
@@ -1166,6 +2420,40 @@ impl Vcpu for KvmVcpu {
 }
 
 impl KvmVcpu {
+    /// Runs the vcpu like `Vcpu::run`, but gives up once `timeout` has elapsed without an exit,
+    /// returning `Ok(None)`. A zero `timeout` returns `Ok(None)` without running.
+    ///
+    /// The deadline is enforced by a timer that sends the signal `run_handle` was taken with to
+    /// this thread, so a handler for that signal must be registered, as is done for kicking vcpus.
+    /// Returns `EINVAL` if `run_handle` was taken without a signal. If the guest exits just as the
+    /// timer fires, the exit is returned and the signal may be delivered after, making the next
+    /// `run` return `EINTR` like any other kick.
+    pub fn run_with_timeout(
+        &mut self,
+        run_handle: &VcpuRunHandle,
+        timeout: Duration,
+    ) -> Result<Option<VcpuExit>> {
+        let signal_num = VCPU_THREAD
+            .with(|v| v.borrow().as_ref().and_then(|state| state.signal_num))
+            .ok_or_else(|| Error::new(EINVAL))?;
+        if timeout.is_zero() {
+            return Ok(None);
+        }
+
+        let timer = ThreadSignalTimer::new(signal_num)?;
+        timer.arm(timeout)?;
+        let result = self.run(run_handle);
+        match result {
+            Err(e) if e.errno() == EINTR && timer.expired()? => {
+                // The kick handler may have requested an immediate exit, which would make the
+                // next run return right away.
+                self.set_immediate_exit(false);
+                Ok(None)
+            }
+            result => result.map(Some),
+        }
+    }
+
     /// Gets the vcpu's current "multiprocessing state".
     ///
     /// See the documentation for KVM_GET_MP_STATE. This call can only succeed after
@@ -1314,3 +2602,53 @@ impl From<&MPState> for kvm_mp_state {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fails with `errno` like an ioctl would.
+    fn fail_with(errno: c_int) -> c_int {
+        // Safe because __errno_location returns a valid pointer to this thread's errno.
+        unsafe { *libc::__errno_location() = errno };
+        -1
+    }
+
+    #[test]
+    fn ioctl_retry_eintr_succeeds_after_interruptions() {
+        let retries = eintr_retries();
+        let mut calls = 0;
+        ioctl_retry_eintr(|| {
+            calls += 1;
+            if calls <= 3 {
+                fail_with(EINTR)
+            } else {
+                0
+            }
+        })
+        .unwrap();
+        assert_eq!(calls, 4);
+        assert!(eintr_retries() >= retries + 3);
+    }
+
+    #[test]
+    fn ioctl_retry_eintr_is_bounded() {
+        let mut calls = 0;
+        let err = ioctl_retry_eintr(|| {
+            calls += 1;
+            fail_with(EINTR)
+        })
+        .unwrap_err();
+        assert_eq!(err.errno(), EINTR);
+        assert_eq!(calls, MAX_EINTR_RETRIES + 1);
+
+        // Other errors are not retried.
+        calls = 0;
+        assert!(ioctl_retry_eintr(|| {
+            calls += 1;
+            fail_with(EINVAL)
+        })
+        .is_err());
+        assert_eq!(calls, 1);
+    }
+}
@@ -3,6 +3,7 @@
 // found in the LICENSE file.
 
 use std::arch::x86_64::CpuidResult;
+use std::mem::size_of;
 
 use base::errno_result;
 use base::error;
@@ -14,17 +15,24 @@ use base::ioctl_with_ref;
 use base::ioctl_with_val;
 use base::AsRawDescriptor;
 use base::Error;
+use base::Event;
 use base::IoctlNr;
 use base::MappedRegion;
 use base::Result;
 use data_model::vec_with_array_field;
 use kvm_sys::*;
 use libc::E2BIG;
+use libc::EBUSY;
+use libc::EINVAL;
+use libc::ENOTSUP;
 use libc::ENXIO;
+use serde::Deserialize;
+use serde::Serialize;
 use vm_memory::GuestAddress;
 
 use super::Config;
 use super::Kvm;
+use super::KvmCap;
 use super::KvmVcpu;
 use super::KvmVm;
 use crate::get_tsc_offset_from_msr;
@@ -40,8 +48,10 @@ use crate::Fpu;
 use crate::HypervisorX86_64;
 use crate::IoapicRedirectionTableEntry;
 use crate::IoapicState;
+use crate::IrqRoute;
 use crate::IrqSourceChip;
 use crate::LapicState;
+use crate::MPState;
 use crate::PicSelect;
 use crate::PicState;
 use crate::PitChannelState;
@@ -56,6 +66,8 @@ use crate::VcpuX86_64;
 use crate::VmCap;
 use crate::VmX86_64;
 use crate::MAX_IOAPIC_PINS;
+use crate::MSR_IA32_TSC_DEADLINE;
+use crate::MSR_IA32_XSS;
 use crate::NUM_IOAPIC_PINS;
 
 type KvmCpuId = kvm::CpuId;
@@ -116,6 +128,23 @@ impl Kvm {
         // Assume the guest physical address size is the same as the host.
         host_phys_addr_bits()
     }
+
+    /// Returns the entry for CPUID leaf `function` and subleaf `index` in KVM's supported CPUID,
+    /// or None if KVM doesn't report that leaf. `index` is ignored for leaves without subleaves.
+    ///
+    /// The supported CPUID is fetched from KVM on first use and shared with clones of this `Kvm`.
+    pub fn get_cpuid_entry(&self, function: u32, index: u32) -> Result<Option<CpuIdEntry>> {
+        let entries = self
+            .supported_cpuid
+            .get_or_try_init(|| self.get_supported_cpuid().map(|cpuid| cpuid.cpu_id_entries))?;
+        Ok(entries
+            .iter()
+            .find(|entry| {
+                entry.function == function
+                    && (entry.flags & KVM_CPUID_FLAG_SIGNIFCANT_INDEX == 0 || entry.index == index)
+            })
+            .copied())
+    }
 }
 
 impl HypervisorX86_64 for Kvm {
@@ -124,6 +153,11 @@ impl HypervisorX86_64 for Kvm {
     }
 
     fn get_emulated_cpuid(&self) -> Result<CpuId> {
+        // Hosts without KVM_CAP_EXT_EMUL_CPUID don't implement the ioctl, which is the same as
+        // not emulating anything.
+        if self.check_extension_int(KVM_CAP_EXT_EMUL_CPUID) == 0 {
+            return Ok(CpuId::new(0));
+        }
         self.get_cpuid(KVM_GET_EMULATED_CPUID())
     }
 
@@ -451,22 +485,57 @@ impl KvmVm {
         }
     }
 
-    /// Enable support for split-irqchip.
-    pub fn enable_split_irqchip(&self, ioapic_pins: usize) -> Result<()> {
+    /// Enables the split irqchip, keeping the LAPICs in the kernel while the IOAPIC and PIC,
+    /// with `num_pins` IOAPIC pins, are emulated in user space.
+    ///
+    /// This replaces `create_irq_chip` for the split model; the two are mutually exclusive and
+    /// this must be called before any vcpu is created. Returns `ENOTSUP` if
+    /// KVM_CAP_SPLIT_IRQCHIP is not available.
+    pub fn enable_split_irqchip(&self, num_pins: u32) -> Result<()> {
+        if !self.check_raw_capability(KvmCap::SplitIrqchip) {
+            return Err(Error::new(ENOTSUP));
+        }
         let mut cap = kvm_enable_cap {
             cap: KVM_CAP_SPLIT_IRQCHIP,
             ..Default::default()
         };
-        cap.args[0] = ioapic_pins as u64;
+        cap.args[0] = num_pins as u64;
         // safe becuase we allocated the struct and we know the kernel will read
         // exactly the size of the struct
         let ret = unsafe { ioctl_with_ref(self, KVM_ENABLE_CAP(), &cap) };
         if ret < 0 {
             errno_result()
         } else {
+            self.split_irqchip
+                .store(true, std::sync::atomic::Ordering::Release);
             Ok(())
         }
     }
+
+    /// Registers `evt` to be signalled when the guest EOIs the user-space IOAPIC pin `gsi`.
+    ///
+    /// With KVM_CAP_SPLIT_IRQCHIP the kernel reports level-triggered EOIs as
+    /// `VcpuExit::IoapicEoi`, carrying only the vector. The user-space IOAPIC owns the
+    /// vector-to-pin mapping, so it forwards the EOI with `notify_irq_ack(gsi)`, which signals
+    /// the event registered here.
+    ///
+    /// Returns `EBUSY` if the full in-kernel irqchip was created, since it handles EOIs itself
+    /// (use an irqfd resample event instead), and `ENOTSUP` if the split irqchip is not enabled.
+    pub fn register_ioapic_ack_notifier(&self, gsi: u32, evt: &Event) -> Result<()> {
+        if self
+            .kernel_irqchip
+            .load(std::sync::atomic::Ordering::Acquire)
+        {
+            return Err(Error::new(EBUSY));
+        }
+        if !self
+            .split_irqchip
+            .load(std::sync::atomic::Ordering::Acquire)
+        {
+            return Err(Error::new(ENOTSUP));
+        }
+        self.register_irq_ack_notifier(gsi, evt)
+    }
 }
 
 impl VmX86_64 for KvmVm {
@@ -527,6 +596,12 @@ impl KvmVcpu {
     pub fn system_event_reset(&self, _event_flags: u64) -> Result<VcpuExit> {
         Ok(VcpuExit::SystemEventReset)
     }
+
+    /// Handles a `KVM_EXIT_HYPERCALL` and returns the appropriate `VcpuExit` value for the run
+    /// loop to handle.
+    pub fn hypercall_exit(&self) -> Result<VcpuExit> {
+        Ok(VcpuExit::Hypercall)
+    }
 }
 
 impl VcpuX86_64 for KvmVcpu {
@@ -834,7 +909,267 @@ impl VcpuX86_64 for KvmVcpu {
     }
 }
 
+// MSRs captured by `KvmVcpu::snapshot`. The TSC itself is carried separately via
+// `get_tsc_offset`/`set_tsc_offset` and the TSC deadline via `VcpuSnapshot::tsc_deadline`.
+// MSRs KVM can't read for this guest, such as IA32_TSC_AUX without RDTSCP or IA32_XSS without
+// XSAVES, are left out of the snapshot.
+const SNAPSHOT_MSRS: &[u32] = &[
+    0x00000174, // IA32_SYSENTER_CS
+    0x00000175, // IA32_SYSENTER_ESP
+    0x00000176, // IA32_SYSENTER_EIP
+    0x000001a0, // IA32_MISC_ENABLE
+    0x00000277, // IA32_PAT
+    0xc0000081, // STAR
+    0xc0000082, // LSTAR
+    0xc0000083, // CSTAR
+    0xc0000084, // SFMASK
+    0xc0000102, // KERNEL_GS_BASE
+    0xc0000103, // IA32_TSC_AUX
+    MSR_IA32_XSS,
+];
+
+/// The complete architectural state of an x86_64 vcpu, as captured by `KvmVcpu::snapshot`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VcpuSnapshot {
+    pub regs: Regs,
+    pub sregs: Sregs,
+    pub fpu: Fpu,
+    pub debugregs: DebugRegs,
+    pub xcrs: Vec<Register>,
+    /// The XSAVE area as returned by `get_xsave`, or `None` if KVM lacks KVM_CAP_XSAVE.
+    pub xsave: Option<Vec<u32>>,
+    pub msrs: Vec<Register>,
+    pub lapic: LapicState,
+    pub mp_state: MPState,
+    /// The TSC deadline MSR, or `None` if KVM lacks the TSC deadline timer.
+    pub tsc_deadline: Option<u64>,
+    pub vcpu_events: VcpuEvents,
+}
+
+/// The state of a vcpu saved across a suspend of the VM, as captured by `KvmVcpu::suspend`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VcpuSuspendState {
+    pub mp_state: MPState,
+    pub vcpu_events: VcpuEvents,
+}
+
+/// Pending exceptions, interrupts, NMIs and SMM state of a vcpu. The fields are those of
+/// `kvm_vcpu_events`; see the documentation for KVM_GET_VCPU_EVENTS.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VcpuEvents {
+    pub exception_injected: u8,
+    pub exception_nr: u8,
+    pub exception_has_error_code: u8,
+    pub exception_pending: u8,
+    pub exception_error_code: u32,
+    pub exception_has_payload: u8,
+    pub exception_payload: u64,
+    pub interrupt_injected: u8,
+    pub interrupt_nr: u8,
+    pub interrupt_soft: u8,
+    pub interrupt_shadow: u8,
+    pub nmi_injected: u8,
+    pub nmi_pending: u8,
+    pub nmi_masked: u8,
+    pub sipi_vector: u32,
+    pub smm: u8,
+    pub smi_pending: u8,
+    pub smm_inside_nmi: u8,
+    pub latched_init: u8,
+    /// `KVM_VCPUEVENT_VALID_*` flags telling which of the fields are valid.
+    pub flags: u32,
+}
+
+impl From<&kvm_vcpu_events> for VcpuEvents {
+    fn from(e: &kvm_vcpu_events) -> Self {
+        VcpuEvents {
+            exception_injected: e.exception.injected,
+            exception_nr: e.exception.nr,
+            exception_has_error_code: e.exception.has_error_code,
+            exception_pending: e.exception.pending,
+            exception_error_code: e.exception.error_code,
+            exception_has_payload: e.exception_has_payload,
+            exception_payload: e.exception_payload,
+            interrupt_injected: e.interrupt.injected,
+            interrupt_nr: e.interrupt.nr,
+            interrupt_soft: e.interrupt.soft,
+            interrupt_shadow: e.interrupt.shadow,
+            nmi_injected: e.nmi.injected,
+            nmi_pending: e.nmi.pending,
+            nmi_masked: e.nmi.masked,
+            sipi_vector: e.sipi_vector,
+            smm: e.smi.smm,
+            smi_pending: e.smi.pending,
+            smm_inside_nmi: e.smi.smm_inside_nmi,
+            latched_init: e.smi.latched_init,
+            flags: e.flags,
+        }
+    }
+}
+
+impl From<&VcpuEvents> for kvm_vcpu_events {
+    fn from(e: &VcpuEvents) -> Self {
+        let mut events = kvm_vcpu_events {
+            sipi_vector: e.sipi_vector,
+            // KVM_GET_VCPU_EVENTS always reports these fields, but KVM_SET_VCPU_EVENTS only
+            // applies them when the corresponding flag is set.
+            flags: e.flags | KVM_VCPUEVENT_VALID_NMI_PENDING | KVM_VCPUEVENT_VALID_SIPI_VECTOR,
+            exception_has_payload: e.exception_has_payload,
+            exception_payload: e.exception_payload,
+            ..Default::default()
+        };
+        events.exception.injected = e.exception_injected;
+        events.exception.nr = e.exception_nr;
+        events.exception.has_error_code = e.exception_has_error_code;
+        events.exception.pending = e.exception_pending;
+        events.exception.error_code = e.exception_error_code;
+        events.interrupt.injected = e.interrupt_injected;
+        events.interrupt.nr = e.interrupt_nr;
+        events.interrupt.soft = e.interrupt_soft;
+        events.interrupt.shadow = e.interrupt_shadow;
+        events.nmi.injected = e.nmi_injected;
+        events.nmi.pending = e.nmi_pending;
+        events.nmi.masked = e.nmi_masked;
+        events.smi.smm = e.smm;
+        events.smi.pending = e.smi_pending;
+        events.smi.smm_inside_nmi = e.smm_inside_nmi;
+        events.smi.latched_init = e.latched_init;
+        events
+    }
+}
+
 impl KvmVcpu {
+    /// Captures the full state of the vcpu so it can later be applied with `restore`.
+    ///
+    /// The VM must have an in-kernel irqchip, since the local APIC and mp_state are included.
+    pub fn snapshot(&self) -> Result<VcpuSnapshot> {
+        let mut msrs = Vec::with_capacity(SNAPSHOT_MSRS.len());
+        let mut remaining = SNAPSHOT_MSRS;
+        while !remaining.is_empty() {
+            let mut read: Vec<Register> = remaining
+                .iter()
+                .map(|&id| Register { id, value: 0 })
+                .collect();
+            self.get_msrs(&mut read)?;
+            // KVM_GET_MSRS stops at the first MSR it can't read, which is skipped.
+            remaining = &remaining[(read.len() + 1).min(remaining.len())..];
+            msrs.append(&mut read);
+        }
+        let xsave = match self.get_xsave() {
+            Ok(xsave) => Some(xsave),
+            Err(e) if e.errno() == ENOTSUP => None,
+            Err(e) => return Err(e),
+        };
+        let tsc_deadline = match self.get_tsc_deadline() {
+            Ok(value) => Some(value),
+            Err(e) if e.errno() == ENOTSUP => None,
+            Err(e) => return Err(e),
+        };
+        let vcpu_events = VcpuEvents::from(&self.get_vcpu_events()?);
+
+        Ok(VcpuSnapshot {
+            regs: self.get_regs()?,
+            sregs: self.get_sregs()?,
+            fpu: self.get_fpu()?,
+            debugregs: self.get_debugregs()?,
+            xcrs: self.get_xcrs()?,
+            xsave,
+            msrs,
+            lapic: LapicState::from(&self.get_lapic()?),
+            mp_state: MPState::from(&self.get_mp_state()?),
+            tsc_deadline,
+            vcpu_events,
+        })
+    }
+
+    /// Applies a state previously captured with `snapshot`.
+    ///
+    /// The sub-states are not independent, so they are applied in this order:
+    /// - xcrs before sregs, since setting CR4.OSXSAVE requires a valid XCR0.
+    /// - XSAVE area after the FPU and xcrs, since it holds the x87 and SSE state too and its
+    ///   components are checked against XCR0.
+    /// - sregs before MSRs, since whether some MSRs are writable depends on CR0, CR4 and EFER.
+    /// - mp_state before the local APIC, and the local APIC before the TSC deadline, since
+    ///   KVM_SET_LAPIC re-arms the APIC timer and would discard a previously written deadline.
+    /// - vcpu events after the local APIC and MSRs, since pending interrupts and SMM state are
+    ///   validated against them.
+    /// - debug registers last, since setting DR7 may arm breakpoints.
+    pub fn restore(&self, snap: &VcpuSnapshot) -> Result<()> {
+        self.set_regs(&snap.regs)?;
+        self.set_fpu(&snap.fpu)?;
+        self.set_xcrs(&snap.xcrs)?;
+        if let Some(xsave) = &snap.xsave {
+            self.set_xsave(xsave)?;
+        }
+        self.set_sregs(&snap.sregs)?;
+        self.set_msrs(&snap.msrs)?;
+        self.set_mp_state(&kvm_mp_state::from(&snap.mp_state))?;
+        self.set_lapic(&kvm_lapic_state::from(&snap.lapic))?;
+        if let Some(value) = snap.tsc_deadline {
+            self.set_tsc_deadline(value)?;
+        }
+        self.set_vcpu_events(&kvm_vcpu_events::from(&snap.vcpu_events))?;
+        self.set_debugregs(&snap.debugregs)
+    }
+
+    /// Saves the mp_state and pending events of the vcpu, which must not be running, before the VM
+    /// is suspended.
+    pub fn suspend(&self) -> Result<VcpuSuspendState> {
+        Ok(VcpuSuspendState {
+            mp_state: MPState::from(&self.get_mp_state()?),
+            vcpu_events: VcpuEvents::from(&self.get_vcpu_events()?),
+        })
+    }
+
+    /// Applies a state saved with `suspend` and, if KVM supports KVM_KVMCLOCK_CTRL
+    /// (`VmCap::PvClockSuspend`), tells the guest that it was paused so its watchdogs don't
+    /// mistake the suspend for a soft lockup.
+    ///
+    /// Returns whether the guest was told. A guest that doesn't use kvmclock has nothing to be
+    /// told, so the `EINVAL` KVM returns for it is not an error.
+    pub fn resume(&self, state: &VcpuSuspendState) -> Result<bool> {
+        self.set_mp_state(&kvm_mp_state::from(&state.mp_state))?;
+        self.set_vcpu_events(&kvm_vcpu_events::from(&state.vcpu_events))?;
+
+        if !self.pvclock_suspend {
+            return Ok(false);
+        }
+        match self.pvclock_ctrl_arch() {
+            Ok(()) => Ok(true),
+            Err(e) if e.errno() == EINVAL => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Gets the vcpu's pending exceptions, interrupts and NMIs.
+    ///
+    /// See the documentation for KVM_GET_VCPU_EVENTS.
+    pub fn get_vcpu_events(&self) -> Result<kvm_vcpu_events> {
+        let mut events: kvm_vcpu_events = Default::default();
+        // Safe because we know that our file is a VCPU fd, we know the kernel will only write the
+        // correct amount of memory to our pointer, and we verify the return result.
+        let ret = unsafe { ioctl_with_mut_ref(self, KVM_GET_VCPU_EVENTS(), &mut events) };
+        if ret == 0 {
+            Ok(events)
+        } else {
+            errno_result()
+        }
+    }
+
+    /// Sets the vcpu's pending exceptions, interrupts and NMIs.
+    ///
+    /// See the documentation for KVM_SET_VCPU_EVENTS.
+    pub fn set_vcpu_events(&self, events: &kvm_vcpu_events) -> Result<()> {
+        // Safe because we know that our file is a VCPU fd, we know the kernel will only read the
+        // correct amount of memory from our pointer, and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, KVM_SET_VCPU_EVENTS(), events) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            errno_result()
+        }
+    }
+
     /// X86 specific call to get the state of the "Local Advanced Programmable Interrupt Controller".
     ///
     /// See the documentation for KVM_GET_LAPIC.
@@ -865,6 +1200,273 @@ impl KvmVcpu {
         }
         Ok(())
     }
+
+    /// Gets the value of the TSC deadline MSR, which holds the TSC value at which a pending local
+    /// APIC timer in TSC-deadline mode fires, or 0 if no such timer is armed.
+    ///
+    /// Returns `ENOTSUP` if KVM does not support the TSC deadline timer.
+    pub fn get_tsc_deadline(&self) -> Result<u64> {
+        self.check_tsc_deadline_timer()?;
+        let mut regs = vec![Register {
+            id: MSR_IA32_TSC_DEADLINE,
+            value: 0,
+        }];
+        self.get_msrs(&mut regs)?;
+        match regs.first() {
+            Some(reg) => Ok(reg.value),
+            None => Err(Error::new(ENXIO)),
+        }
+    }
+
+    /// Sets the value of the TSC deadline MSR. See `get_tsc_deadline`.
+    ///
+    /// Returns `ENOTSUP` if KVM does not support the TSC deadline timer, and `EINVAL` if KVM
+    /// rejects `value`.
+    pub fn set_tsc_deadline(&self, value: u64) -> Result<()> {
+        self.check_tsc_deadline_timer()?;
+        self.set_single_msr(MSR_IA32_TSC_DEADLINE, value)
+    }
+
+    /// Gets the value of CR8, the task priority register used to mask lower priority interrupts.
+    pub fn get_cr8(&self) -> Result<u64> {
+        Ok(self.get_sregs()?.cr8)
+    }
+
+    /// Sets the value of CR8, leaving the rest of the special registers untouched.
+    ///
+    /// Returns `EINVAL` if `value` does not fit in the 4 bits of the task priority class.
+    pub fn set_cr8(&self, value: u64) -> Result<()> {
+        if value > 0xf {
+            return Err(Error::new(EINVAL));
+        }
+        let mut sregs = self.get_sregs()?;
+        sregs.cr8 = value;
+        self.set_sregs(&sregs)
+    }
+
+    /// Gets the value of the XSS MSR, which enables the supervisor state components, such as CET
+    /// state, that XSAVES saves.
+    ///
+    /// Returns `ENOTSUP` if KVM does not let the XSS MSR be accessed on this vcpu.
+    pub fn get_xss(&self) -> Result<u64> {
+        let mut regs = vec![Register {
+            id: MSR_IA32_XSS,
+            value: 0,
+        }];
+        self.get_msrs(&mut regs)?;
+        match regs.first() {
+            Some(reg) => Ok(reg.value),
+            None => Err(Error::new(ENOTSUP)),
+        }
+    }
+
+    /// Sets the value of the XSS MSR. See `get_xss`.
+    ///
+    /// Returns `ENOTSUP` if KVM does not let the XSS MSR be accessed on this vcpu, and `EINVAL` if
+    /// it rejects `value`.
+    pub fn set_xss(&self, value: u64) -> Result<()> {
+        self.get_xss()?;
+        self.set_single_msr(MSR_IA32_XSS, value)
+    }
+
+    // Sets the MSR `id` to `value`. Unlike `set_msrs`, which succeeds however many entries KVM
+    // writes, this returns `EINVAL` if KVM doesn't write the MSR.
+    fn set_single_msr(&self, id: u32, value: u64) -> Result<()> {
+        let msrs = to_kvm_msrs(&[Register { id, value }]);
+        // Safe because we allocated the struct and we know the kernel will read exactly the size
+        // of the struct and its single entry.
+        let ret = unsafe { ioctl_with_ref(self, KVM_SET_MSRS(), &msrs[0]) };
+        // KVM_SET_MSRS returns the number of msr entries written.
+        match ret {
+            1 => Ok(()),
+            0 => Err(Error::new(EINVAL)),
+            _ => errno_result(),
+        }
+    }
+
+    /// Gets the XSAVE area of the vcpu as the 32-bit words of an uncompacted XSAVE image.
+    ///
+    /// The area is sized as reported by KVM_CAP_XSAVE2 so it holds every state component KVM
+    /// saves, including those beyond the legacy 4KiB `kvm_xsave` struct. Returns `ENOTSUP` if
+    /// KVM_CAP_XSAVE is not available.
+    pub fn get_xsave(&self) -> Result<Vec<u32>> {
+        let (size, get_xsave) = self.xsave_size()?;
+        let mut xsave = vec![0u32; size / 4];
+        // Safe because the buffer is `size` bytes long, which is as much as KVM reported it will
+        // write, and we verify the return result.
+        let ret = unsafe { ioctl_with_mut_ptr(self, get_xsave, xsave.as_mut_ptr()) };
+        if ret < 0 {
+            return errno_result();
+        }
+        Ok(xsave)
+    }
+
+    /// Sets the XSAVE area of the vcpu, as returned by `get_xsave`.
+    ///
+    /// Returns `EINVAL` if `xsave` is not the size of the vcpu's XSAVE area, and `ENOTSUP` if
+    /// KVM_CAP_XSAVE is not available.
+    pub fn set_xsave(&self, xsave: &[u32]) -> Result<()> {
+        let (size, _) = self.xsave_size()?;
+        if xsave.len() * 4 != size {
+            return Err(Error::new(EINVAL));
+        }
+        // Safe because the buffer is `size` bytes long, which is as much as KVM reads, and we
+        // verify the return result.
+        let ret = unsafe { ioctl_with_ptr(self, KVM_SET_XSAVE(), xsave.as_ptr()) };
+        if ret < 0 {
+            errno_result()
+        } else {
+            Ok(())
+        }
+    }
+
+    // Returns the size in bytes of the XSAVE area and the ioctl that gets all of it.
+    fn xsave_size(&self) -> Result<(usize, IoctlNr)> {
+        // Safe because we know that our file is a VM fd, and if the cap is invalid KVM assumes
+        // it's an unavailable extension and returns 0. Otherwise KVM_CAP_XSAVE2 returns the size
+        // of the XSAVE area.
+        let size =
+            unsafe { ioctl_with_val(&self.vm, KVM_CHECK_EXTENSION(), KVM_CAP_XSAVE2.into()) };
+        if size > 0 {
+            return Ok((
+                (size as usize).max(size_of::<kvm_xsave>()),
+                KVM_GET_XSAVE2(),
+            ));
+        }
+        // Safe because we know that our file is a VM fd, and if the cap is invalid KVM assumes
+        // it's an unavailable extension and returns 0.
+        let ret = unsafe { ioctl_with_val(&self.vm, KVM_CHECK_EXTENSION(), KVM_CAP_XSAVE.into()) };
+        if ret == 1 {
+            Ok((size_of::<kvm_xsave>(), KVM_GET_XSAVE()))
+        } else {
+            Err(Error::new(ENOTSUP))
+        }
+    }
+
+    /// Makes KVM refuse the guest's use of paravirtual features that are not advertised in the
+    /// KVM_CPUID_FEATURES leaf, so the guest cannot rely on features it was not offered.
+    ///
+    /// The leaf is taken from the CPUID set with `set_cpuid`, so call this afterwards. Returns
+    /// `ENOTSUP` if KVM_CAP_ENFORCE_PV_FEATURE_CPUID is not available.
+    pub fn enforce_pv_feature_cpuid(&self) -> Result<()> {
+        // Safe because we know that our file is a VM fd, and if the cap is invalid KVM assumes
+        // it's an unavailable extension and returns 0.
+        let ret = unsafe {
+            ioctl_with_val(
+                &self.vm,
+                KVM_CHECK_EXTENSION(),
+                KVM_CAP_ENFORCE_PV_FEATURE_CPUID.into(),
+            )
+        };
+        if ret != 1 {
+            return Err(Error::new(ENOTSUP));
+        }
+        let mut cap = kvm_enable_cap {
+            cap: KVM_CAP_ENFORCE_PV_FEATURE_CPUID,
+            ..Default::default()
+        };
+        cap.args[0] = 1;
+        // Safe because we allocated the struct and we know the kernel will read exactly the size
+        // of the struct.
+        let ret = unsafe { ioctl_with_ref(self, KVM_ENABLE_CAP(), &cap) };
+        if ret < 0 {
+            errno_result()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the deadline of the VMX preemption timer of the nested guest this vcpu is running,
+    /// or `None` if it is not running one with the timer armed.
+    ///
+    /// The deadline, in L1 TSC cycles, is part of the nested state that must be saved along with
+    /// the rest of the vcpu state to migrate a guest running nested VMX guests. Returns `ENOTSUP`
+    /// if KVM_CAP_NESTED_STATE is not available.
+    pub fn get_preemption_timer(&self) -> Result<Option<u64>> {
+        let state = self.get_nested_state()?;
+        let nested = state.as_ptr() as *const kvm_nested_state;
+        // Safe because `state` is at least as large as the header of `kvm_nested_state` and
+        // suitably aligned, and the kernel initialized it. The union is only read as `vmx` when
+        // the kernel reports the VMX format.
+        unsafe {
+            if (*nested).format as u32 != KVM_STATE_NESTED_FORMAT_VMX
+                || (*nested).hdr.vmx.flags & KVM_STATE_VMX_PREEMPTION_TIMER_DEADLINE == 0
+            {
+                return Ok(None);
+            }
+            Ok(Some((*nested).hdr.vmx.preemption_timer_deadline))
+        }
+    }
+
+    /// Sets the deadline of the VMX preemption timer of the nested guest this vcpu is running, as
+    /// returned by `get_preemption_timer`.
+    ///
+    /// Returns `EINVAL` if the vcpu is not running a nested VMX guest, and `ENOTSUP` if
+    /// KVM_CAP_NESTED_STATE is not available.
+    pub fn set_preemption_timer(&self, deadline: u64) -> Result<()> {
+        let mut state = self.get_nested_state()?;
+        let nested = state.as_mut_ptr() as *mut kvm_nested_state;
+        // Safe because `state` holds a `kvm_nested_state` initialized by the kernel, which only
+        // reads the `size` bytes it reported.
+        let ret = unsafe {
+            if (*nested).format as u32 != KVM_STATE_NESTED_FORMAT_VMX
+                || (*nested).flags as u32 & KVM_STATE_NESTED_GUEST_MODE == 0
+            {
+                return Err(Error::new(EINVAL));
+            }
+            (*nested).hdr.vmx.flags |= KVM_STATE_VMX_PREEMPTION_TIMER_DEADLINE;
+            (*nested).hdr.vmx.preemption_timer_deadline = deadline;
+            ioctl_with_ptr(self, KVM_SET_NESTED_STATE(), nested)
+        };
+        if ret < 0 {
+            errno_result()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the nested virtualization state of the vcpu in a buffer sized for the largest
+    /// state KVM supports, aligned for `kvm_nested_state`.
+    fn get_nested_state(&self) -> Result<Vec<u64>> {
+        // Safe because we know that our file is a VM fd, and if the cap is invalid KVM assumes
+        // it's an unavailable extension and returns 0. Otherwise it returns the maximum size of
+        // the nested state.
+        let max_size =
+            unsafe { ioctl_with_val(&self.vm, KVM_CHECK_EXTENSION(), KVM_CAP_NESTED_STATE.into()) };
+        if max_size <= 0 {
+            return Err(Error::new(ENOTSUP));
+        }
+        let max_size = (max_size as usize).max(size_of::<kvm_nested_state>());
+        let mut state = vec![0u64; (max_size + 7) / 8];
+        let nested = state.as_mut_ptr() as *mut kvm_nested_state;
+        // Safe because the buffer is `max_size` bytes long, as the `size` field tells the kernel,
+        // and we verify the return result.
+        let ret = unsafe {
+            (*nested).size = max_size as u32;
+            ioctl_with_mut_ptr(self, KVM_GET_NESTED_STATE(), nested)
+        };
+        if ret < 0 {
+            return errno_result();
+        }
+        Ok(state)
+    }
+
+    fn check_tsc_deadline_timer(&self) -> Result<()> {
+        // Safe because we know that our file is a VM fd, and if the cap is invalid KVM assumes
+        // it's an unavailable extension and returns 0.
+        let ret = unsafe {
+            ioctl_with_val(
+                &self.vm,
+                KVM_CHECK_EXTENSION(),
+                KVM_CAP_TSC_DEADLINE_TIMER.into(),
+            )
+        };
+        if ret == 1 {
+            Ok(())
+        } else {
+            Err(Error::new(ENOTSUP))
+        }
+    }
 }
 
 impl<'a> From<&'a KvmCpuId> for CpuId {
@@ -1150,6 +1752,24 @@ pub(super) fn chip_to_kvm_chip(chip: IrqSourceChip) -> u32 {
     }
 }
 
+// Number of GSIs in the routing table KVM installs when the irqchip is created, one per IOAPIC pin.
+const KVM_DEFAULT_IRQ_ROUTES: u32 = 24;
+
+// Returns the routing table KVM installs when the in-kernel irqchip is created. GSIs 0-15 are
+// routed to both a PIC and the IOAPIC, and the remaining GSIs only to the IOAPIC.
+pub(super) fn default_irq_routes() -> Vec<IrqRoute> {
+    let mut routes = Vec::new();
+    for gsi in 0..KVM_DEFAULT_IRQ_ROUTES {
+        if gsi < 8 {
+            routes.push(IrqRoute::pic_irq_route(IrqSourceChip::PicPrimary, gsi));
+        } else if gsi < 16 {
+            routes.push(IrqRoute::pic_irq_route(IrqSourceChip::PicSecondary, gsi));
+        }
+        routes.push(IrqRoute::ioapic_irq_route(gsi));
+    }
+    routes
+}
+
 impl From<&kvm_regs> for Regs {
     fn from(r: &kvm_regs) -> Self {
         Regs {
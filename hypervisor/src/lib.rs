@@ -11,6 +11,8 @@ pub mod caps;
 pub mod haxm;
 #[cfg(unix)]
 pub mod kvm;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
 #[cfg(all(windows, feature = "whpx"))]
 pub mod whpx;
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
@@ -416,6 +418,8 @@ pub enum VcpuExit {
     Shutdown,
     FailEntry {
         hardware_entry_failure_reason: u64,
+        /// The host CPU the failed entry was attempted on.
+        cpu: u32,
     },
     Intr,
     SetTpr,
@@ -465,6 +469,13 @@ pub enum VcpuExit {
     ApicInitSipiTrap,
     /// vcpu stoppted due to bus lock
     BusLock,
+    /// An HVC from the guest that was forwarded to user space, with the SMCCC function id and
+    /// arguments taken from x0-x3.
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    Hvc {
+        function_id: u32,
+        args: [u64; 3],
+    },
 }
 
 /// A hypercall with parameters being made from the guest.
@@ -532,7 +543,7 @@ pub struct ClockState {
 
 /// The MPState represents the state of a processor.
 #[repr(C)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum MPState {
     /// the vcpu is currently running (x86/x86_64,arm/arm64)
     Runnable,
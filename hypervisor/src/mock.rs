@@ -0,0 +1,274 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A `Vcpu` that replays scripted exits, for testing code that handles vcpu exits without a
+//! hypervisor or a running guest.
+
+use std::collections::VecDeque;
+use std::os::raw::c_int;
+use std::sync::Arc;
+
+use base::Error;
+use base::Result;
+use libc::EINVAL;
+use libc::ENOTSUP;
+use sync::Mutex;
+
+use crate::HypervHypercall;
+use crate::IoOperation;
+use crate::IoParams;
+use crate::Vcpu;
+use crate::VcpuExit;
+use crate::VcpuRunHandle;
+
+/// An exit returned by `MockVcpu::run`, with the access to hand to `handle_mmio` or `handle_io`
+/// for `VcpuExit::Mmio` and `VcpuExit::Io` exits.
+#[derive(Clone, Debug)]
+pub struct ScriptedExit {
+    pub exit: VcpuExit,
+    pub io: Option<IoParams>,
+}
+
+impl ScriptedExit {
+    /// An MMIO write of `data` to `address`. Panics if `data` is longer than 8 bytes.
+    pub fn mmio_write(address: u64, data: &[u8]) -> Self {
+        Self::access(VcpuExit::Mmio, address, Some(data), data.len())
+    }
+
+    /// An MMIO read of `size` bytes from `address`. Panics if `size` is more than 8.
+    pub fn mmio_read(address: u64, size: usize) -> Self {
+        Self::access(VcpuExit::Mmio, address, None, size)
+    }
+
+    /// A port write of `data` to `port`. Panics if `data` is longer than 8 bytes.
+    pub fn io_write(port: u64, data: &[u8]) -> Self {
+        Self::access(VcpuExit::Io, port, Some(data), data.len())
+    }
+
+    /// A port read of `size` bytes from `port`. Panics if `size` is more than 8.
+    pub fn io_read(port: u64, size: usize) -> Self {
+        Self::access(VcpuExit::Io, port, None, size)
+    }
+
+    fn access(exit: VcpuExit, address: u64, data: Option<&[u8]>, size: usize) -> Self {
+        assert!(
+            size <= 8,
+            "an MMIO or port access is at most 8 bytes, not {}",
+            size
+        );
+        let operation = match data {
+            Some(data) => {
+                let mut buf = [0u8; 8];
+                buf[..data.len()].copy_from_slice(data);
+                IoOperation::Write { data: buf }
+            }
+            None => IoOperation::Read,
+        };
+        ScriptedExit {
+            exit,
+            io: Some(IoParams {
+                address,
+                size,
+                operation,
+            }),
+        }
+    }
+}
+
+impl From<VcpuExit> for ScriptedExit {
+    fn from(exit: VcpuExit) -> Self {
+        ScriptedExit { exit, io: None }
+    }
+}
+
+#[derive(Default)]
+struct MockVcpuState {
+    script: VecDeque<ScriptedExit>,
+    current: Option<ScriptedExit>,
+    reads: Vec<Option<[u8; 8]>>,
+}
+
+/// A `Vcpu` whose `run` returns a scripted sequence of exits, followed by
+/// `VcpuExit::Shutdown` once the script is exhausted.
+///
+/// `handle_mmio` and `handle_io` pass the access of the current exit to the handler and record
+/// what it returns for reads, which `reads` returns. Clones share the script.
+#[derive(Clone)]
+pub struct MockVcpu {
+    id: usize,
+    state: Arc<Mutex<MockVcpuState>>,
+}
+
+impl MockVcpu {
+    /// Creates a vcpu that replays `script` from `run`.
+    pub fn new(id: usize, script: impl IntoIterator<Item = ScriptedExit>) -> Self {
+        MockVcpu {
+            id,
+            state: Arc::new(Mutex::new(MockVcpuState {
+                script: script.into_iter().collect(),
+                ..Default::default()
+            })),
+        }
+    }
+
+    /// Returns the data the handlers returned for each read handled so far, in order.
+    pub fn reads(&self) -> Vec<Option<[u8; 8]>> {
+        self.state.lock().reads.clone()
+    }
+
+    fn handle_access(
+        &self,
+        kind: fn(&VcpuExit) -> bool,
+        handle_fn: &mut dyn FnMut(IoParams) -> Option<[u8; 8]>,
+    ) -> Result<()> {
+        let params = {
+            let state = self.state.lock();
+            match &state.current {
+                Some(ScriptedExit {
+                    exit,
+                    io: Some(params),
+                }) if kind(exit) => *params,
+                _ => return Err(Error::new(EINVAL)),
+            }
+        };
+        // The handler is called without the lock held so that it may inspect the vcpu.
+        let data = handle_fn(params);
+        if let IoOperation::Read = params.operation {
+            self.state.lock().reads.push(data);
+        }
+        Ok(())
+    }
+}
+
+extern "C" fn mock_immediate_exit() {}
+
+impl Vcpu for MockVcpu {
+    fn try_clone(&self) -> Result<Self> {
+        Ok(self.clone())
+    }
+
+    fn as_vcpu(&self) -> &dyn Vcpu {
+        self
+    }
+
+    fn take_run_handle(&self, _signal_num: Option<c_int>) -> Result<VcpuRunHandle> {
+        Ok(VcpuRunHandle::new(|| {}))
+    }
+
+    fn run(&mut self, _run_handle: &VcpuRunHandle) -> Result<VcpuExit> {
+        let mut state = self.state.lock();
+        state.current = state.script.pop_front();
+        Ok(state
+            .current
+            .as_ref()
+            .map_or(VcpuExit::Shutdown, |scripted| scripted.exit))
+    }
+
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn set_immediate_exit(&self, _exit: bool) {}
+
+    fn set_local_immediate_exit(_exit: bool) {}
+
+    fn set_local_immediate_exit_fn(&self) -> extern "C" fn() {
+        mock_immediate_exit
+    }
+
+    fn handle_mmio(&self, handle_fn: &mut dyn FnMut(IoParams) -> Option<[u8; 8]>) -> Result<()> {
+        self.handle_access(|exit| matches!(exit, VcpuExit::Mmio), handle_fn)
+    }
+
+    fn handle_io(&self, handle_fn: &mut dyn FnMut(IoParams) -> Option<[u8; 8]>) -> Result<()> {
+        self.handle_access(|exit| matches!(exit, VcpuExit::Io), handle_fn)
+    }
+
+    fn handle_hyperv_hypercall(&self, _func: &mut dyn FnMut(HypervHypercall) -> u64) -> Result<()> {
+        Err(Error::new(ENOTSUP))
+    }
+
+    fn handle_rdmsr(&self, _data: u64) -> Result<()> {
+        Ok(())
+    }
+
+    fn handle_wrmsr(&self) {}
+
+    fn pvclock_ctrl(&self) -> Result<()> {
+        Err(Error::new(ENOTSUP))
+    }
+
+    fn set_signal_mask(&self, _signals: &[c_int]) -> Result<()> {
+        Ok(())
+    }
+
+    unsafe fn enable_raw_capability(&self, _cap: u32, _args: &[u64; 4]) -> Result<()> {
+        Err(Error::new(ENOTSUP))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A device with a single 32-bit register at `BASE`, driven by a vcpu run loop.
+    const BASE: u64 = 0x1000;
+
+    fn run_device(vcpu: &mut dyn Vcpu, register: &mut u32) {
+        let handle = vcpu.take_run_handle(None).unwrap();
+        loop {
+            match vcpu.run(&handle).unwrap() {
+                VcpuExit::Mmio => vcpu
+                    .handle_mmio(&mut |params| {
+                        assert_eq!(params.address, BASE);
+                        match params.operation {
+                            IoOperation::Write { data } => {
+                                *register = u32::from_le_bytes(data[..4].try_into().unwrap());
+                                None
+                            }
+                            IoOperation::Read => {
+                                let mut data = [0u8; 8];
+                                data[..4].copy_from_slice(&register.to_le_bytes());
+                                Some(data)
+                            }
+                        }
+                    })
+                    .unwrap(),
+                VcpuExit::Shutdown => break,
+                exit => panic!("unexpected exit {:?}", exit),
+            }
+        }
+    }
+
+    #[test]
+    fn mmio_write_reaches_device() {
+        let mut vcpu = MockVcpu::new(
+            0,
+            [
+                ScriptedExit::mmio_write(BASE, &0x1234_5678u32.to_le_bytes()),
+                ScriptedExit::mmio_read(BASE, 4),
+            ],
+        );
+        let mut register = 0;
+        run_device(&mut vcpu, &mut register);
+
+        assert_eq!(register, 0x1234_5678);
+        assert_eq!(vcpu.reads(), [Some([0x78, 0x56, 0x34, 0x12, 0, 0, 0, 0])]);
+    }
+
+    #[test]
+    #[should_panic(expected = "at most 8 bytes")]
+    fn oversized_write() {
+        ScriptedExit::io_write(0x3f8, &[0; 9]);
+    }
+
+    #[test]
+    fn handle_without_access() {
+        let mut vcpu = MockVcpu::new(0, [ScriptedExit::from(VcpuExit::Hlt)]);
+        let handle = vcpu.take_run_handle(None).unwrap();
+        assert!(matches!(vcpu.run(&handle).unwrap(), VcpuExit::Hlt));
+        assert!(vcpu.handle_mmio(&mut |_| None).is_err());
+        assert!(matches!(vcpu.run(&handle).unwrap(), VcpuExit::Shutdown));
+    }
+}
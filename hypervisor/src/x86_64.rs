@@ -13,7 +13,9 @@ use base::Result;
 use bit_field::*;
 use downcast_rs::impl_downcast;
 use serde::Deserialize;
+use serde::Deserializer;
 use serde::Serialize;
+use serde::Serializer;
 use vm_memory::GuestAddress;
 
 use crate::Hypervisor;
@@ -28,7 +30,8 @@ pub trait HypervisorX86_64: Hypervisor {
     /// Get the system supported CPUID values.
     fn get_supported_cpuid(&self) -> Result<CpuId>;
 
-    /// Get the system emulated CPUID values.
+    /// Get the system emulated CPUID values, i.e. the features the hypervisor can emulate in
+    /// software on top of the supported ones. Empty if the hypervisor can't report them.
     fn get_emulated_cpuid(&self) -> Result<CpuId>;
 
     /// Gets the list of supported MSRs.
@@ -127,6 +130,10 @@ impl_downcast!(VcpuX86_64);
 
 // TSC MSR
 pub const MSR_IA32_TSC: u32 = 0x00000010;
+// TSC deadline MSR
+pub const MSR_IA32_TSC_DEADLINE: u32 = 0x000006e0;
+// Supervisor state components enabled for XSAVES
+pub const MSR_IA32_XSS: u32 = 0x00000da0;
 
 /// Implementation of get_tsc_offset that uses VcpuX86_64::get_msrs.
 #[cfg(any(unix, feature = "haxm", feature = "whpx"))]
@@ -480,6 +487,24 @@ impl PartialEq for LapicState {
 // Lapic equality is reflexive, so we impl Eq
 impl Eq for LapicState {}
 
+// rust arrays longer than 32 need custom implementations of Serialize
+impl Serialize for LapicState {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        self.regs[..].serialize(serializer)
+    }
+}
+
+// rust arrays longer than 32 need custom implementations of Deserialize
+impl<'de> Deserialize<'de> for LapicState {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let regs: Vec<LapicRegister> = Vec::deserialize(deserializer)?;
+        let regs = regs
+            .try_into()
+            .map_err(|_| <D::Error as serde::de::Error>::custom("expected 64 lapic registers"))?;
+        Ok(LapicState { regs })
+    }
+}
+
 /// The PitState represents the state of the PIT (aka the Programmable Interval Timer).
 /// The state is simply the state of it's three channels.
 #[repr(C)]
@@ -609,7 +634,7 @@ impl IrqRoute {
 
 /// State of a VCPU's general purpose registers.
 #[repr(C)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Regs {
     pub rax: u64,
     pub rbx: u64,
@@ -658,7 +683,7 @@ impl Default for Regs {
 
 /// State of a memory segment.
 #[repr(C)]
-#[derive(Debug, Default, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Segment {
     pub base: u64,
     pub limit: u32,
@@ -675,7 +700,7 @@ pub struct Segment {
 
 /// State of a global descriptor table or interrupt descriptor table.
 #[repr(C)]
-#[derive(Debug, Default, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct DescriptorTable {
     pub base: u64,
     pub limit: u16,
@@ -683,7 +708,7 @@ pub struct DescriptorTable {
 
 /// State of a VCPU's special registers.
 #[repr(C)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Sregs {
     pub cs: Segment,
     pub ds: Segment,
@@ -800,7 +825,7 @@ impl Default for Sregs {
 
 /// State of a VCPU's floating point unit.
 #[repr(C)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Fpu {
     pub fpr: [[u8; 16usize]; 8usize],
     pub fcw: u16,
@@ -831,7 +856,7 @@ impl Default for Fpu {
 
 /// State of a VCPU's debug registers.
 #[repr(C)]
-#[derive(Debug, Default, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct DebugRegs {
     pub db: [u64; 4usize],
     pub dr6: u64,
@@ -839,7 +864,7 @@ pub struct DebugRegs {
 }
 
 /// State of one VCPU register.  Currently used for MSRs and XCRs.
-#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Register {
     pub id: u32,
     pub value: u64,
@@ -139,3 +139,159 @@ where
         vcpu_regs.rbx
     );
 }
+
+#[test]
+#[cfg(unix)]
+fn test_kvm_iter_dirty_pages() {
+    use hypervisor::kvm::*;
+
+    /*
+    0000  881C mov [si],bl
+    0002  881D mov [di],bl
+    0004  F4   hlt
+    */
+    let code = [0x88, 0x1c, 0x88, 0x1d, 0xf4];
+    let mem_size = 0x10000;
+    let load_addr = GuestAddress(0x1000);
+    let guest_mem = GuestMemory::new(&[(GuestAddress(0x20000), 0x1000)]).unwrap();
+    let mem = SharedMemory::new("test", mem_size).expect("failed to create shared memory");
+    let mmap = MemoryMappingBuilder::new(mem_size as usize)
+        .from_shared_memory(&mem)
+        .build()
+        .expect("failed to create memory mapping");
+
+    mmap.write_slice(&code[..], load_addr.offset() as usize)
+        .expect("Writing code to memory failed.");
+
+    let kvm = Kvm::new().expect("failed to create kvm");
+    let mut vm = KvmVm::new(&kvm, guest_mem, Default::default()).expect("failed to create vm");
+    let mut vcpu = vm.create_vcpu(0).expect("new vcpu failed");
+    let mut vcpu_sregs = vcpu.get_sregs().expect("get sregs failed");
+    vcpu_sregs.cs.base = 0;
+    vcpu_sregs.cs.selector = 0;
+    vcpu.set_sregs(&vcpu_sregs).expect("set sregs failed");
+
+    let vcpu_regs = Regs {
+        rip: load_addr.offset() as u64,
+        rflags: 2,
+        // Write 0x12 to the 9th and 11th pages.
+        rsi: 0x8000,
+        rdi: 0xa010,
+        rbx: 0x12,
+        ..Default::default()
+    };
+    vcpu.set_regs(&vcpu_regs).expect("set regs failed");
+    let slot = vm
+        .add_memory_region(
+            GuestAddress(0),
+            Box::new(
+                MemoryMappingBuilder::new(mem_size as usize)
+                    .from_shared_memory(&mem)
+                    .build()
+                    .expect("failed to create memory mapping"),
+            ),
+            false,
+            true,
+        )
+        .expect("failed to register memory");
+
+    let run_handle = vcpu.take_run_handle(None).unwrap();
+    loop {
+        match vcpu.run(&run_handle).expect("run failed") {
+            // Continue on external interrupt or signal
+            VcpuExit::Intr => continue,
+            VcpuExit::Hlt => break,
+            r => panic!("unexpected exit reason: {:?}", r),
+        }
+    }
+
+    let dirty_pages = vm
+        .iter_dirty_pages(slot)
+        .expect("failed to iterate dirty pages");
+    let dirty: Vec<(GuestAddress, Vec<u8>)> = dirty_pages
+        .iter()
+        .map(|(addr, data)| {
+            let mut buf = vec![0u8; data.size()];
+            data.copy_to(&mut buf);
+            (addr, buf)
+        })
+        .collect();
+    let addrs: Vec<GuestAddress> = dirty.iter().map(|(addr, _)| *addr).collect();
+    assert_eq!(addrs, vec![GuestAddress(0x8000), GuestAddress(0xa000)]);
+    assert_eq!(dirty[0].1[0], 0x12);
+    assert_eq!(dirty[1].1[0x10], 0x12);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_kvm_verify_dirty_log_matches() {
+    use hypervisor::kvm::*;
+
+    /*
+    0000  881C mov [si],bl
+    0002  F4   hlt
+    */
+    let code = [0x88, 0x1c, 0xf4];
+    let mem_size = 0x10000;
+    let load_addr = GuestAddress(0x1000);
+    let guest_mem = GuestMemory::new(&[(GuestAddress(0x20000), 0x1000)]).unwrap();
+    let mem = SharedMemory::new("test", mem_size).expect("failed to create shared memory");
+    let mmap = MemoryMappingBuilder::new(mem_size as usize)
+        .from_shared_memory(&mem)
+        .build()
+        .expect("failed to create memory mapping");
+
+    mmap.write_slice(&code[..], load_addr.offset() as usize)
+        .expect("Writing code to memory failed.");
+
+    let kvm = Kvm::new().expect("failed to create kvm");
+    let mut vm = KvmVm::new(&kvm, guest_mem, Default::default()).expect("failed to create vm");
+    let mut vcpu = vm.create_vcpu(0).expect("new vcpu failed");
+    let mut vcpu_sregs = vcpu.get_sregs().expect("get sregs failed");
+    vcpu_sregs.cs.base = 0;
+    vcpu_sregs.cs.selector = 0;
+    vcpu.set_sregs(&vcpu_sregs).expect("set sregs failed");
+
+    let vcpu_regs = Regs {
+        rip: load_addr.offset() as u64,
+        rflags: 2,
+        // Write 0x12 to the 9th page.
+        rsi: 0x8000,
+        rbx: 0x12,
+        ..Default::default()
+    };
+    vcpu.set_regs(&vcpu_regs).expect("set regs failed");
+    let slot = vm
+        .add_memory_region(
+            GuestAddress(0),
+            Box::new(
+                MemoryMappingBuilder::new(mem_size as usize)
+                    .from_shared_memory(&mem)
+                    .build()
+                    .expect("failed to create memory mapping"),
+            ),
+            false,
+            true,
+        )
+        .expect("failed to register memory");
+
+    let mut reference = vec![0u8; mem_size as usize];
+    mmap.read_slice(&mut reference, 0)
+        .expect("failed to read memory");
+
+    let run_handle = vcpu.take_run_handle(None).unwrap();
+    loop {
+        match vcpu.run(&run_handle).expect("run failed") {
+            // Continue on external interrupt or signal
+            VcpuExit::Intr => continue,
+            VcpuExit::Hlt => break,
+            r => panic!("unexpected exit reason: {:?}", r),
+        }
+    }
+
+    let mismatches = vm
+        .verify_dirty_log_matches(slot, &reference)
+        .expect("failed to verify dirty log");
+    assert!(mismatches.is_empty(), "{:?}", mismatches);
+    assert!(vm.verify_dirty_log_matches(slot, &reference[1..]).is_err());
+}
@@ -5,8 +5,11 @@
 // Copyright 2022 The ChromiumOS Authors
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
+use base::AsRawDescriptor;
 use hypervisor::kvm::*;
 use hypervisor::*;
+use kvm_sys::KVM_REG_SIZE_MASK;
+use kvm_sys::KVM_REG_SIZE_U32;
 use vm_memory::GuestAddress;
 use vm_memory::GuestMemory;
 
@@ -51,3 +54,190 @@ fn set_gsi_routing() {
     ])
     .unwrap();
 }
+
+#[test]
+fn get_irq_route() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    vm.create_irq_chip().unwrap();
+    let routes = [
+        IrqRoute {
+            gsi: 1,
+            source: IrqSource::Irqchip {
+                chip: IrqSourceChip::Gic,
+                pin: 3,
+            },
+        },
+        IrqRoute {
+            gsi: 2,
+            source: IrqSource::Msi {
+                address: 0xf000000,
+                data: 0xa0,
+            },
+        },
+    ];
+    vm.set_gsi_routing(&routes).unwrap();
+    assert_eq!(
+        vm.get_irq_route(1).map(|r| r.source),
+        Some(IrqSource::Irqchip {
+            chip: IrqSourceChip::Gic,
+            pin: 3,
+        })
+    );
+    assert_eq!(vm.get_irq_route(3), None);
+    assert_eq!(vm.current_routes(), routes);
+}
+
+#[test]
+fn add_remove_irq_route() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    vm.create_irq_chip().unwrap();
+    vm.set_gsi_routing(&[]).unwrap();
+    vm.add_irq_route(IrqRoute {
+        gsi: 1,
+        source: IrqSource::Irqchip {
+            chip: IrqSourceChip::Gic,
+            pin: 3,
+        },
+    })
+    .unwrap();
+    vm.add_irq_route(IrqRoute {
+        gsi: 2,
+        source: IrqSource::Msi {
+            address: 0xf000000,
+            data: 0xa0,
+        },
+    })
+    .unwrap();
+    assert_eq!(vm.current_routes().len(), 2);
+    assert!(vm.get_irq_route(1).is_some());
+    assert!(vm.get_irq_route(2).is_some());
+
+    // Adding a route for an existing GSI replaces it.
+    vm.add_irq_route(IrqRoute {
+        gsi: 2,
+        source: IrqSource::Msi {
+            address: 0xf000000,
+            data: 0xa1,
+        },
+    })
+    .unwrap();
+    assert_eq!(vm.current_routes().len(), 2);
+    assert_eq!(
+        vm.get_irq_route(2).map(|r| r.source),
+        Some(IrqSource::Msi {
+            address: 0xf000000,
+            data: 0xa1,
+        })
+    );
+
+    vm.remove_irq_route(1).unwrap();
+    assert_eq!(vm.get_irq_route(1), None);
+    assert!(vm.get_irq_route(2).is_some());
+    assert!(vm.remove_irq_route(1).is_err());
+}
+
+#[test]
+fn vgic_nr_irqs_reseeds_routes() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    let vgic = match vm
+        .create_device_typed(DeviceKind::ArmVgicV3)
+        .or_else(|_| vm.create_device_typed(DeviceKind::ArmVgicV2))
+        .unwrap()
+    {
+        TypedKvmDevice::ArmVgic(vgic) => vgic,
+        _ => panic!("expected a vGIC"),
+    };
+    // 256 interrupts by default, less the 32 SGIs and PPIs.
+    assert_eq!(vm.current_routes().len(), 224);
+    vgic.set_nr_irqs(64).unwrap();
+    assert_eq!(vm.current_routes().len(), 32);
+    assert_eq!(vm.get_irq_route(31), Some(IrqRoute::gic_irq_route(31)));
+    assert_eq!(vm.get_irq_route(32), None);
+}
+
+#[test]
+fn core_regs() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    let vcpu = vm.create_kvm_vcpu(0).unwrap();
+    vcpu.init(&[]).unwrap();
+    vcpu.set_one_reg(VcpuRegAArch64::Pc, 0x1234).unwrap();
+    let regs = vcpu.core_regs().unwrap();
+    assert_eq!(regs.len(), 36);
+    assert!(regs.contains(&(Aarch64CoreReg::Pc, 0x1234)));
+}
+
+#[test]
+fn setup_boot_regs() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    let vcpu = vm.create_kvm_vcpu(0).unwrap();
+    vcpu.init(&[]).unwrap();
+    vcpu.setup_boot_regs(0x8_0000, 0xf000).unwrap();
+    assert_eq!(vcpu.get_one_reg(VcpuRegAArch64::Pc).unwrap(), 0x8_0000);
+    assert_eq!(vcpu.get_one_reg(VcpuRegAArch64::X(0)).unwrap(), 0xf000);
+    assert_eq!(vcpu.get_one_reg(VcpuRegAArch64::Pstate).unwrap(), 0x3c5);
+}
+
+#[test]
+fn one_regs_batch() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    let vcpu = vm.create_kvm_vcpu(0).unwrap();
+    vcpu.init(&[]).unwrap();
+    // X0-X30 and SP, repeated to make a batch of 50.
+    let ids: Vec<u64> = Aarch64CoreReg::all()
+        .filter(|reg| !matches!(reg, Aarch64CoreReg::Pstate))
+        .cycle()
+        .take(50)
+        .map(|reg| u64::from(KvmVcpuRegister::from(reg)))
+        .collect();
+    let regs: Vec<(u64, u64)> = ids
+        .iter()
+        .enumerate()
+        .map(|(i, &id)| (id, 0x1000 + i as u64 * 8))
+        .collect();
+    vcpu.set_one_regs(&regs).unwrap();
+    let values = vcpu.get_one_regs(&ids).unwrap();
+    assert_eq!(values.len(), 50);
+    // Later writes to a repeated register win.
+    for (i, &id) in ids.iter().enumerate() {
+        let (_, expected) = regs.iter().rev().find(|(r, _)| *r == id).unwrap();
+        assert_eq!(values[i], *expected);
+    }
+    // A 32-bit register id is rejected before any ioctl is issued.
+    let id32 = (ids[0] & !KVM_REG_SIZE_MASK) | KVM_REG_SIZE_U32;
+    assert!(vcpu.get_one_regs(&[id32]).is_err());
+}
+
+#[test]
+fn enable_arm_user_irq() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    match vm.enable_arm_user_irq() {
+        Ok(lines) => assert!(lines > 0),
+        Err(e) => assert_eq!(e.errno(), libc::ENOTSUP),
+    }
+}
+
+#[test]
+fn create_gic_v2() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    // Hosts with a GICv3 that lacks the GICv2 compatibility interface cannot create a GICv2.
+    match vm.create_gic_v2(0x3fff_0000, 0x3ffd_0000) {
+        Ok(gic) => assert!(gic.as_raw_descriptor() >= 0),
+        Err(e) => assert_eq!(e.errno(), libc::ENODEV),
+    }
+}
@@ -10,21 +10,35 @@ mod aarch64;
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 mod x86_64;
 
+use std::os::unix::io::AsRawFd;
 use std::thread;
+use std::time::Duration;
 
 use base::pagesize;
+use base::AsRawDescriptor;
+use base::Descriptor;
 use base::Event;
 use base::FromRawDescriptor;
 use base::MappedRegion;
 use base::MemoryMappingArena;
 use base::MemoryMappingBuilder;
+use base::Protection;
+use base::RawDescriptor;
+use base::SharedMemory;
 use hypervisor::kvm::dirty_log_bitmap_size;
+use hypervisor::kvm::read_memory_snapshot;
+use hypervisor::kvm::IrqfdConfig;
 use hypervisor::kvm::Kvm;
 use hypervisor::kvm::KvmVm;
+use hypervisor::kvm::MemRegionDescriptor;
+use hypervisor::kvm::TypedKvmDevice;
 use hypervisor::Datamatch;
+use hypervisor::DeviceKind;
 use hypervisor::Hypervisor;
 use hypervisor::HypervisorCap;
 use hypervisor::IoEventAddress;
+use hypervisor::IrqRoute;
+use hypervisor::IrqSource;
 use hypervisor::Vm;
 #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
 use hypervisor::VmAArch64;
@@ -56,6 +70,25 @@ fn check_capability() {
     assert!(!kvm.check_capability(HypervisorCap::S390UserSigp));
 }
 
+#[test]
+fn max_vcpus() {
+    let kvm = Kvm::new().unwrap();
+    let max_vcpus = kvm.max_vcpus();
+    let max_vcpu_id = kvm.max_vcpu_id();
+    assert!(max_vcpus > 0);
+    assert!(max_vcpu_id > 0);
+    assert!(max_vcpu_id >= max_vcpus);
+}
+
+#[test]
+fn create_vcpu_id_too_large() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    let err = vm.create_kvm_vcpu(kvm.max_vcpu_id()).unwrap_err();
+    assert_eq!(err.errno(), libc::EINVAL);
+}
+
 #[test]
 fn create_vm() {
     let kvm = Kvm::new().unwrap();
@@ -127,6 +160,95 @@ fn add_memory() {
         .unwrap();
 }
 
+#[test]
+fn is_range_backed() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+    let mut vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    let mem_size = 0x1000;
+    for addr in [0x10000, 0x11000, 0x13000] {
+        let mem = MemoryMappingBuilder::new(mem_size).build().unwrap();
+        vm.add_memory_region(GuestAddress(addr), Box::new(mem), false, false)
+            .unwrap();
+    }
+
+    assert!(vm.is_range_backed(GuestAddress(0x800), 0x800));
+    assert!(!vm.is_range_backed(GuestAddress(0x800), 0x801));
+    // Straddles the two adjacent regions at 0x10000 and 0x11000.
+    assert!(vm.is_range_backed(GuestAddress(0x10800), 0x1000));
+    assert!(vm.is_range_backed(GuestAddress(0x10000), 0x2000));
+    // Runs into the gap between 0x12000 and 0x13000.
+    assert!(!vm.is_range_backed(GuestAddress(0x11800), 0x2000));
+    assert!(!vm.is_range_backed(GuestAddress(0x12000), 0x100));
+    assert!(!vm.is_range_backed(GuestAddress(0x10000), 0));
+}
+
+#[test]
+fn capability_report() {
+    let kvm = Kvm::new().unwrap();
+    let report = kvm.capability_report();
+    assert_eq!(report.get("USER_MEMORY"), Some(&true));
+    assert!(report.contains_key("MANUAL_DIRTY_LOG_PROTECT2"));
+}
+
+#[test]
+fn set_halt_poll_ns() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    if vm.check_raw_capability(Cap::HaltPoll) {
+        vm.set_halt_poll_ns(200_000).unwrap();
+        vm.set_halt_poll_ns(0).unwrap();
+    } else {
+        assert_eq!(
+            vm.set_halt_poll_ns(200_000).unwrap_err().errno(),
+            libc::ENOTSUP
+        );
+    }
+}
+
+#[test]
+fn memory_snapshot() {
+    let kvm = Kvm::new().unwrap();
+    let page_size = pagesize() as u64;
+    let regions = [
+        (GuestAddress(0), 16 * page_size),
+        (GuestAddress(0x10000000), 16 * page_size),
+    ];
+    let gm = GuestMemory::new(&regions).unwrap();
+    let written = [
+        (GuestAddress(0), 0x11u8),
+        (GuestAddress(3 * page_size), 0x22),
+        (GuestAddress(0x10000000 + 4 * page_size), 0x33),
+        (GuestAddress(0x10000000 + 5 * page_size), 0x44),
+    ];
+    for (addr, value) in written {
+        gm.write_all_at_addr(&vec![value; page_size as usize], addr)
+            .unwrap();
+    }
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+
+    let mut snapshot = Vec::new();
+    vm.write_memory_snapshot(&mut snapshot).unwrap();
+    // Zero pages are skipped.
+    assert!(snapshot.len() < 5 * page_size as usize);
+
+    let restored = GuestMemory::new(&regions).unwrap();
+    read_memory_snapshot(&restored, &mut snapshot.as_slice()).unwrap();
+    for (addr, size) in regions {
+        let mut contents = vec![0u8; size as usize];
+        restored.read_exact_at_addr(&mut contents, addr).unwrap();
+        for (page_index, page) in contents.chunks(page_size as usize).enumerate() {
+            let page_addr = addr.unchecked_add(page_index as u64 * page_size);
+            let expected = written
+                .iter()
+                .find(|(written_addr, _)| *written_addr == page_addr)
+                .map_or(0, |(_, value)| *value);
+            assert!(page.iter().all(|&b| b == expected), "{}", page_addr);
+        }
+    }
+}
+
 #[test]
 fn add_memory_ro() {
     let kvm = Kvm::new().unwrap();
@@ -138,6 +260,288 @@ fn add_memory_ro() {
         .unwrap();
 }
 
+#[test]
+fn add_memory_ro_unsupported() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+    let mut vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    let mem_size = 0x1000;
+    let mem = MemoryMappingBuilder::new(mem_size).build().unwrap();
+    let res = vm.add_memory_region(GuestAddress(0x1000), Box::new(mem), true, false);
+    if vm.check_raw_capability(Cap::ReadonlyMem) {
+        res.unwrap();
+    } else {
+        assert_eq!(res.unwrap_err().errno(), libc::ENOTSUP);
+    }
+}
+
+#[test]
+fn host_page_size() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    let page_size = vm.host_page_size();
+    assert!(page_size.is_power_of_two());
+    assert!(page_size >= 4096);
+    assert_eq!(page_size, pagesize());
+}
+
+#[test]
+fn add_memory_region_tracked() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+    let mut vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    let mem_size = pagesize() * 20;
+    let mem = MemoryMappingBuilder::new(mem_size).build().unwrap();
+    let (slot, mut dirty_log) = vm
+        .add_memory_region_tracked(GuestAddress(0x10000), Box::new(mem), false)
+        .unwrap();
+    assert_eq!(dirty_log.len(), dirty_log_bitmap_size(mem_size));
+    vm.get_dirty_log(slot, &mut dirty_log).unwrap();
+}
+
+#[test]
+fn memory_layout() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+    let mut vm = KvmVm::new(&kvm, gm.clone(), Default::default()).unwrap();
+    let mem_size = 0x1000;
+    let mem = MemoryMappingBuilder::new(mem_size).build().unwrap();
+    let ro_slot = vm
+        .add_memory_region(GuestAddress(0x1000), Box::new(mem), true, false)
+        .unwrap();
+    let mem = MemoryMappingBuilder::new(mem_size).build().unwrap();
+    let dirty_slot = vm
+        .add_memory_region(GuestAddress(0x10000), Box::new(mem), false, true)
+        .unwrap();
+
+    let layout = vm.memory_layout();
+    assert_eq!(layout.len(), 3);
+    assert!(layout.contains(&MemRegionDescriptor {
+        slot: ro_slot,
+        guest_addr: GuestAddress(0x1000),
+        size: mem_size as u64,
+        read_only: true,
+        log_dirty_pages: false,
+    }));
+    assert!(layout.contains(&MemRegionDescriptor {
+        slot: dirty_slot,
+        guest_addr: GuestAddress(0x10000),
+        size: mem_size as u64,
+        read_only: false,
+        log_dirty_pages: true,
+    }));
+
+    let mut restored = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    restored
+        .restore_memory_layout(&layout, |desc| {
+            Ok(Box::new(
+                MemoryMappingBuilder::new(desc.size as usize)
+                    .build()
+                    .unwrap(),
+            ))
+        })
+        .unwrap();
+    assert_eq!(restored.memory_layout(), layout);
+}
+
+#[test]
+fn iter_regions() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+    let mut vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    let mut add = |addr, size, read_only| {
+        let mem = MemoryMappingBuilder::new(size).build().unwrap();
+        vm.add_memory_region(GuestAddress(addr), Box::new(mem), read_only, false)
+            .unwrap()
+    };
+    let slot_high = add(0x30000, 0x1000, false);
+    let slot_low = add(0x10000, 0x2000, true);
+    let slot_mid = add(0x20000, 0x1000, false);
+
+    assert_eq!(
+        vm.iter_regions(),
+        vec![
+            (slot_low, GuestAddress(0x10000), 0x2000, true),
+            (slot_mid, GuestAddress(0x20000), 0x1000, false),
+            (slot_high, GuestAddress(0x30000), 0x1000, false),
+        ]
+    );
+}
+
+#[test]
+fn prefault_region() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+    let mut vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    let num_pages = 16;
+    let mem_size = num_pages * pagesize();
+    let mem = MemoryMappingBuilder::new(mem_size).build().unwrap();
+    let host_addr = mem.as_ptr();
+    let slot = vm
+        .add_memory_region(GuestAddress(0x1000), Box::new(mem), false, false)
+        .unwrap();
+
+    let resident_pages = || {
+        let mut vec = vec![0u8; num_pages];
+        // Safe because `vec` has one byte per page of the mapping, which is still owned by `vm`.
+        let ret =
+            unsafe { libc::mincore(host_addr as *mut libc::c_void, mem_size, vec.as_mut_ptr()) };
+        assert_eq!(ret, 0);
+        vec.iter().filter(|&&v| v & 1 != 0).count()
+    };
+    assert_eq!(resident_pages(), 0);
+    vm.prefault_region(slot).unwrap();
+    assert_eq!(resident_pages(), num_pages);
+
+    assert_eq!(
+        vm.prefault_region(slot + 1).unwrap_err().errno(),
+        libc::ENOENT
+    );
+
+    // A read-only region is populated without writing to its read-only host mapping.
+    if vm.check_raw_capability(Cap::ReadonlyMem) {
+        let mem = MemoryMappingBuilder::new(mem_size)
+            .protection(Protection::read())
+            .build()
+            .unwrap();
+        let slot = vm
+            .add_memory_region(GuestAddress(0x100000), Box::new(mem), true, false)
+            .unwrap();
+        vm.prefault_region(slot).unwrap();
+    }
+}
+
+#[test]
+fn register_userfaultfd() {
+    use userfaultfd::Event as UffdEvent;
+    use userfaultfd::UffdBuilder;
+
+    let uffd = match UffdBuilder::new()
+        .close_on_exec(true)
+        .non_blocking(true)
+        .user_mode_only(true)
+        .create()
+    {
+        Ok(uffd) => uffd,
+        // userfaultfd is not available to this process.
+        Err(_) => return,
+    };
+    let uffd_descriptor = Descriptor(uffd.as_raw_fd());
+
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+    let mut vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    let mem_size = pagesize();
+    let mem = MemoryMappingBuilder::new(mem_size).build().unwrap();
+    let host_addr = mem.as_ptr() as usize;
+    let slot = vm
+        .add_memory_region(GuestAddress(0x1000), Box::new(mem), false, false)
+        .unwrap();
+    vm.register_userfaultfd(slot, &uffd_descriptor).unwrap();
+
+    // The first access to the page blocks until the fault is resolved.
+    let reader = thread::spawn(move || {
+        // Safe because the page stays mapped by `vm` until the thread is joined.
+        unsafe { std::ptr::read_volatile(host_addr as *const u8) }
+    });
+    let fault_addr = loop {
+        match uffd.read_event().unwrap() {
+            Some(UffdEvent::Pagefault { addr, .. }) => break addr as usize,
+            Some(_) => {}
+            None => thread::sleep(Duration::from_millis(1)),
+        }
+    };
+    assert_eq!(fault_addr, host_addr);
+    // Safe because the page is the unpopulated region page that faulted.
+    unsafe {
+        uffd.zeropage(fault_addr as *mut libc::c_void, mem_size, true)
+            .unwrap()
+    };
+    assert_eq!(reader.join().unwrap(), 0);
+
+    vm.deregister_userfaultfd(slot, &uffd_descriptor).unwrap();
+    assert_eq!(
+        vm.register_userfaultfd(slot + 1, &uffd_descriptor)
+            .unwrap_err()
+            .errno(),
+        libc::ENOENT
+    );
+}
+
+#[test]
+fn add_private_memory_region() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+    let mut vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    // Only hosts without guest_memfd support are covered.
+    if vm.check_raw_capability(Cap::UserMemory2) && vm.check_raw_capability(Cap::GuestMemfd) {
+        return;
+    }
+    let mem = MemoryMappingBuilder::new(pagesize()).build().unwrap();
+    let gmem = SharedMemory::new("gmem", pagesize() as u64).unwrap();
+    assert_eq!(
+        vm.add_private_memory_region(GuestAddress(0x1000), Box::new(mem), &gmem, 0)
+            .unwrap_err()
+            .errno(),
+        libc::ENOTSUP
+    );
+}
+
+#[test]
+fn enable_dirty_log_all() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let mut vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    let mem_size = 0x4000;
+    let mem = MemoryMappingBuilder::new(mem_size).build().unwrap();
+    let slot = vm
+        .add_memory_region(GuestAddress(0x10000), Box::new(mem), false, false)
+        .unwrap();
+    let mut dirty_log = vec![0u8; dirty_log_bitmap_size(mem_size)];
+    // KVM refuses to report a dirty log for a slot that isn't logging.
+    assert!(vm.get_dirty_log(slot, &mut dirty_log).is_err());
+    vm.enable_dirty_log_all().unwrap();
+    vm.get_dirty_log(slot, &mut dirty_log).unwrap();
+    vm.disable_dirty_log_all().unwrap();
+    assert!(vm.get_dirty_log(slot, &mut dirty_log).is_err());
+}
+
+#[test]
+fn add_fd_mapping() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let mut vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    let page_size = pagesize();
+    let shm = SharedMemory::new("test", 2 * page_size as u64).unwrap();
+    let mmap = MemoryMappingBuilder::new(2 * page_size)
+        .from_shared_memory(&shm)
+        .build()
+        .unwrap();
+    mmap.write_obj(0x1234_5678u32, page_size).unwrap();
+
+    assert_eq!(
+        vm.add_fd_mapping(GuestAddress(0x10000), &shm, 0x10, page_size, false)
+            .unwrap_err()
+            .errno(),
+        libc::EINVAL
+    );
+    let slot = vm
+        .add_fd_mapping(
+            GuestAddress(0x10000),
+            &shm,
+            page_size as u64,
+            page_size,
+            false,
+        )
+        .unwrap();
+    let region = vm.remove_memory_region(slot).unwrap();
+    assert_eq!(region.size(), page_size);
+    // Safe because the region is mapped for at least a page and still owned by `region`.
+    let value = unsafe { std::ptr::read_volatile(region.as_ptr() as *const u32) };
+    assert_eq!(value, 0x1234_5678);
+}
+
 #[test]
 fn remove_memory() {
     let kvm = Kvm::new().unwrap();
@@ -237,6 +641,164 @@ fn irqfd_resample() {
         .unwrap_err();
 }
 
+#[test]
+fn irqfd_resample_reregister() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    let evt = Event::new().unwrap();
+    let resample_evt = Event::new().unwrap();
+    vm.create_irq_chip().unwrap();
+    vm.register_irqfd(4, &evt, Some(&resample_evt)).unwrap();
+    vm.unregister_irqfd(4, &evt).unwrap();
+    vm.register_irqfd(4, &evt, Some(&resample_evt)).unwrap();
+    vm.unregister_irqfd(4, &evt).unwrap();
+}
+
+#[test]
+fn register_irqfd_config() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    let evtfd1 = Event::new().unwrap();
+    let evtfd2 = Event::new().unwrap();
+    let resample_evt = Event::new().unwrap();
+    vm.create_irq_chip().unwrap();
+    vm.register_irqfd_config(&IrqfdConfig {
+        gsi: 4,
+        evt: &evtfd1,
+        resample: None,
+    })
+    .unwrap();
+    vm.register_irqfd_config(&IrqfdConfig {
+        gsi: 8,
+        evt: &evtfd2,
+        resample: Some(&resample_evt),
+    })
+    .unwrap();
+    vm.unregister_irqfd(4, &evtfd1).unwrap();
+    vm.unregister_irqfd(8, &evtfd2).unwrap();
+}
+
+#[test]
+fn validate_routes() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    let msi_route = |gsi| IrqRoute {
+        gsi,
+        source: IrqSource::Msi {
+            address: 0xfee00000,
+            data: gsi,
+        },
+    };
+
+    vm.validate_routes(&[msi_route(1), msi_route(2)]).unwrap();
+
+    // Far more routes than KVM_MAX_IRQ_ROUTES.
+    let routes: Vec<IrqRoute> = (0..0x10000).map(msi_route).collect();
+    assert_eq!(
+        vm.validate_routes(&routes).unwrap_err().errno(),
+        libc::E2BIG
+    );
+    assert_eq!(
+        vm.set_gsi_routing(&routes).unwrap_err().errno(),
+        libc::E2BIG
+    );
+
+    // A GSI can't be routed to two MSIs.
+    let routes = [msi_route(1), msi_route(2), msi_route(1)];
+    assert_eq!(
+        vm.validate_routes(&routes).unwrap_err().errno(),
+        libc::EINVAL
+    );
+}
+
+#[test]
+fn irq_ack_notifier() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    let evt = Event::new().unwrap();
+    vm.register_irq_ack_notifier(4, &evt).unwrap();
+    assert_eq!(
+        vm.register_irq_ack_notifier(4, &evt).unwrap_err().errno(),
+        libc::EEXIST
+    );
+    vm.notify_irq_ack(4).unwrap();
+    evt.wait().unwrap();
+    // Acks for GSIs without a notifier are ignored.
+    vm.notify_irq_ack(5).unwrap();
+    vm.unregister_irq_ack_notifier(4).unwrap();
+    assert_eq!(
+        vm.unregister_irq_ack_notifier(4).unwrap_err().errno(),
+        libc::ENOENT
+    );
+}
+
+#[test]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn irq_ack_notifier_kernel_irqchip() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    vm.create_irq_chip().unwrap();
+    let evt = Event::new().unwrap();
+    assert_eq!(
+        vm.register_irq_ack_notifier(4, &evt).unwrap_err().errno(),
+        libc::ENOTSUP
+    );
+}
+
+#[test]
+fn create_device_typed_vfio() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    let device = vm.create_device_typed(DeviceKind::Vfio).unwrap();
+    assert_eq!(device.kind(), DeviceKind::Vfio);
+    // Only the VFIO group attributes are reachable from a VFIO handle; the vGIC setters live on
+    // `KvmVgicDevice`, so setting a GIC attribute on this device does not compile.
+    let vfio = match device {
+        TypedKvmDevice::Vfio(vfio) => vfio,
+        #[allow(unreachable_patterns)]
+        _ => panic!("VFIO device created with the wrong type"),
+    };
+    // An event is not a VFIO group, so the kernel must reject it.
+    let evt = Event::new().unwrap();
+    assert!(vfio.add_group(&evt).is_err());
+}
+
+#[test]
+fn vfio_group_add() {
+    struct ClosedDescriptor;
+    impl AsRawDescriptor for ClosedDescriptor {
+        fn as_raw_descriptor(&self) -> RawDescriptor {
+            -1
+        }
+    }
+
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    let vfio_dev = match vm.create_device(DeviceKind::Vfio) {
+        Ok(dev) => dev,
+        // The host kernel was built without the KVM VFIO device.
+        Err(_) => return,
+    };
+    // An invalid descriptor is rejected before reaching KVM.
+    assert_eq!(
+        vm.vfio_group_add(&vfio_dev, &ClosedDescriptor)
+            .unwrap_err()
+            .errno(),
+        libc::EBADF
+    );
+    // A valid descriptor reaches KVM_SET_DEVICE_ATTR, which rejects anything but a VFIO group.
+    let evt = Event::new().unwrap();
+    assert!(vm.vfio_group_add(&vfio_dev, &evt).is_err());
+    assert!(vm.vfio_group_remove(&vfio_dev, &evt).is_err());
+}
+
 #[test]
 fn set_signal_mask() {
     let kvm = Kvm::new().unwrap();
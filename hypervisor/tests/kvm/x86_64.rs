@@ -2,8 +2,12 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::time::Duration;
+
+use base::Event;
 use hypervisor::kvm::get_cpuid_with_initial_capacity;
 use hypervisor::kvm::Kvm;
+use hypervisor::kvm::KvmCap;
 use hypervisor::kvm::KvmVcpu;
 use hypervisor::kvm::KvmVm;
 use hypervisor::DeliveryMode;
@@ -26,7 +30,11 @@ use hypervisor::PitRWMode;
 use hypervisor::PitRWState;
 use hypervisor::PitState;
 use hypervisor::Register;
+use hypervisor::Regs;
 use hypervisor::TriggerMode;
+use hypervisor::Vcpu;
+use hypervisor::VcpuExit;
+use hypervisor::VcpuX86_64;
 use hypervisor::Vm;
 use hypervisor::VmCap;
 use hypervisor::VmX86_64;
@@ -42,11 +50,67 @@ fn get_supported_cpuid() {
     assert!(cpuid.cpu_id_entries.len() > 0);
 }
 
+#[test]
+fn get_cpuid_entry() {
+    let kvm = Kvm::new().unwrap();
+    let leaf0 = kvm.get_cpuid_entry(0, 0).unwrap().unwrap();
+    assert_ne!(leaf0.cpuid.eax, 0);
+    // A second lookup is served from the cache and must agree.
+    assert_eq!(kvm.get_cpuid_entry(0, 0).unwrap(), Some(leaf0));
+    assert_eq!(kvm.get_cpuid_entry(0x4fff_ffff, 0).unwrap(), None);
+}
+
+#[test]
+fn enable_split_irqchip() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    if vm.check_raw_capability(KvmCap::SplitIrqchip) {
+        vm.enable_split_irqchip(24).unwrap();
+    } else {
+        assert_eq!(
+            vm.enable_split_irqchip(24).unwrap_err().errno(),
+            libc::ENOTSUP
+        );
+    }
+}
+
+#[test]
+fn ioapic_ack_notifier() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let evt = Event::new().unwrap();
+
+    let vm = KvmVm::new(&kvm, gm.clone(), Default::default()).unwrap();
+    assert_eq!(
+        vm.register_ioapic_ack_notifier(4, &evt)
+            .unwrap_err()
+            .errno(),
+        libc::ENOTSUP
+    );
+    vm.create_irq_chip().unwrap();
+    assert_eq!(
+        vm.register_ioapic_ack_notifier(4, &evt)
+            .unwrap_err()
+            .errno(),
+        libc::EBUSY
+    );
+
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    if !vm.check_raw_capability(KvmCap::SplitIrqchip) {
+        return;
+    }
+    vm.enable_split_irqchip(24).unwrap();
+    vm.register_ioapic_ack_notifier(4, &evt).unwrap();
+    vm.notify_irq_ack(4).unwrap();
+    evt.wait().unwrap();
+}
+
 #[test]
 fn get_emulated_cpuid() {
     let hypervisor = Kvm::new().unwrap();
-    let cpuid = hypervisor.get_emulated_cpuid().unwrap();
-    assert!(cpuid.cpu_id_entries.len() > 0);
+    // Empty on hosts that can't report the emulated CPUID.
+    hypervisor.get_emulated_cpuid().unwrap();
 }
 
 #[test]
@@ -291,6 +355,218 @@ fn set_gsi_routing() {
     .unwrap();
 }
 
+#[test]
+fn set_gsi_routing_empty_clears_table() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    vm.create_irq_chip().unwrap();
+    let route = IrqRoute {
+        gsi: 1,
+        source: IrqSource::Irqchip {
+            chip: IrqSourceChip::Ioapic,
+            pin: 3,
+        },
+    };
+    vm.set_gsi_routing(&[route]).unwrap();
+    assert_eq!(vm.get_irq_route(1), Some(route));
+    vm.set_gsi_routing(&[]).unwrap();
+    assert_eq!(vm.get_irq_route(1), None);
+}
+
+#[test]
+fn get_irq_route() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    vm.create_irq_chip().unwrap();
+    let routes = [
+        IrqRoute {
+            gsi: 1,
+            source: IrqSource::Irqchip {
+                chip: IrqSourceChip::Ioapic,
+                pin: 3,
+            },
+        },
+        IrqRoute {
+            gsi: 2,
+            source: IrqSource::Msi {
+                address: 0xf000000,
+                data: 0xa0,
+            },
+        },
+    ];
+    vm.set_gsi_routing(&routes).unwrap();
+    assert_eq!(
+        vm.get_irq_route(2).map(|r| r.source),
+        Some(IrqSource::Msi {
+            address: 0xf000000,
+            data: 0xa0,
+        })
+    );
+    assert_eq!(vm.get_irq_route(3), None);
+    assert_eq!(vm.current_routes(), routes);
+}
+
+#[test]
+fn add_remove_irq_route() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    vm.create_irq_chip().unwrap();
+    vm.set_gsi_routing(&[]).unwrap();
+    vm.add_irq_route(IrqRoute {
+        gsi: 1,
+        source: IrqSource::Irqchip {
+            chip: IrqSourceChip::Ioapic,
+            pin: 3,
+        },
+    })
+    .unwrap();
+    vm.add_irq_route(IrqRoute {
+        gsi: 2,
+        source: IrqSource::Msi {
+            address: 0xf000000,
+            data: 0xa0,
+        },
+    })
+    .unwrap();
+    assert_eq!(vm.current_routes().len(), 2);
+    assert!(vm.get_irq_route(1).is_some());
+    assert!(vm.get_irq_route(2).is_some());
+
+    // Adding a route for an existing GSI replaces it.
+    vm.add_irq_route(IrqRoute {
+        gsi: 2,
+        source: IrqSource::Msi {
+            address: 0xf000000,
+            data: 0xa1,
+        },
+    })
+    .unwrap();
+    assert_eq!(vm.current_routes().len(), 2);
+    assert_eq!(
+        vm.get_irq_route(2).map(|r| r.source),
+        Some(IrqSource::Msi {
+            address: 0xf000000,
+            data: 0xa1,
+        })
+    );
+
+    vm.remove_irq_route(1).unwrap();
+    assert_eq!(vm.get_irq_route(1), None);
+    assert!(vm.get_irq_route(2).is_some());
+    assert!(vm.remove_irq_route(1).is_err());
+}
+
+#[test]
+fn add_irq_route_keeps_default_routes() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    vm.create_irq_chip().unwrap();
+    // KVM routes GSIs 0-15 to a PIC and the IOAPIC, and GSIs 16-23 to the IOAPIC.
+    assert_eq!(vm.current_routes().len(), 40);
+
+    // Rerouting the IOAPIC pin of GSI 4 keeps its PIC route.
+    vm.add_irq_route(IrqRoute {
+        gsi: 4,
+        source: IrqSource::Irqchip {
+            chip: IrqSourceChip::Ioapic,
+            pin: 20,
+        },
+    })
+    .unwrap();
+    let routes = vm.current_routes();
+    assert_eq!(routes.len(), 40);
+    let gsi4: Vec<IrqSource> = routes
+        .iter()
+        .filter(|r| r.gsi == 4)
+        .map(|r| r.source)
+        .collect();
+    assert_eq!(gsi4.len(), 2);
+    assert!(gsi4.contains(&IrqSource::Irqchip {
+        chip: IrqSourceChip::PicPrimary,
+        pin: 4,
+    }));
+    assert!(gsi4.contains(&IrqSource::Irqchip {
+        chip: IrqSourceChip::Ioapic,
+        pin: 20,
+    }));
+
+    // An MSI route can't share a GSI, so it replaces both.
+    vm.add_irq_route(IrqRoute {
+        gsi: 4,
+        source: IrqSource::Msi {
+            address: 0xf000000,
+            data: 0xa0,
+        },
+    })
+    .unwrap();
+    assert_eq!(vm.current_routes().len(), 39);
+}
+
+#[test]
+fn tsc_deadline() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    vm.create_irq_chip().unwrap();
+    let vcpu = vm.create_kvm_vcpu(0).unwrap();
+    if kvm.check_capability(HypervisorCap::TscDeadlineTimer) {
+        let deadline = vcpu.get_tsc_deadline().unwrap();
+        vcpu.set_tsc_deadline(deadline).unwrap();
+    } else {
+        assert_eq!(vcpu.get_tsc_deadline().unwrap_err().errno(), libc::ENOTSUP);
+        assert_eq!(vcpu.set_tsc_deadline(0).unwrap_err().errno(), libc::ENOTSUP);
+    }
+}
+
+#[test]
+fn cr8() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    vm.create_irq_chip().unwrap();
+    let vcpu = vm.create_kvm_vcpu(0).unwrap();
+    vcpu.set_cr8(4).unwrap();
+    assert_eq!(vcpu.get_cr8().unwrap(), 4);
+    assert_eq!(vcpu.set_cr8(16).unwrap_err().errno(), libc::EINVAL);
+    assert_eq!(vcpu.get_cr8().unwrap(), 4);
+}
+
+#[test]
+fn xss() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    let vcpu = vm.create_kvm_vcpu(0).unwrap();
+    vcpu.set_cpuid(&kvm.get_supported_cpuid().unwrap()).unwrap();
+    match vcpu.get_xss() {
+        Ok(xss) => {
+            vcpu.set_xss(xss).unwrap();
+            assert_eq!(vcpu.get_xss().unwrap(), xss);
+        }
+        Err(e) => {
+            assert_eq!(e.errno(), libc::ENOTSUP);
+            assert_eq!(vcpu.set_xss(0).unwrap_err().errno(), libc::ENOTSUP);
+        }
+    }
+}
+
+#[test]
+fn xsave() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    let vcpu = vm.create_kvm_vcpu(0).unwrap();
+    let xsave = vcpu.get_xsave().unwrap();
+    assert!(xsave.len() * 4 >= std::mem::size_of::<kvm_xsave>());
+    vcpu.set_xsave(&xsave).unwrap();
+    assert_eq!(vcpu.get_xsave().unwrap(), xsave);
+    assert_eq!(vcpu.set_xsave(&xsave[1..]).unwrap_err().errno(), EINVAL);
+}
+
 #[test]
 fn set_identity_map_addr() {
     let kvm = Kvm::new().unwrap();
@@ -310,6 +586,138 @@ fn mp_state() {
     vcpu.set_mp_state(&state).unwrap();
 }
 
+#[test]
+fn snapshot_restore() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    vm.create_irq_chip().unwrap();
+    let vcpu: KvmVcpu = vm.create_kvm_vcpu(0).unwrap();
+
+    const MSR_IA32_SYSENTER_CS: u32 = 0x00000174;
+    let mut regs = vcpu.get_regs().unwrap();
+    regs.rax = 0x1234;
+    regs.rip = 0x1000;
+    vcpu.set_regs(&regs).unwrap();
+    let mut fpu = vcpu.get_fpu().unwrap();
+    fpu.xmm[3][7] = 42;
+    vcpu.set_fpu(&fpu).unwrap();
+    let mut dregs = vcpu.get_debugregs().unwrap();
+    dregs.db[1] = 0x5000;
+    vcpu.set_debugregs(&dregs).unwrap();
+    vcpu.set_msrs(&[Register {
+        id: MSR_IA32_SYSENTER_CS,
+        value: 0x10,
+    }])
+    .unwrap();
+
+    let snap = vcpu.snapshot().unwrap();
+
+    vcpu.set_regs(&Default::default()).unwrap();
+    vcpu.set_fpu(&Default::default()).unwrap();
+    vcpu.set_debugregs(&Default::default()).unwrap();
+    vcpu.set_msrs(&[Register {
+        id: MSR_IA32_SYSENTER_CS,
+        value: 0,
+    }])
+    .unwrap();
+    let mut sregs = vcpu.get_sregs().unwrap();
+    sregs.cr2 = 0xdead000;
+    vcpu.set_sregs(&sregs).unwrap();
+
+    vcpu.restore(&snap).unwrap();
+
+    let restored = vcpu.snapshot().unwrap();
+    assert_eq!(restored.regs, snap.regs);
+    assert_eq!(restored.regs.rax, 0x1234);
+    assert_eq!(restored.sregs, snap.sregs);
+    assert_eq!(restored.fpu, snap.fpu);
+    assert_eq!(restored.fpu.xmm[3][7], 42);
+    assert_eq!(restored.debugregs, snap.debugregs);
+    assert_eq!(restored.debugregs.db[1], 0x5000);
+    assert_eq!(restored.xcrs, snap.xcrs);
+    assert_eq!(restored.xsave, snap.xsave);
+    assert_eq!(restored.msrs, snap.msrs);
+    assert!(restored.msrs.contains(&Register {
+        id: MSR_IA32_SYSENTER_CS,
+        value: 0x10,
+    }));
+    assert_eq!(restored.lapic, snap.lapic);
+    assert_eq!(restored.mp_state, snap.mp_state);
+    assert_eq!(restored.tsc_deadline, snap.tsc_deadline);
+    assert_eq!(restored.vcpu_events, snap.vcpu_events);
+}
+
+#[test]
+fn fail_entry() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    // hlt, so a vcpu that does manage to enter stops instead of running off.
+    vm.get_memory()
+        .write_at_addr(&[0xf4], GuestAddress(0x1000))
+        .unwrap();
+    let vcpu = vm.create_vcpu(0).unwrap();
+    let mut sregs = vcpu.get_sregs().unwrap();
+    sregs.cs.base = 0;
+    sregs.cs.selector = 0;
+    // A task register must be a busy TSS; type 0 fails the VM-entry guest state checks.
+    sregs.tr.type_ = 0;
+    vcpu.set_sregs(&sregs).unwrap();
+    vcpu.set_regs(&Regs {
+        rip: 0x1000,
+        rflags: 2,
+        ..Default::default()
+    })
+    .unwrap();
+    let run_handle = vcpu.take_run_handle(None).unwrap();
+    match vcpu.run(&run_handle).unwrap() {
+        VcpuExit::FailEntry {
+            hardware_entry_failure_reason,
+            ..
+        } => assert_ne!(hardware_entry_failure_reason, 0),
+        exit => panic!("unexpected exit {:?}", exit),
+    }
+}
+
+#[test]
+fn run_with_timeout() {
+    extern "C" fn handle_kick(_: libc::c_int) {}
+
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    // jmp $, so the guest never exits on its own.
+    vm.get_memory()
+        .write_at_addr(&[0xeb, 0xfe], GuestAddress(0x1000))
+        .unwrap();
+    let mut vcpu = vm.create_kvm_vcpu(0).unwrap();
+    let mut sregs = vcpu.get_sregs().unwrap();
+    sregs.cs.base = 0;
+    sregs.cs.selector = 0;
+    vcpu.set_sregs(&sregs).unwrap();
+    vcpu.set_regs(&Regs {
+        rip: 0x1000,
+        rflags: 2,
+        ..Default::default()
+    })
+    .unwrap();
+
+    let signal_num = base::SIGRTMIN() + 0;
+    // Safe because the handler does nothing.
+    unsafe { base::register_rt_signal_handler(signal_num, handle_kick) }.unwrap();
+    let run_handle = vcpu.take_run_handle(Some(signal_num)).unwrap();
+    assert!(vcpu
+        .run_with_timeout(&run_handle, Duration::from_millis(50))
+        .unwrap()
+        .is_none());
+    // The vcpu is still usable after timing out.
+    assert!(vcpu
+        .run_with_timeout(&run_handle, Duration::from_millis(10))
+        .unwrap()
+        .is_none());
+}
+
 #[test]
 fn enable_feature() {
     let kvm = Kvm::new().unwrap();
@@ -365,6 +773,74 @@ fn xcrs() {
     assert_eq!(xcrs[0].value, xcrs2[0].value);
 }
 
+#[test]
+fn suspend_resume() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    vm.create_irq_chip().unwrap();
+    let vcpu = vm.create_kvm_vcpu(0).unwrap();
+
+    let state = vcpu.suspend().unwrap();
+    // The guest hasn't enabled kvmclock, so there is nothing to tell it.
+    assert!(!vcpu.resume(&state).unwrap());
+
+    // Enable kvmclock through MSR_KVM_SYSTEM_TIME_NEW, with its structure at 0x1000.
+    vcpu.set_msrs(&[Register {
+        id: 0x4b56_4d01,
+        value: 0x1000 | 1,
+    }])
+    .unwrap();
+    // KVM_KVMCLOCK_CTRL is only issued when KVM reports the capability for it.
+    assert_eq!(
+        vcpu.resume(&state).unwrap(),
+        vm.check_capability(VmCap::PvClockSuspend)
+    );
+    assert_eq!(MPState::from(&vcpu.get_mp_state().unwrap()), state.mp_state);
+}
+
+#[test]
+fn enforce_pv_feature_cpuid() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    let vcpu = vm.create_kvm_vcpu(0).unwrap();
+    vcpu.set_cpuid(&kvm.get_supported_cpuid().unwrap()).unwrap();
+    if vm.check_raw_capability(KvmCap::EnforcePvFeatureCpuid) {
+        vcpu.enforce_pv_feature_cpuid().unwrap();
+    } else {
+        assert_eq!(
+            vcpu.enforce_pv_feature_cpuid().unwrap_err().errno(),
+            libc::ENOTSUP
+        );
+    }
+}
+
+#[test]
+fn preemption_timer() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    let vcpu = vm.create_kvm_vcpu(0).unwrap();
+    if !vm.check_raw_capability(KvmCap::NestedState) {
+        assert_eq!(
+            vcpu.get_preemption_timer().unwrap_err().errno(),
+            libc::ENOTSUP
+        );
+        assert_eq!(
+            vcpu.set_preemption_timer(0x1000).unwrap_err().errno(),
+            libc::ENOTSUP
+        );
+        return;
+    }
+    // The vcpu is not running a nested guest, so there is no timer to save or restore.
+    assert_eq!(vcpu.get_preemption_timer().unwrap(), None);
+    assert_eq!(
+        vcpu.set_preemption_timer(0x1000).unwrap_err().errno(),
+        libc::EINVAL
+    );
+}
+
 #[test]
 fn get_msrs() {
     let kvm = Kvm::new().unwrap();
@@ -123,6 +123,16 @@ pub enum Cap {
     IoapicNumPins = KVM_CAP_IOAPIC_NUM_PINS,
     ArmProtectedVm = KVM_CAP_ARM_PROTECTED_VM,
     ArmMte = KVM_CAP_ARM_MTE,
+    ArmUserIrq = KVM_CAP_ARM_USER_IRQ,
+    HaltPoll = KVM_CAP_HALT_POLL,
+    ManualDirtyLogProtect2 = KVM_CAP_MANUAL_DIRTY_LOG_PROTECT2,
+    SplitIrqchip = KVM_CAP_SPLIT_IRQCHIP,
+    UserMemory2 = KVM_CAP_USER_MEMORY2,
+    GuestMemfd = KVM_CAP_GUEST_MEMFD,
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     BusLockDetect = KVM_CAP_X86_BUS_LOCK_EXIT,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    EnforcePvFeatureCpuid = KVM_CAP_ENFORCE_PV_FEATURE_CPUID,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    NestedState = KVM_CAP_NESTED_STATE,
 }
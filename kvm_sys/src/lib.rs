@@ -57,7 +57,13 @@ pub mod x86 {
     ioctl_iow_nr!(KVM_SET_XSAVE, KVMIO, 0xa5, kvm_xsave);
     ioctl_ior_nr!(KVM_GET_XCRS, KVMIO, 0xa6, kvm_xcrs);
     ioctl_iow_nr!(KVM_SET_XCRS, KVMIO, 0xa7, kvm_xcrs);
+    ioctl_iowr_nr!(KVM_GET_NESTED_STATE, KVMIO, 0xbe, kvm_nested_state);
+    ioctl_iow_nr!(KVM_SET_NESTED_STATE, KVMIO, 0xbf, kvm_nested_state);
     ioctl_iowr_nr!(KVM_GET_SUPPORTED_HV_CPUID, KVMIO, 0xc1, kvm_cpuid2);
+    ioctl_ior_nr!(KVM_GET_XSAVE2, KVMIO, 0xcf, kvm_xsave);
+
+    // Newer than the generated bindings; available since Linux 5.17.
+    pub const KVM_CAP_XSAVE2: u32 = 208;
 }
 
 #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
@@ -166,6 +172,33 @@ ioctl_io_nr!(KVM_SMI, KVMIO, 0xb7);
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 ioctl_iow_nr!(KVM_X86_SET_MSR_FILTER, KVMIO, 0xc6, kvm_msr_filter);
 
+// Private memory backed by a guest_memfd is newer than the generated bindings; available since
+// Linux 6.8.
+pub const KVM_CAP_USER_MEMORY2: u32 = 231;
+pub const KVM_CAP_GUEST_MEMFD: u32 = 234;
+pub const KVM_MEM_GUEST_MEMFD: u32 = 1 << 2;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct kvm_userspace_memory_region2 {
+    pub slot: u32,
+    pub flags: u32,
+    pub guest_phys_addr: u64,
+    pub memory_size: u64,
+    pub userspace_addr: u64,
+    pub guest_memfd_offset: u64,
+    pub guest_memfd: u32,
+    pub pad1: u32,
+    pub pad2: [u64; 14usize],
+}
+
+ioctl_iow_nr!(
+    KVM_SET_USER_MEMORY_REGION2,
+    KVMIO,
+    0x49,
+    kvm_userspace_memory_region2
+);
+
 // Along with the common ioctls, we reexport the ioctls of the current
 // platform.
 
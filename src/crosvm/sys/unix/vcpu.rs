@@ -548,8 +548,12 @@ where
                 Ok(VcpuExit::Shutdown) => return ExitState::Stop,
                 Ok(VcpuExit::FailEntry {
                     hardware_entry_failure_reason,
+                    cpu,
                 }) => {
-                    error!("vcpu hw run failure: {:#x}", hardware_entry_failure_reason);
+                    error!(
+                        "vcpu hw run failure on host cpu {}: {:#x}",
+                        cpu, hardware_entry_failure_reason
+                    );
                     return ExitState::Crash;
                 }
                 Ok(VcpuExit::SystemEventShutdown) => {
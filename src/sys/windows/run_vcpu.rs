@@ -853,6 +853,7 @@ where
                 Ok(VcpuExit::Shutdown) => bail_exit_code!(Exit::VcpuShutdown, "vcpu shutdown"),
                 Ok(VcpuExit::FailEntry {
                     hardware_entry_failure_reason,
+                    ..
                 }) => bail_exit_code!(
                     Exit::VcpuFailEntry,
                     "vcpu hw run failure: {:#x}",
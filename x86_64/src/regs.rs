@@ -26,6 +26,9 @@ pub enum Error {
     /// Failed to get base registers for this cpu.
     #[error("failed to get base registers for this cpu: {0}")]
     GettingRegistersIoctl(base::Error),
+    /// The page tables do not fit in guest memory at this address.
+    #[error("page tables do not fit at {0}")]
+    InvalidPageTableAddress(GuestAddress),
     /// Failed to set sregs for this cpu.
     #[error("failed to set sregs for this cpu: {0}")]
     SetSRegsIoctlFailed(base::Error),
@@ -293,27 +296,48 @@ pub fn configure_segments_and_sregs(mem: &GuestMemory, sregs: &mut Sregs) -> Res
     Ok(())
 }
 
-/// Configures the system page tables and control registers for long mode with paging.
-pub fn setup_page_tables(mem: &GuestMemory, sregs: &mut Sregs) -> Result<()> {
-    // Puts PML4 right after zero page but aligned to 4k.
-    let boot_pml4_addr = GuestAddress(0x9000);
-    let boot_pdpte_addr = GuestAddress(0xa000);
-    let boot_pde_addr = GuestAddress(0xb000);
+// Size of one page table, which holds 512 8-byte entries.
+const PAGE_TABLE_SIZE: u64 = 0x1000;
+// Number of tables written by `setup_identity_page_tables`: PML4, PDPT and PD.
+const IDENTITY_PAGE_TABLES: u64 = 3;
+
+/// Writes 4-level page tables identity mapping the low 1GB of the address space into `mem`.
+///
+/// The PML4, PDPT and page directory occupy three consecutive pages starting at `pml4_addr`, which
+/// must be page aligned and entirely backed by guest memory. The directory maps 2MB pages, so no
+/// page table level is needed. Pair this with setting CR3 to `pml4_addr`.
+pub fn setup_identity_page_tables(mem: &GuestMemory, pml4_addr: GuestAddress) -> Result<()> {
+    if pml4_addr.offset() % PAGE_TABLE_SIZE != 0
+        || !mem.is_valid_range(pml4_addr, IDENTITY_PAGE_TABLES * PAGE_TABLE_SIZE)
+    {
+        return Err(Error::InvalidPageTableAddress(pml4_addr));
+    }
+    let pdpte_addr = pml4_addr.unchecked_add(PAGE_TABLE_SIZE);
+    let pde_addr = pdpte_addr.unchecked_add(PAGE_TABLE_SIZE);
 
     // Entry covering VA [0..512GB)
-    mem.write_obj_at_addr(boot_pdpte_addr.offset() as u64 | 0x03, boot_pml4_addr)
+    mem.write_obj_at_addr(pdpte_addr.offset() as u64 | 0x03, pml4_addr)
         .map_err(|_| Error::WritePML4Address)?;
 
     // Entry covering VA [0..1GB)
-    mem.write_obj_at_addr(boot_pde_addr.offset() as u64 | 0x03, boot_pdpte_addr)
+    mem.write_obj_at_addr(pde_addr.offset() as u64 | 0x03, pdpte_addr)
         .map_err(|_| Error::WritePDPTEAddress)?;
 
     // 512 2MB entries together covering VA [0..1GB). Note we are assuming
     // CPU supports 2MB pages (/proc/cpuinfo has 'pse'). All modern CPUs do.
     for i in 0..512 {
-        mem.write_obj_at_addr((i << 21) + 0x83u64, boot_pde_addr.unchecked_add(i * 8))
+        mem.write_obj_at_addr((i << 21) + 0x83u64, pde_addr.unchecked_add(i * 8))
             .map_err(|_| Error::WritePDEAddress)?;
     }
+    Ok(())
+}
+
+/// Configures the system page tables and control registers for long mode with paging.
+pub fn setup_page_tables(mem: &GuestMemory, sregs: &mut Sregs) -> Result<()> {
+    // Puts PML4 right after zero page but aligned to 4k.
+    let boot_pml4_addr = GuestAddress(0x9000);
+    setup_identity_page_tables(mem, boot_pml4_addr)?;
+
     sregs.cr3 = boot_pml4_addr.offset() as u64;
     sregs.cr4 |= X86_CR4_PAE;
     sregs.cr0 |= X86_CR0_PG;
@@ -378,4 +402,19 @@ mod tests {
         assert_eq!(X86_CR4_PAE, sregs.cr4);
         assert_eq!(X86_CR0_PG, sregs.cr0 & X86_CR0_PG);
     }
+
+    #[test]
+    fn identity_page_tables() {
+        let gm = create_guest_mem();
+        setup_identity_page_tables(&gm, GuestAddress(0x4000)).unwrap();
+
+        assert_eq!(0x5003, read_u64(&gm, 0x4000));
+        assert_eq!(0x6003, read_u64(&gm, 0x5000));
+        assert_eq!(0x83, read_u64(&gm, 0x6000));
+        assert_eq!((511 << 21) + 0x83u64, read_u64(&gm, 0x6000 + 511 * 8));
+
+        // Unaligned, and running off the end of guest memory.
+        assert!(setup_identity_page_tables(&gm, GuestAddress(0x4008)).is_err());
+        assert!(setup_identity_page_tables(&gm, GuestAddress(0xe000)).is_err());
+    }
 }